@@ -1,14 +1,14 @@
-use chrono::TimeZone;
 use clap::Parser;
 
 use ring::rand::SystemRandom;
 use ring::signature::Ed25519KeyPair;
 use serde::{Deserialize, Serialize};
 
-use std::io::{self, Read, Write};
 use std::path::PathBuf;
 
-use crate::files::{crypto, Files, Path, Revision};
+use crate::config;
+use crate::encryption::Cipher;
+use crate::files::{at_rest, crypto, Files};
 
 #[derive(Parser)]
 pub enum Command {
@@ -40,6 +40,40 @@ pub enum Command {
     DbgKey {
         key: PathBuf,
     },
+
+    /// Generate a secret key for encryption; either end-to-end encryption of files, which is kept by clients and never
+    /// sent to the server, or encryption of a server's database at rest
+    GenEncryptionKey {
+        out: PathBuf,
+    },
+
+    /// Re-encrypt a server's database in place with a new key, while the server is not running. The current key is
+    /// taken from the server's configuration, which should be changed to the new key once this has finished
+    RotateKey {
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+        /// A file holding the new key, as written by `gen-encryption-key`
+        #[arg(long, required_unless_present = "decrypt", conflicts_with = "decrypt")]
+        new: Option<PathBuf>,
+        /// Decrypt the database, rather than moving it to a new key
+        #[arg(long)]
+        decrypt: bool,
+    },
+
+    CollectGarbage {
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+
+    Prune {
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
+
+    Fsck {
+        #[arg(short, long)]
+        db: Option<PathBuf>,
+    },
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -52,7 +86,58 @@ struct KeyGen {
     identity: Identity,
 }
 
-pub fn admin_cli(command: Command) -> anyhow::Result<()> {
+#[derive(Debug, thiserror::Error)]
+pub enum AdminError {
+    #[error("no database provided")]
+    NoDatabase,
+
+    #[error("no retention policy configured")]
+    NoRetention,
+
+    #[error("database is damaged")]
+    Damaged,
+}
+
+/// Load the key a server's database is encrypted with, if it is
+fn db_key(server: Option<&config::Server>) -> anyhow::Result<Option<at_rest::Key>> {
+    server
+        .and_then(|server| server.encryption.as_ref())
+        .map(at_rest::Key::load)
+        .transpose()
+}
+
+/// Open a server's database directly, either from a given path, or from the server's configuration
+fn open_db(
+    db: Option<PathBuf>,
+    server: Option<&config::Server>,
+    keys: at_rest::Keys,
+) -> anyhow::Result<Files> {
+    let db = db
+        .or_else(|| server.map(|server| server.db.clone()))
+        .ok_or(AdminError::NoDatabase)?;
+
+    let storage = server
+        .map(|server| server.storage.clone())
+        .unwrap_or_default();
+    let compression = server.map(|server| server.compression).unwrap_or_default();
+
+    Ok(Files::open(db, &storage, compression, keys)?)
+}
+
+/// Open a server's database directly, with the key from the server's configuration
+fn open_configured_db(
+    db: Option<PathBuf>,
+    server: Option<&config::Server>,
+) -> anyhow::Result<Files> {
+    let keys = at_rest::Keys {
+        current: db_key(server)?,
+        previous: None,
+    };
+
+    open_db(db, server, keys)
+}
+
+pub fn admin_cli(command: Command, server: Option<config::Server>) -> anyhow::Result<()> {
     match command {
         Command::GenPair { out } => {
             let editor = std::env::var("EDITOR")?;
@@ -129,6 +214,58 @@ pub fn admin_cli(command: Command) -> anyhow::Result<()> {
 
             std::fs::write(out, pub_key)?;
         }
+
+        Command::GenEncryptionKey { out } => {
+            Cipher::generate(&out)?;
+        }
+
+        Command::RotateKey { db, new, .. } => {
+            let new = match new {
+                Some(new) => Some(at_rest::Key::new(&std::fs::read(new)?)?),
+                None => None,
+            };
+
+            // values may be stored with either key, if a previous rotation was interrupted
+            let keys = at_rest::Keys {
+                current: new,
+                previous: db_key(server.as_ref())?,
+            };
+
+            let files = open_db(db, server.as_ref(), keys)?;
+            let report = files.reseal()?;
+
+            println!("{report}");
+        }
+
+        Command::CollectGarbage { db } => {
+            let files = open_configured_db(db, server.as_ref())?;
+            let report = files.collect_garbage()?;
+
+            println!("{report}");
+        }
+
+        Command::Prune { db } => {
+            let policy = server
+                .as_ref()
+                .and_then(|server| server.retention.as_ref())
+                .ok_or(AdminError::NoRetention)?;
+
+            let files = open_configured_db(db, server.as_ref())?;
+            let report = files.prune(policy)?;
+
+            println!("{report}");
+        }
+
+        Command::Fsck { db } => {
+            let files = open_configured_db(db, server.as_ref())?;
+            let report = files.fsck()?;
+
+            println!("{report}");
+
+            if !report.is_clean() {
+                return Err(AdminError::Damaged.into());
+            }
+        }
     }
 
     Ok(())
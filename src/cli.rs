@@ -1,14 +1,27 @@
 use clap::Parser;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use anyhow::anyhow;
 
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf, Take};
+
+use std::collections::VecDeque;
+use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
 
-use crate::client::Client;
+use crate::client::{file_object, Client};
+use crate::config;
+use crate::encryption::Cipher;
+use crate::files::batch::Operation;
 use crate::files::crypto;
-use crate::files::Path;
+use crate::files::diff::Change;
+use crate::files::fsck::FsckReport;
+use crate::files::gc::GcReport;
+use crate::files::prune::PruneReport;
+use crate::files::{Object, Path, Revision};
 use crate::server::methods;
 
 #[derive(Parser)]
@@ -27,6 +40,146 @@ pub enum Method {
 
         #[arg(short, long)]
         from: Option<PathBuf>,
+
+        /// Only insert the file if this is still the latest revision of the tree
+        #[arg(short, long)]
+        expect: Option<Object>,
+    },
+
+    Delete {
+        #[arg(short, long)]
+        path: String,
+
+        /// Only delete the node if this is still the latest revision of the tree
+        #[arg(short, long)]
+        expect: Option<Object>,
+    },
+
+    /// Move or rename a file or directory, without uploading it again
+    #[command(name = "mv")]
+    Move {
+        #[arg(short, long)]
+        from: String,
+
+        #[arg(short, long)]
+        to: String,
+
+        /// Only move the node if this is still the latest revision of the tree
+        #[arg(short, long)]
+        expect: Option<Object>,
+    },
+
+    /// Copy a file or directory, as it was in a given revision, to a new path, without uploading it again
+    #[command(name = "cp")]
+    Copy {
+        #[arg(short, long)]
+        from: String,
+
+        #[arg(short, long, default_value = "latest")]
+        revision: Revision,
+
+        #[arg(short, long)]
+        to: String,
+
+        /// Only copy the node if this is still the latest revision of the tree
+        #[arg(short, long)]
+        expect: Option<Object>,
+    },
+
+    /// Insert the contents of a tar (or `.tar.gz`) archive under a directory, as a single revision
+    Import {
+        #[arg(short, long, default_value = "/")]
+        to: String,
+
+        #[arg(short, long)]
+        from: Option<PathBuf>,
+    },
+
+    /// Download a revision of a directory or file as a `.tar.gz` archive
+    Export {
+        #[arg(short, long, default_value = "/")]
+        from: String,
+
+        #[arg(short, long, default_value = "latest")]
+        revision: Revision,
+
+        #[arg(short, long)]
+        to: Option<PathBuf>,
+    },
+
+    /// Apply a script of operations to the tree as a single revision. Each line of the script is one of `insert <local
+    /// file> <path>`, `delete <path>`, `mkdir <path>` or `move <from> <to>`; blank lines and lines starting with `#` are
+    /// ignored
+    Batch {
+        #[arg(short, long)]
+        from: Option<PathBuf>,
+    },
+
+    /// List the files that changed below a path between two revisions
+    Diff {
+        #[arg(short, long, default_value = "~1")]
+        from: Revision,
+
+        #[arg(short, long, default_value = "latest")]
+        to: Revision,
+
+        #[arg(short, long, default_value = "/")]
+        path: String,
+    },
+
+    /// Revert the whole tree to an older revision, as a new revision
+    Rollback {
+        #[arg(short, long)]
+        revision: Revision,
+    },
+
+    /// List every version of a file or directory, along with the revision it appeared in
+    Log {
+        #[arg(short, long)]
+        path: String,
+    },
+
+    /// Bring back a file or directory as it was in an older revision, leaving the rest of the tree as it is
+    Restore {
+        #[arg(short, long)]
+        path: String,
+
+        #[arg(short, long)]
+        revision: Revision,
+
+        /// Only restore the path if this is still the latest revision of the tree
+        #[arg(short, long)]
+        expect: Option<Object>,
+    },
+
+    /// List the deleted files and directories below a path, along with when they were deleted
+    Tombstones {
+        #[arg(short, long, default_value = "/")]
+        path: String,
+    },
+
+    /// Bring back a deleted file or directory with the contents it had when it was deleted
+    Undelete {
+        #[arg(short, long)]
+        path: String,
+
+        /// Only undelete the path if this is still the latest revision of the tree
+        #[arg(short, long)]
+        expect: Option<Object>,
+    },
+
+    /// Permanently remove the tombstones below a path that were deleted before a given time, such as `30 days ago`.
+    /// They are still kept by older revisions
+    PurgeTombstones {
+        #[arg(short, long, default_value = "/")]
+        path: String,
+
+        #[arg(short, long)]
+        before: Revision,
+
+        /// Only purge the tombstones if this is still the latest revision of the tree
+        #[arg(short, long)]
+        expect: Option<Object>,
     },
 
     Configure {
@@ -37,6 +190,49 @@ pub enum Method {
         #[arg(short, long)]
         client_path: PathBuf,
     },
+
+    /// Pin a name to a revision, so that it can be given as `tag:NAME` wherever a revision is accepted. Tagged revisions
+    /// are never pruned
+    Tag {
+        #[arg(short, long)]
+        name: String,
+
+        #[arg(short, long, default_value = "latest")]
+        revision: Revision,
+    },
+
+    ListTags,
+
+    DeleteTag {
+        #[arg(short, long)]
+        name: String,
+    },
+
+    CreateTree {
+        #[arg(short, long)]
+        name: String,
+    },
+
+    ListTrees,
+
+    RenameTree {
+        #[arg(short, long)]
+        from: String,
+
+        #[arg(short, long)]
+        to: String,
+    },
+
+    DeleteTree {
+        #[arg(short, long)]
+        name: String,
+    },
+
+    CollectGarbage,
+
+    Prune,
+
+    Fsck,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -57,6 +253,12 @@ pub enum CliError {
 
     #[error("failed to authenticate with server")]
     AuthenticationFailed,
+
+    #[error("database is damaged")]
+    Damaged,
+
+    #[error("{command} is not supported with end-to-end encryption")]
+    Encrypted { command: &'static str },
 }
 
 impl CliError {
@@ -67,14 +269,96 @@ impl CliError {
     }
 }
 
+/// Check that `path` is a valid [Path], and encrypt it's names if they are to be encrypted
+fn remote_path(cipher: Option<&Cipher>, path: &str) -> Result<String, CliError> {
+    Path::new(path).map_err(CliError::command_failed)?;
+
+    match cipher {
+        Some(cipher) => Ok(cipher.encrypt_path(path)),
+        None => Ok(path.to_owned()),
+    }
+}
+
+/// Decrypt the paths of a [Change] for display
+fn decrypt_change(cipher: &Cipher, change: Change) -> Change {
+    let path = |path: String| cipher.display_path(&path);
+
+    match change {
+        Change::Added { path: p, object } => Change::Added {
+            path: path(p),
+            object,
+        },
+        Change::Modified { path: p, old, new } => Change::Modified {
+            path: path(p),
+            old,
+            new,
+        },
+        Change::Deleted { path: p, object } => Change::Deleted {
+            path: path(p),
+            object,
+        },
+        Change::Moved { from, to, object } => Change::Moved {
+            from: path(from),
+            to: path(to),
+            object,
+        },
+    }
+}
+
+/// Download the contents of a file to `writer`, decrypting them as they are received if they are encrypted
+async fn download_file(
+    client: &mut Client,
+    cipher: Option<&Cipher>,
+    tree: &str,
+    from: Path<'_>,
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> Result<(), CliError> {
+    match cipher {
+        Some(cipher) => {
+            let mut writer = cipher.decrypt_writer(writer);
+
+            client
+                .download(methods::fs::Get, (tree, from), &mut writer)
+                .await
+                .map_err(CliError::command_failed)?;
+
+            writer.finish().map_err(CliError::command_failed)?;
+        }
+
+        None => {
+            client
+                .download(methods::fs::Get, (tree, from), writer)
+                .await
+                .map_err(CliError::command_failed)?;
+        }
+    }
+
+    Ok(())
+}
+
 pub async fn invoke(
     key: Option<PathBuf>,
     remote: Option<SocketAddr>,
+    encryption: Option<config::Encryption>,
+    tree: &str,
     method: Method,
 ) -> Result<(), CliError> {
     let key = key.ok_or(CliError::NoKey)?;
     let remote = remote.ok_or(CliError::NoRemote)?;
 
+    let cipher = match encryption {
+        Some(config) => Some(
+            Cipher::load(&config)
+                .await
+                .map_err(|e| CliError::FailedReadKey {
+                    path: config.key.clone(),
+                    error: e,
+                })?,
+        ),
+        None => None,
+    };
+    let cipher = cipher.as_ref();
+
     let mut client = Client::connect(remote)
         .await
         .map_err(|e| CliError::ConnectionFailed {
@@ -105,53 +389,295 @@ pub async fn invoke(
     match method {
         Method::Get { from, to } => {
             // parse from string as a server Path
+            let from = remote_path(cipher, &from)?;
             let from = Path::new(&from).map_err(CliError::command_failed)?;
 
-            // get the file's contents from the server
-            let data = client
-                .invoke(methods::fs::Get, from)
+            if let Some(to) = to {
+                // write response to a temporary file, so we don't leave a partial file behind if the transfer fails
+                let dir = to.parent().unwrap_or(std::path::Path::new("."));
+                let tmp = tempfile::NamedTempFile::new_in(dir).map_err(CliError::command_failed)?;
+                let mut file =
+                    tokio::fs::File::from_std(tmp.reopen().map_err(CliError::command_failed)?);
+
+                download_file(&mut client, cipher, tree, from, &mut file).await?;
+
+                tmp.persist(&to).map_err(CliError::command_failed)?;
+            } else {
+                // write response to stdout
+                download_file(&mut client, cipher, tree, from, &mut tokio::io::stdout()).await?;
+            }
+        }
+
+        Method::Insert { to, from, expect } => {
+            // parse to string as path
+            let to = remote_path(cipher, &to)?;
+            let to = Path::new(&to).map_err(CliError::command_failed)?;
+
+            // contents the server already has are linked rather than sent again, which means reading them twice
+            match (cipher, from) {
+                (Some(cipher), Some(from)) => {
+                    let file = tokio::fs::File::open(from)
+                        .await
+                        .map_err(CliError::command_failed)?;
+
+                    client
+                        .insert_file(tree, to, expect, &mut cipher.encrypt_reader(file))
+                        .await
+                        .map_err(CliError::command_failed)?;
+                }
+                (Some(cipher), None) => {
+                    client
+                        .upload::<_, ()>(
+                            methods::fs::Insert,
+                            (tree, to, expect),
+                            &mut cipher.encrypt_reader(tokio::io::stdin()),
+                        )
+                        .await
+                        .map_err(CliError::command_failed)?;
+                }
+                (None, Some(from)) => {
+                    let mut file = tokio::fs::File::open(from)
+                        .await
+                        .map_err(CliError::command_failed)?;
+
+                    client
+                        .insert_file(tree, to, expect, &mut file)
+                        .await
+                        .map_err(CliError::command_failed)?;
+                }
+                (None, None) => {
+                    // stdin can only be read once, so is always streamed to the server
+                    client
+                        .upload::<_, ()>(
+                            methods::fs::Insert,
+                            (tree, to, expect),
+                            &mut tokio::io::stdin(),
+                        )
+                        .await
+                        .map_err(CliError::command_failed)?;
+                }
+            }
+        }
+
+        Method::Delete { path, expect } => {
+            let path = remote_path(cipher, &path)?;
+            let path = Path::new(&path).map_err(CliError::command_failed)?;
+
+            client
+                .invoke(methods::fs::Delete, (tree, path, expect))
+                .await
+                .map_err(CliError::command_failed)?;
+        }
+
+        Method::Move { from, to, expect } => {
+            let from = remote_path(cipher, &from)?;
+            let from = Path::new(&from).map_err(CliError::command_failed)?;
+            let to = remote_path(cipher, &to)?;
+            let to = Path::new(&to).map_err(CliError::command_failed)?;
+
+            client
+                .invoke(methods::fs::Move, (tree, from, to, expect))
+                .await
+                .map_err(CliError::command_failed)?;
+        }
+
+        Method::Copy {
+            from,
+            revision,
+            to,
+            expect,
+        } => {
+            let from = remote_path(cipher, &from)?;
+            let from = Path::new(&from).map_err(CliError::command_failed)?;
+            let to = remote_path(cipher, &to)?;
+            let to = Path::new(&to).map_err(CliError::command_failed)?;
+
+            client
+                .invoke(methods::fs::Copy, (tree, from, revision, to, expect))
+                .await
+                .map_err(CliError::command_failed)?;
+        }
+
+        Method::Import { to, from } => {
+            if cipher.is_some() {
+                return Err(CliError::Encrypted { command: "import" });
+            }
+
+            let mut reader: Box<dyn AsyncRead + Unpin> = if let Some(from) = from {
+                let file = tokio::fs::File::open(from)
+                    .await
+                    .map_err(CliError::command_failed)?;
+
+                Box::new(file)
+            } else {
+                Box::new(tokio::io::stdin())
+            };
+
+            let to = Path::new(&to).map_err(CliError::command_failed)?;
+
+            let count: usize = client
+                .upload(methods::fs::Import, (tree, to), &mut reader)
                 .await
                 .map_err(CliError::command_failed)?;
 
+            println!("imported {count} files");
+        }
+
+        Method::Export { from, revision, to } => {
+            if cipher.is_some() {
+                return Err(CliError::Encrypted { command: "export" });
+            }
+
+            let from = Path::new(&from).map_err(CliError::command_failed)?;
+
             if let Some(to) = to {
-                // write response to file
-                tokio::fs::write(&to, &data)
+                // as with get, don't leave a partial archive behind if the transfer fails
+                let dir = to.parent().unwrap_or(std::path::Path::new("."));
+                let tmp = tempfile::NamedTempFile::new_in(dir).map_err(CliError::command_failed)?;
+                let mut file =
+                    tokio::fs::File::from_std(tmp.reopen().map_err(CliError::command_failed)?);
+
+                client
+                    .download(methods::fs::Export, (tree, from, revision), &mut file)
                     .await
                     .map_err(CliError::command_failed)?;
+
+                tmp.persist(&to).map_err(CliError::command_failed)?;
             } else {
-                // write response to stdout
                 let mut stdout = tokio::io::stdout();
 
-                stdout.write(&data).await.unwrap();
+                client
+                    .download(methods::fs::Export, (tree, from, revision), &mut stdout)
+                    .await
+                    .map_err(CliError::command_failed)?;
             }
         }
 
-        Method::Insert { to, from } => {
-            let data = if let Some(from) = from {
-                // read data from file
-                tokio::fs::read(from)
+        Method::Batch { from } => {
+            if cipher.is_some() {
+                return Err(CliError::Encrypted { command: "batch" });
+            }
+
+            let script = if let Some(from) = from {
+                tokio::fs::read_to_string(from)
                     .await
                     .map_err(CliError::command_failed)?
             } else {
-                // read data from stdin
-                let mut stdin = tokio::io::stdin();
-                let mut buf = vec![];
+                io::read_to_string(io::stdin()).map_err(CliError::command_failed)?
+            };
 
-                stdin.read_to_end(&mut buf).await.unwrap();
+            let (operations, mut reader) = parse_batch(&mut client, &script).await?;
 
-                buf
-            };
+            client
+                .upload::<_, ()>(methods::fs::Batch, (tree, operations), &mut reader)
+                .await
+                .map_err(CliError::command_failed)?;
+        }
 
-            // parse to string as path
-            let to = Path::new(&to).map_err(CliError::command_failed)?;
+        Method::Diff { from, to, path } => {
+            let path = remote_path(cipher, &path)?;
+            let path = Path::new(&path).map_err(CliError::command_failed)?;
 
-            // insert data to server
+            let changes = client
+                .invoke(methods::fs::Diff, (tree, from, to, path))
+                .await
+                .map_err(CliError::command_failed)?;
+
+            for change in changes {
+                match cipher {
+                    Some(cipher) => println!("{}", decrypt_change(cipher, change)),
+                    None => println!("{change}"),
+                }
+            }
+        }
+
+        Method::Rollback { revision } => {
             client
-                .invoke(methods::fs::Insert, (to, data))
+                .invoke(methods::fs::Rollback, (tree, revision))
                 .await
                 .map_err(CliError::command_failed)?;
         }
 
+        Method::Log { path } => {
+            let path = remote_path(cipher, &path)?;
+            let path = Path::new(&path).map_err(CliError::command_failed)?;
+
+            let versions = client
+                .invoke(methods::fs::Log, (tree, path))
+                .await
+                .map_err(CliError::command_failed)?;
+
+            for version in versions {
+                println!("{version}");
+            }
+        }
+
+        Method::Restore {
+            path,
+            revision,
+            expect,
+        } => {
+            let path = remote_path(cipher, &path)?;
+            let path = Path::new(&path).map_err(CliError::command_failed)?;
+
+            client
+                .invoke(methods::fs::Restore, (tree, path, revision, expect))
+                .await
+                .map_err(CliError::command_failed)?;
+        }
+
+        Method::Tombstones { path } => {
+            let path = remote_path(cipher, &path)?;
+            let path = Path::new(&path).map_err(CliError::command_failed)?;
+
+            let tombstones = client
+                .invoke(methods::fs::ListTombstones, (tree, path))
+                .await
+                .map_err(CliError::command_failed)?;
+
+            for mut tombstone in tombstones {
+                if let Some(cipher) = cipher {
+                    tombstone.path = cipher.display_path(&tombstone.path);
+                }
+
+                println!("{tombstone}");
+            }
+        }
+
+        Method::Undelete { path, expect } => {
+            let remote = remote_path(cipher, &path)?;
+            let remote = Path::new(&remote).map_err(CliError::command_failed)?;
+
+            let revision = client
+                .invoke(methods::fs::Undelete, (tree, remote, expect))
+                .await
+                .map_err(CliError::command_failed)?;
+
+            println!("restored {path} from #{revision}");
+        }
+
+        Method::PurgeTombstones {
+            path,
+            before,
+            expect,
+        } => {
+            let path = remote_path(cipher, &path)?;
+            let path = Path::new(&path).map_err(CliError::command_failed)?;
+
+            let Revision::AsOfTime(time) = before else {
+                return Err(CliError::command_failed(anyhow!(
+                    "tombstones can only be purged before a date and time, or a time relative to now"
+                )));
+            };
+
+            let purged = client
+                .invoke(methods::fs::PurgeTombstones, (tree, path, time, expect))
+                .await
+                .map_err(CliError::command_failed)?;
+
+            println!("purged {purged} tombstones");
+        }
+
         Method::Configure {
             admin_path,
             server_path,
@@ -172,7 +698,303 @@ pub async fn invoke(
                 .await
                 .map_err(CliError::command_failed)?;
         }
+
+        Method::Tag { name, revision } => {
+            let tag = client
+                .invoke(methods::fs::CreateTag, (tree, &name, revision))
+                .await
+                .map_err(CliError::command_failed)?;
+
+            println!("{tag}");
+        }
+
+        Method::ListTags => {
+            let tags = client
+                .invoke(methods::fs::ListTags, tree)
+                .await
+                .map_err(CliError::command_failed)?;
+
+            for tag in tags {
+                println!("{tag}");
+            }
+        }
+
+        Method::DeleteTag { name } => {
+            client
+                .invoke(methods::fs::DeleteTag, (tree, &name))
+                .await
+                .map_err(CliError::command_failed)?;
+        }
+
+        Method::CreateTree { name } => {
+            client
+                .invoke(methods::fs::CreateTree, &name)
+                .await
+                .map_err(CliError::command_failed)?;
+        }
+
+        Method::ListTrees => {
+            let trees = client
+                .invoke(methods::fs::ListTrees, ())
+                .await
+                .map_err(CliError::command_failed)?;
+
+            for tree in trees {
+                println!("{tree}");
+            }
+        }
+
+        Method::RenameTree { from, to } => {
+            client
+                .invoke(methods::fs::RenameTree, (&from, &to))
+                .await
+                .map_err(CliError::command_failed)?;
+        }
+
+        Method::DeleteTree { name } => {
+            client
+                .invoke(methods::fs::DeleteTree, &name)
+                .await
+                .map_err(CliError::command_failed)?;
+        }
+
+        Method::CollectGarbage => {
+            let report: GcReport = client
+                .run(methods::admin::CollectGarbage, ())
+                .await
+                .map_err(CliError::command_failed)?;
+
+            println!("{report}");
+        }
+
+        Method::Prune => {
+            let report: PruneReport = client
+                .run(methods::admin::Prune, ())
+                .await
+                .map_err(CliError::command_failed)?;
+
+            println!("{report}");
+        }
+
+        Method::Fsck => {
+            let report: FsckReport = client
+                .run(methods::admin::Fsck, ())
+                .await
+                .map_err(CliError::command_failed)?;
+
+            println!("{report}");
+
+            if !report.is_clean() {
+                return Err(CliError::Damaged);
+            }
+        }
     }
 
     Ok(())
 }
+
+/// Reads the contents of the local files inserted by a batch one after the other, only opening each file once the one
+/// before it has been read, so that a batch never holds more than one of them open
+struct BatchReader {
+    /// The files left to read, along with how much of each to read
+    files: VecDeque<(PathBuf, u64)>,
+    opening: Option<Pin<Box<dyn Future<Output = io::Result<tokio::fs::File>> + Send>>>,
+    /// The file being read, along with it's path
+    current: Option<(PathBuf, Take<tokio::fs::File>)>,
+}
+
+impl BatchReader {
+    fn new(files: VecDeque<(PathBuf, u64)>) -> BatchReader {
+        BatchReader {
+            files,
+            opening: None,
+            current: None,
+        }
+    }
+}
+
+impl AsyncRead for BatchReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            if let Some((path, file)) = &mut this.current {
+                let filled = buf.filled().len();
+                ready!(Pin::new(&mut *file).poll_read(cx, buf))?;
+
+                if buf.filled().len() > filled {
+                    return Poll::Ready(Ok(()));
+                }
+
+                // the server expects exactly as many bytes as were declared
+                if file.limit() > 0 {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("{path:?} was cut short while it was being sent"),
+                    )));
+                }
+
+                this.current = None;
+            }
+
+            if let Some(opening) = &mut this.opening {
+                let file = ready!(opening.as_mut().poll(cx))?;
+                this.opening = None;
+
+                let (path, size) = this.files.pop_front().unwrap();
+                this.current = Some((path, file.take(size)));
+
+                continue;
+            }
+
+            let Some((path, _)) = this.files.front() else {
+                return Poll::Ready(Ok(()));
+            };
+
+            this.opening = Some(Box::pin(tokio::fs::File::open(path.clone())));
+        }
+    }
+}
+
+/// Parse a batch script into it's operations, along with a reader over the contents of the local files it inserts.
+/// Files the server already has are linked rather than sent again, which costs one round trip to find out
+async fn parse_batch(
+    client: &mut Client,
+    script: &str,
+) -> Result<(Vec<Operation>, BatchReader), CliError> {
+    let mut operations = vec![];
+    // the local files being inserted, along with the index of their operation
+    let mut inserts = vec![];
+
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid =
+            || CliError::command_failed(anyhow!("invalid batch line {}: {line}", number + 1));
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        let operation = match words[..] {
+            ["insert", local, path] => {
+                let mut file = tokio::fs::File::open(local)
+                    .await
+                    .map_err(CliError::command_failed)?;
+                let size = file
+                    .metadata()
+                    .await
+                    .map_err(CliError::command_failed)?
+                    .len();
+
+                // only read as much as was declared, in case the file grows in the meantime
+                let object = file_object(&mut (&mut file).take(size))
+                    .await
+                    .map_err(CliError::command_failed)?;
+
+                inserts.push((operations.len(), PathBuf::from(local), object));
+
+                Operation::Insert {
+                    path: path.to_owned(),
+                    size,
+                }
+            }
+            ["delete", path] => Operation::Delete {
+                path: path.to_owned(),
+            },
+            ["mkdir", path] => Operation::MakeDir {
+                path: path.to_owned(),
+            },
+            ["move", from, to] => Operation::Move {
+                from: from.to_owned(),
+                to: to.to_owned(),
+            },
+            _ => return Err(invalid()),
+        };
+
+        operations.push(operation);
+    }
+
+    let have = if inserts.is_empty() {
+        vec![]
+    } else {
+        let objects = inserts.iter().map(|(_, _, object)| *object).collect();
+
+        client
+            .invoke(methods::fs::Have, objects)
+            .await
+            .map_err(CliError::command_failed)?
+    };
+
+    if have.len() != inserts.len() {
+        return Err(CliError::command_failed(anyhow!(
+            "server checked {} files, rather than {}",
+            have.len(),
+            inserts.len()
+        )));
+    }
+
+    let mut sent = VecDeque::new();
+
+    for ((index, local, object), have) in inserts.into_iter().zip(have) {
+        let Operation::Insert { path, size } = &operations[index] else {
+            unreachable!()
+        };
+
+        if have {
+            log::debug!("server already has the contents of {path}, so linking them");
+
+            operations[index] = Operation::Link {
+                path: path.clone(),
+                object,
+            };
+        } else {
+            sent.push_back((local, *size));
+        }
+    }
+
+    Ok((operations, BatchReader::new(sent)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn batch_files_are_read_in_turn() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = VecDeque::new();
+
+        for (name, data, size) in [("a", &b"first"[..], 5), ("b", b"second, grown", 6)] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, data).unwrap();
+
+            files.push_back((path, size));
+        }
+
+        let mut sent = vec![];
+        BatchReader::new(files)
+            .read_to_end(&mut sent)
+            .await
+            .unwrap();
+
+        // only as much of each file is sent as was declared
+        assert_eq!(sent, b"firstsecond");
+
+        let short = dir.path().join("short");
+        std::fs::write(&short, b"cut").unwrap();
+
+        let mut reader = BatchReader::new(VecDeque::from([(short, 10)]));
+        let e = reader.read_to_end(&mut vec![]).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
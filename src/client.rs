@@ -1,9 +1,32 @@
 use std::io;
 use std::net::SocketAddr;
 
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite};
 use tokio::net::TcpStream;
 
+use crate::files::chunk::ManifestBuilder;
+use crate::files::{Object, Path};
 use crate::proto::{self, Method};
+use crate::server::methods;
+
+/// Work out the [Object] the server would store the contents of `reader` as, without sending anything
+pub async fn file_object(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Object> {
+    let mut manifest = ManifestBuilder::default();
+    let mut buf = vec![0; proto::FRAME_SIZE];
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+
+        if n == 0 {
+            break;
+        }
+
+        manifest.update(&buf[..n]);
+    }
+
+    Ok(manifest.finish().object())
+}
 
 pub struct Client {
     peer: TcpStream,
@@ -23,4 +46,70 @@ impl Client {
     ) -> anyhow::Result<M::Output> {
         proto::invoke(&mut self.peer, method, args).await
     }
+
+    /// Invoke a [Method] that does it's work on the server once it has returned, waiting for the result of the work
+    pub async fn run<'a, M: Method, T: DeserializeOwned>(
+        &mut self,
+        method: M,
+        args: M::Input<'a>,
+    ) -> anyhow::Result<T> {
+        self.invoke(method, args).await?;
+
+        proto::read_response(&mut self.peer).await
+    }
+
+    /// Invoke a [Method] that receives a stream from the client, sending the contents of `reader`. Returns the
+    /// server's response once the whole stream has been processed
+    pub async fn upload<'a, M: Method, T: DeserializeOwned>(
+        &mut self,
+        method: M,
+        args: M::Input<'a>,
+        reader: &mut (impl AsyncRead + Unpin),
+    ) -> anyhow::Result<T> {
+        self.invoke(method, args).await?;
+
+        proto::send_stream(&mut self.peer, reader).await?;
+
+        proto::read_response(&mut self.peer).await
+    }
+
+    /// Invoke a [Method] that sends a stream back to the client, writing it's contents to `writer`
+    pub async fn download<'a, M: Method>(
+        &mut self,
+        method: M,
+        args: M::Input<'a>,
+        writer: &mut (impl AsyncWrite + Unpin),
+    ) -> anyhow::Result<M::Output> {
+        let output = self.invoke(method, args).await?;
+
+        proto::recv_stream(&mut self.peer, writer).await?;
+
+        Ok(output)
+    }
+
+    /// Insert the contents of `file` at a given `path` in a `tree`. If the server already has them, they are linked
+    /// rather than sent again, which only costs a single round trip; otherwise `file` is read again to upload it
+    pub async fn insert_file(
+        &mut self,
+        tree: &str,
+        path: Path<'_>,
+        expected: Option<Object>,
+        file: &mut (impl AsyncRead + AsyncSeek + Unpin),
+    ) -> anyhow::Result<()> {
+        let object = file_object(file).await?;
+
+        let linked = self
+            .invoke(methods::fs::InsertObject, (tree, path, object, expected))
+            .await?;
+
+        if linked {
+            log::debug!("server already has the contents of {path}, so linked them");
+            return Ok(());
+        }
+
+        file.seek(io::SeekFrom::Start(0)).await?;
+
+        self.upload(methods::fs::Insert, (tree, path, expected), file)
+            .await
+    }
 }
@@ -1,6 +1,5 @@
-use std::io;
 use std::net::SocketAddr;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 use serde::Deserialize;
 
@@ -20,6 +19,108 @@ pub struct Server {
     pub addr: SocketAddr,
     /// Location of files database
     pub db: PathBuf,
+    /// Which revisions of each filesystem tree to keep
+    pub retention: Option<Retention>,
+    /// Where objects are stored
+    #[serde(default)]
+    pub storage: Storage,
+    /// How objects are compressed when they are stored
+    #[serde(default)]
+    pub compression: Compression,
+    /// Periodically write archives of the filesystem
+    pub backup: Option<Backup>,
+    /// Encrypt the database while it is stored on disk
+    pub encryption: Option<AtRest>,
+}
+
+/// Where a server stores the data of objects. The history of each tree is always kept in the database itself
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum Storage {
+    /// Store objects in the database, alongside everything else
+    #[default]
+    Sled,
+    /// Store each object as a file in a directory, which may be on a different disk to the database
+    Dir { dir: PathBuf },
+}
+
+/// How objects are compressed when they are stored in the database. Objects are always readable regardless of the
+/// codec they were stored with, so this can be changed at any time
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Compression {
+    /// The codec used to compress new objects
+    #[serde(default)]
+    pub codec: Codec,
+    /// The compression level passed to the codec
+    #[serde(default = "Compression::default_level")]
+    pub level: i32,
+}
+
+impl Compression {
+    fn default_level() -> i32 {
+        3
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression {
+            codec: Codec::default(),
+            level: Compression::default_level(),
+        }
+    }
+}
+
+/// A compression algorithm for objects
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// Store objects as-is
+    None,
+    /// Compress objects with `zstd`
+    #[default]
+    Zstd,
+}
+
+/// A retention policy for the history of filesystem trees. A revision is kept if any of the rules match it, and the
+/// latest revision is always kept. If no rules are given, every revision is kept.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Retention {
+    /// Keep the latest `n` revisions
+    pub keep_last: Option<usize>,
+    /// Keep every revision newer than this many seconds
+    pub keep_within: Option<u64>,
+    /// Keep the latest revision of each of the last `n` hours
+    pub keep_hourly: Option<usize>,
+    /// Keep the latest revision of each of the last `n` days
+    pub keep_daily: Option<usize>,
+    /// Keep the latest revision of each of the last `n` weeks
+    pub keep_weekly: Option<usize>,
+    /// Keep the latest revision of each of the last `n` months
+    pub keep_monthly: Option<usize>,
+    /// The time (in seconds) between automatically pruning history and collecting garbage
+    pub interval: Option<u64>,
+}
+
+/// Encryption of the values in the server's database while they are stored on disk, with a key given by either a file
+/// or an environment variable
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AtRest {
+    /// A file holding the key, as written by `ksync admin gen-encryption-key`
+    pub key: Option<PathBuf>,
+    /// An environment variable holding the key, as hex
+    pub key_env: Option<String>,
+}
+
+/// A policy for automatically writing `.tar.gz` archives of the latest revision of the filesystem
+#[derive(Deserialize, Debug, Clone)]
+pub struct Backup {
+    /// The directory archives are written to
+    pub dir: PathBuf,
+    /// The time (in seconds) between writing archives
+    pub interval: u64,
+    /// Keep the latest `n` archives, removing older ones. If not given, every archive is kept
+    pub keep: Option<usize>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -28,6 +129,17 @@ pub struct Sync {
     pub point: SyncPoint,
     pub resync_time: u64,
     pub key: PathBuf,
+    /// The filesystem tree on the server to synchronise with
+    #[serde(default = "Sync::default_tree")]
+    pub tree: String,
+    /// Encrypt files before they are sent to the server
+    pub encryption: Option<Encryption>,
+}
+
+impl Sync {
+    fn default_tree() -> String {
+        crate::files::DEFAULT_TREE.to_owned()
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -39,6 +151,19 @@ pub struct SyncPoint {
 pub struct Client {
     pub remote: SocketAddr,
     pub key: PathBuf,
+    /// Encrypt files before they are sent to the server
+    pub encryption: Option<Encryption>,
+}
+
+/// End-to-end encryption of files by a client, with a key the server never sees. Clients that share a tree must use the
+/// same settings
+#[derive(Deserialize, Debug, Clone)]
+pub struct Encryption {
+    /// A file holding the secret key, as written by `ksync admin gen-encryption-key`
+    pub key: PathBuf,
+    /// Also encrypt the names of files and directories
+    #[serde(default)]
+    pub names: bool,
 }
 
 #[derive(Debug, thiserror::Error)]
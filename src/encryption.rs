@@ -0,0 +1,782 @@
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+
+use std::io::{self, Write};
+use std::path::Path as SysPath;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use crate::config;
+use crate::files::chunk::{Chunker, MAX_CHUNK};
+use crate::files::Node;
+use crate::util::fmt::HexSlice;
+
+/// Marks a file's contents as encrypted, and is authenticated along with each of it's chunks
+const MAGIC: &[u8; 8] = b"ksyncen\0";
+
+/// The length of the header of each encrypted chunk; whether it is the last chunk of the file, and the length of the
+/// sealed chunk that follows
+const RECORD_HEADER_LEN: usize = 5;
+
+/// The length of an encryption key, in bytes
+pub const KEY_LEN: usize = 32;
+
+/// The length of the tag appended to each encrypted message
+const TAG_LEN: usize = 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("encryption key must be {KEY_LEN} bytes long")]
+    InvalidKey,
+
+    #[error("file is not encrypted")]
+    NotEncrypted,
+
+    #[error("failed to decrypt; it was encrypted with a different key, or has been tampered with")]
+    Decrypt,
+
+    #[error("encrypted file ended early")]
+    Truncated,
+
+    #[error("name '{name}' is not encrypted")]
+    InvalidName { name: String },
+}
+
+/// Encrypts file contents, and optionally the names of files and directories, before they are sent to the server,
+/// with a key the server never sees.
+///
+/// Encryption is deterministic: each message is encrypted with AES-256-GCM under a nonce derived from a keyed hash of
+/// the message itself, so the same contents or names always encrypt to the same bytes under the same key. This keeps
+/// the server's de-duplication working, and lets names be looked up, at the cost of revealing which files are
+/// identical. A nonce is only ever reused for the same message, which produces the same ciphertext.
+///
+/// The contents of a file are split into chunks the same way the server splits them, and each chunk is encrypted on
+/// it's own, so files are encrypted as they are streamed, and the parts two files have in common are only stored once.
+/// Only the last chunk is marked as such, so a file that has been cut short is detected, but the server could reorder
+/// the other chunks of a file, or splice in chunks of another file encrypted with the same key.
+pub struct Cipher {
+    contents: Keys,
+    /// Only present if names are encrypted
+    names: Option<Keys>,
+}
+
+/// The pair of keys used to encrypt one kind of message
+struct Keys {
+    cipher: LessSafeKey,
+    /// Derives the nonce of a message from it's contents
+    nonce: hmac::Key,
+}
+
+impl Keys {
+    /// Derive a pair of keys from the master key, for a given purpose
+    fn derive(master: &hmac::Key, purpose: &str) -> Keys {
+        let derive = |use_: &str| hmac::sign(master, format!("ksync {purpose} {use_}").as_bytes());
+
+        let cipher = UnboundKey::new(&AES_256_GCM, derive("cipher").as_ref()).unwrap();
+
+        Keys {
+            cipher: LessSafeKey::new(cipher),
+            nonce: hmac::Key::new(hmac::HMAC_SHA256, derive("nonce").as_ref()),
+        }
+    }
+
+    /// Encrypt `data`, returning the nonce followed by the ciphertext and it's tag. The nonce is derived from `aad` as
+    /// well as `data`, so a nonce is never reused with different `aad`
+    fn seal(&self, data: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut tag = hmac::Context::with_key(&self.nonce);
+        tag.update(aad);
+        tag.update(data);
+
+        let tag = tag.sign();
+        let nonce: [u8; NONCE_LEN] = tag.as_ref()[..NONCE_LEN].try_into().unwrap();
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + data.len() + TAG_LEN);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(data);
+
+        let tag = self
+            .cipher
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(aad),
+                &mut sealed[NONCE_LEN..],
+            )
+            .unwrap();
+
+        sealed.extend_from_slice(tag.as_ref());
+
+        sealed
+    }
+
+    /// Decrypt a message encrypted with [Keys::seal]
+    fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err(Error::Decrypt);
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| Error::Decrypt)?;
+
+        let mut data = ciphertext.to_vec();
+        let len = self
+            .cipher
+            .open_in_place(nonce, Aad::from(aad), &mut data)
+            .map_err(|_| Error::Decrypt)?
+            .len();
+
+        data.truncate(len);
+
+        Ok(data)
+    }
+}
+
+/// The data authenticated along with a chunk of a file
+fn chunk_aad(last: bool) -> [u8; MAGIC.len() + 1] {
+    let mut aad = [0; MAGIC.len() + 1];
+    aad[..MAGIC.len()].copy_from_slice(MAGIC);
+    aad[MAGIC.len()] = last as u8;
+
+    aad
+}
+
+/// Encrypts the contents of a file one chunk at a time. See [Cipher] for details
+pub struct Encryptor<'a> {
+    keys: &'a Keys,
+    chunker: Chunker,
+    /// The latest chunk, which is only encrypted once we know whether it is the last
+    held: Option<Vec<u8>>,
+    started: bool,
+}
+
+impl<'a> Encryptor<'a> {
+    /// Append a chunk to `out`
+    fn seal_chunk(&mut self, chunk: &[u8], last: bool, out: &mut Vec<u8>) {
+        if !self.started {
+            out.extend_from_slice(MAGIC);
+            self.started = true;
+        }
+
+        let sealed = self.keys.seal(chunk, &chunk_aad(last));
+
+        out.push(last as u8);
+        out.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+        out.extend_from_slice(&sealed);
+    }
+
+    /// Encrypt the next part of the file, appending whatever can be encrypted so far to `out`
+    pub fn push(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        let mut chunks = vec![];
+
+        self.chunker
+            .push(data, |chunk| {
+                chunks.push(chunk.to_vec());
+
+                Ok::<_, std::convert::Infallible>(())
+            })
+            .unwrap();
+
+        for chunk in chunks {
+            if let Some(held) = self.held.replace(chunk) {
+                self.seal_chunk(&held, false, out);
+            }
+        }
+    }
+
+    /// Encrypt the rest of the file, appending it to `out`
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        let mut chunks: Vec<_> = self.held.take().into_iter().collect();
+
+        std::mem::take(&mut self.chunker)
+            .finish(|chunk| {
+                chunks.push(chunk.to_vec());
+
+                Ok::<_, std::convert::Infallible>(())
+            })
+            .unwrap();
+
+        // an empty file is a single, empty, last chunk
+        let last = chunks.pop().unwrap_or_default();
+
+        for chunk in chunks {
+            self.seal_chunk(&chunk, false, out);
+        }
+
+        self.seal_chunk(&last, true, out);
+    }
+}
+
+/// Decrypts the contents of a file encrypted by an [Encryptor] as they are received
+pub struct Decryptor<'a> {
+    keys: &'a Keys,
+    buf: Vec<u8>,
+    started: bool,
+    /// Whether the last chunk has been decrypted
+    ended: bool,
+}
+
+impl<'a> Decryptor<'a> {
+    /// Decrypt the next part of the file, appending whatever can be decrypted so far to `out`
+    pub fn push(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+        self.buf.extend_from_slice(data);
+
+        let mut offset = 0;
+
+        if !self.started {
+            if self.buf.len() < MAGIC.len() {
+                return Ok(());
+            }
+
+            if !self.buf.starts_with(MAGIC) {
+                return Err(Error::NotEncrypted);
+            }
+
+            offset = MAGIC.len();
+            self.started = true;
+        }
+
+        while self.buf.len() - offset >= RECORD_HEADER_LEN {
+            let header = &self.buf[offset..offset + RECORD_HEADER_LEN];
+            let last = match header[0] {
+                0 => false,
+                1 => true,
+                _ => return Err(Error::Decrypt),
+            };
+            let len = u32::from_le_bytes(header[1..].try_into().unwrap()) as usize;
+
+            // nothing may follow the last chunk, and no chunk is larger than the chunker cuts them
+            if self.ended || len > MAX_CHUNK as usize + NONCE_LEN + TAG_LEN {
+                return Err(Error::Decrypt);
+            }
+
+            let start = offset + RECORD_HEADER_LEN;
+
+            if self.buf.len() - start < len {
+                break;
+            }
+
+            let chunk = self
+                .keys
+                .open(&self.buf[start..start + len], &chunk_aad(last))?;
+            out.extend_from_slice(&chunk);
+
+            self.ended = last;
+            offset = start + len;
+        }
+
+        self.buf.drain(..offset);
+
+        Ok(())
+    }
+
+    /// Check that the whole file has been decrypted
+    pub fn finish(&self) -> Result<(), Error> {
+        match (self.started, self.ended) {
+            (false, _) if self.buf.is_empty() => Err(Error::Truncated),
+            (false, _) => Err(Error::NotEncrypted),
+            (true, false) => Err(Error::Truncated),
+            (true, true) => Ok(()),
+        }
+    }
+}
+
+/// Encrypts the contents of a file as they are read from an inner reader. Can only be rewound to the start, which
+/// rewinds the inner reader
+pub struct EncryptReader<'a, R> {
+    encryptor: Encryptor<'a>,
+    inner: R,
+    /// Data read from the inner reader
+    buf: Vec<u8>,
+    /// Encrypted data waiting to be read
+    out: Vec<u8>,
+    offset: usize,
+    done: bool,
+}
+
+impl<'a, R: AsyncRead + Unpin> AsyncRead for EncryptReader<'a, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        while this.offset == this.out.len() {
+            if this.done {
+                return Poll::Ready(Ok(()));
+            }
+
+            this.out.clear();
+            this.offset = 0;
+
+            let mut input = ReadBuf::new(&mut this.buf);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut input))?;
+
+            if input.filled().is_empty() {
+                this.encryptor.finish(&mut this.out);
+                this.done = true;
+            } else {
+                this.encryptor.push(input.filled(), &mut this.out);
+            }
+        }
+
+        let n = buf.remaining().min(this.out.len() - this.offset);
+        buf.put_slice(&this.out[this.offset..this.offset + n]);
+        this.offset += n;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<'a, R: AsyncSeek + Unpin> AsyncSeek for EncryptReader<'a, R> {
+    fn start_seek(mut self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        if position != io::SeekFrom::Start(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an encrypted file can only be rewound to the start",
+            ));
+        }
+
+        let this = &mut *self;
+        Pin::new(&mut this.inner).start_seek(position)?;
+
+        this.encryptor = Encryptor {
+            keys: this.encryptor.keys,
+            chunker: Chunker::default(),
+            held: None,
+            started: false,
+        };
+        this.out.clear();
+        this.offset = 0;
+        this.done = false;
+
+        Ok(())
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.inner).poll_complete(cx)
+    }
+}
+
+/// Decrypts the contents of a file as they are written, writing them to an inner writer. [DecryptWriter::finish] must
+/// be called once it has been flushed, to check that the whole file was received
+pub struct DecryptWriter<'a, W> {
+    decryptor: Decryptor<'a>,
+    inner: W,
+    /// Decrypted data waiting to be written to the inner writer
+    out: Vec<u8>,
+    offset: usize,
+}
+
+impl<'a, W: AsyncWrite + Unpin> DecryptWriter<'a, W> {
+    /// Write everything that has been decrypted so far to the inner writer
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.offset < self.out.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out[self.offset..]))?;
+
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+
+            self.offset += n;
+        }
+
+        self.out.clear();
+        self.offset = 0;
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Check that the whole file was received, returning the inner writer
+    pub fn finish(self) -> Result<W, Error> {
+        self.decryptor.finish()?;
+
+        Ok(self.inner)
+    }
+}
+
+impl<'a, W: AsyncWrite + Unpin> AsyncWrite for DecryptWriter<'a, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_drain(cx))?;
+
+        this.decryptor
+            .push(buf, &mut this.out)
+            .map_err(io::Error::other)?;
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Parse a string of hex digits into bytes
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+impl Cipher {
+    /// Create a [Cipher] from a secret `key`, which also encrypts names if `names` is set
+    pub fn new(key: &[u8], names: bool) -> Result<Cipher, Error> {
+        if key.len() != KEY_LEN {
+            return Err(Error::InvalidKey);
+        }
+
+        let master = hmac::Key::new(hmac::HMAC_SHA256, key);
+
+        Ok(Cipher {
+            contents: Keys::derive(&master, "contents"),
+            names: names.then(|| Keys::derive(&master, "names")),
+        })
+    }
+
+    /// Load the key named by an encryption config
+    pub async fn load(config: &config::Encryption) -> anyhow::Result<Cipher> {
+        let key = tokio::fs::read(&config.key).await?;
+
+        Ok(Cipher::new(&key, config.names)?)
+    }
+
+    /// Generate a new random key, and write it to `path`. The file is only readable by it's owner, and an existing
+    /// file is never overwritten, as that would lose the key it holds
+    pub fn generate(path: &SysPath) -> anyhow::Result<()> {
+        let mut key = [0; KEY_LEN];
+        SystemRandom::new()
+            .fill(&mut key)
+            .map_err(|_| anyhow::anyhow!("failed to generate key"))?;
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        options.open(path)?.write_all(&key)?;
+
+        Ok(())
+    }
+
+    /// Begin encrypting the contents of a file. See [Encryptor] for details
+    pub fn encryptor(&self) -> Encryptor<'_> {
+        Encryptor {
+            keys: &self.contents,
+            chunker: Chunker::default(),
+            held: None,
+            started: false,
+        }
+    }
+
+    /// Begin decrypting the contents of a file encrypted by an [Encryptor]
+    pub fn decryptor(&self) -> Decryptor<'_> {
+        Decryptor {
+            keys: &self.contents,
+            buf: vec![],
+            started: false,
+            ended: false,
+        }
+    }
+
+    /// Encrypt the contents of a file as they are read from `inner`
+    pub fn encrypt_reader<R>(&self, inner: R) -> EncryptReader<'_, R> {
+        EncryptReader {
+            encryptor: self.encryptor(),
+            inner,
+            buf: vec![0; MAX_CHUNK as usize],
+            out: vec![],
+            offset: 0,
+            done: false,
+        }
+    }
+
+    /// Decrypt the contents of a file as they are written, writing them to `inner`
+    pub fn decrypt_writer<W>(&self, inner: W) -> DecryptWriter<'_, W> {
+        DecryptWriter {
+            decryptor: self.decryptor(),
+            inner,
+            out: vec![],
+            offset: 0,
+        }
+    }
+
+    /// Encrypt each component of a path, if names are encrypted. The root stays as `/`
+    pub fn encrypt_path(&self, path: &str) -> String {
+        let Some(names) = &self.names else {
+            return path.to_owned();
+        };
+
+        if path == "/" {
+            return path.to_owned();
+        }
+
+        path.split('/')
+            .skip(1)
+            .map(|name| format!("/{}", HexSlice::from(&names.seal(name.as_bytes(), b"")[..])))
+            .collect()
+    }
+
+    /// Decrypt a single name encrypted by [Cipher::encrypt_path]
+    fn decrypt_name(&self, name: &str) -> Result<String, Error> {
+        let Some(names) = &self.names else {
+            return Ok(name.to_owned());
+        };
+
+        let invalid = || Error::InvalidName {
+            name: name.to_owned(),
+        };
+
+        let sealed = decode_hex(name).ok_or_else(invalid)?;
+        let name = names.open(&sealed, b"")?;
+
+        String::from_utf8(name).map_err(|_| Error::Decrypt)
+    }
+
+    /// Decrypt each component of a path encrypted with [Cipher::encrypt_path]
+    pub fn decrypt_path(&self, path: &str) -> Result<String, Error> {
+        if self.names.is_none() || path == "/" {
+            return Ok(path.to_owned());
+        }
+
+        path.split('/')
+            .skip(1)
+            .map(|name| Ok(format!("/{}", self.decrypt_name(name)?)))
+            .collect()
+    }
+
+    /// Decrypt a path for display, leaving it as it is if it was not encrypted with this key
+    pub fn display_path(&self, path: &str) -> String {
+        self.decrypt_path(path).unwrap_or_else(|_| path.to_owned())
+    }
+
+    /// Decrypt the names of every child below a [Node] fetched from the server. Children whose names were not encrypted
+    /// with this key are left out, as they can't have been written by a client sharing it
+    pub fn decrypt_node(&self, node: &mut Node) {
+        if self.names.is_none() {
+            return;
+        }
+
+        let Some(children) = node.dir_mut() else {
+            return;
+        };
+
+        *children = std::mem::take(children)
+            .into_iter()
+            .filter_map(|(name, mut child)| {
+                let name = match self.decrypt_name(&name) {
+                    Ok(name) => name,
+                    Err(e) => {
+                        log::warn!("skipping '{name}': {e}");
+                        return None;
+                    }
+                };
+
+                self.decrypt_node(&mut child);
+
+                Some((name, child))
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cipher, Error, KEY_LEN};
+    use crate::files::{tests, Node, Path};
+
+    #[test]
+    fn generated_keys_are_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+
+        Cipher::generate(&path).unwrap();
+        let key = std::fs::read(&path).unwrap();
+
+        assert_eq!(key.len(), KEY_LEN);
+        assert!(Cipher::new(&key, false).is_ok());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // an existing key is never overwritten
+        assert!(Cipher::generate(&path).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), key);
+    }
+
+    /// Encrypt `data` with an [Encryptor](super::Encryptor), writing it in pieces of `size` bytes
+    fn encrypt(cipher: &Cipher, data: &[u8], size: usize) -> Vec<u8> {
+        let mut encryptor = cipher.encryptor();
+        let mut encrypted = vec![];
+
+        for piece in data.chunks(size) {
+            encryptor.push(piece, &mut encrypted);
+        }
+
+        encryptor.finish(&mut encrypted);
+
+        encrypted
+    }
+
+    /// Decrypt `data` with a [Decryptor](super::Decryptor), writing it in pieces of 1000 bytes
+    fn decrypt(cipher: &Cipher, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut decryptor = cipher.decryptor();
+        let mut decrypted = vec![];
+
+        for piece in data.chunks(1000) {
+            decryptor.push(piece, &mut decrypted)?;
+        }
+
+        decryptor.finish()?;
+
+        Ok(decrypted)
+    }
+
+    #[test]
+    fn contents_are_encrypted() {
+        let cipher = Cipher::new(&[1; KEY_LEN], false).unwrap();
+        let data = b"passwords";
+
+        let encrypted = encrypt(&cipher, data, 4);
+
+        assert!(!encrypted
+            .windows(data.len())
+            .any(|window| window == &data[..]));
+        assert_eq!(decrypt(&cipher, &encrypted).unwrap(), data);
+
+        // the same contents always encrypt the same way, so they are de-duplicated
+        assert_eq!(encrypt(&cipher, data, 100), encrypted);
+        assert_ne!(encrypt(&cipher, b"passwordz", 100), encrypted);
+
+        // but not under another key
+        let other = Cipher::new(&[2; KEY_LEN], false).unwrap();
+        assert_ne!(encrypt(&other, data, 100), encrypted);
+        assert!(matches!(decrypt(&other, &encrypted), Err(Error::Decrypt)));
+
+        // tampering is detected
+        let mut tampered = encrypted.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(decrypt(&cipher, &tampered), Err(Error::Decrypt)));
+
+        assert!(matches!(decrypt(&cipher, data), Err(Error::NotEncrypted)));
+        assert_eq!(decrypt(&cipher, &encrypt(&cipher, b"", 1)).unwrap(), b"");
+        assert!(Cipher::new(&[1; 16], false).is_err());
+    }
+
+    #[test]
+    fn chunks_are_encrypted_separately() {
+        let cipher = Cipher::new(&[1; KEY_LEN], false).unwrap();
+        let data = tests::data(1, 1024 * 1024);
+
+        let encrypted = encrypt(&cipher, &data, 100_000);
+        assert_eq!(encrypt(&cipher, &data, data.len()), encrypted);
+        assert_eq!(decrypt(&cipher, &encrypted).unwrap(), data);
+
+        // changing the end of a file leaves the start of it's encrypted contents the same
+        let mut changed = data.clone();
+        changed.extend_from_slice(b"more");
+
+        let other = encrypt(&cipher, &changed, 100_000);
+        let common = encrypted
+            .iter()
+            .zip(&other)
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        assert!(common > data.len() / 2);
+        assert_eq!(decrypt(&cipher, &other).unwrap(), changed);
+
+        // a file that has been cut short is detected, even where a chunk ends
+        let len = u32::from_le_bytes(encrypted[9..13].try_into().unwrap()) as usize;
+
+        for end in [encrypted.len() - 1, 13 + len] {
+            assert!(matches!(
+                decrypt(&cipher, &encrypted[..end]),
+                Err(Error::Truncated)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn files_are_encrypted_as_they_are_streamed() {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+        let cipher = Cipher::new(&[1; KEY_LEN], true).unwrap();
+        let data = tests::data(2, 700 * 1024);
+
+        let mut reader = cipher.encrypt_reader(std::io::Cursor::new(data.clone()));
+        let mut encrypted = vec![];
+        reader.read_to_end(&mut encrypted).await.unwrap();
+
+        assert_eq!(encrypted, encrypt(&cipher, &data, data.len()));
+
+        // rewinding starts encrypting again from the start
+        reader.rewind().await.unwrap();
+        let mut again = vec![];
+        reader.read_to_end(&mut again).await.unwrap();
+        assert_eq!(again, encrypted);
+
+        let mut decrypted = vec![];
+        let mut writer = cipher.decrypt_writer(&mut decrypted);
+
+        for piece in encrypted.chunks(5000) {
+            writer.write_all(piece).await.unwrap();
+        }
+
+        writer.flush().await.unwrap();
+        writer.finish().unwrap();
+        assert_eq!(decrypted, data);
+    }
+
+    #[test]
+    fn names_are_encrypted() {
+        let cipher = Cipher::new(&[1; KEY_LEN], true).unwrap();
+
+        let path = cipher.encrypt_path("/tax/2023.pdf");
+        let same_dir = cipher.encrypt_path("/tax/2022.pdf");
+
+        assert!(!path.contains("tax"));
+        assert!(Path::new(&path).is_ok());
+        assert_eq!(path.split('/').nth(1), same_dir.split('/').nth(1));
+        assert_eq!(cipher.decrypt_path(&path).unwrap(), "/tax/2023.pdf");
+        assert_eq!(cipher.encrypt_path("/"), "/");
+
+        // names are left alone unless they are to be encrypted
+        let contents_only = Cipher::new(&[1; KEY_LEN], false).unwrap();
+        assert_eq!(contents_only.encrypt_path("/tax/2023.pdf"), "/tax/2023.pdf");
+
+        let mut node = Node::new_dir();
+        node.make_dir_recursive(Path::new(&cipher.encrypt_path("/tax")).unwrap())
+            .unwrap();
+        node.make_dir(Path::new("/plain").unwrap()).unwrap();
+
+        cipher.decrypt_node(&mut node);
+
+        let paths: Vec<_> = node.iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, ["/", "/tax"]);
+    }
+}
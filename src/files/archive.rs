@@ -0,0 +1,761 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use crate::config::Backup;
+use crate::files::gc::Pending;
+use crate::files::{Error, Files, Node, Object, Path, Revision};
+
+/// The prefix of the name of every archive written by [Files::backup], used to tell them apart from other files when
+/// rotating old backups
+const BACKUP_PREFIX: &str = "ksync-";
+
+/// Reads the contents of a file one chunk at a time, so that it never has to be held in memory all at once
+struct FileReader<'a> {
+    files: &'a Files,
+    chunks: VecDeque<Object>,
+    chunk: Vec<u8>,
+    offset: usize,
+}
+
+impl<'a> Read for FileReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.chunk.len() {
+            let Some(chunk) = self.chunks.pop_front() else {
+                return Ok(0);
+            };
+
+            self.chunk = self.files.load(&chunk).map_err(io::Error::other)?;
+            self.offset = 0;
+        }
+
+        let n = buf.len().min(self.chunk.len() - self.offset);
+        buf[..n].copy_from_slice(&self.chunk[self.offset..self.offset + n]);
+        self.offset += n;
+
+        Ok(n)
+    }
+}
+
+/// A tar header for an entry modified at a given `timestamp`
+fn header(timestamp: u128, size: u64, mode: u32, kind: tar::EntryType) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+
+    header.set_entry_type(kind);
+    header.set_size(size);
+    header.set_mode(mode);
+    header.set_mtime((timestamp / 1_000_000_000) as u64);
+
+    header
+}
+
+/// Append a directory to an `archive`
+fn append_dir<W: Write>(
+    archive: &mut tar::Builder<W>,
+    name: &str,
+    timestamp: u128,
+) -> Result<(), Error> {
+    let mut header = header(timestamp, 0, 0o755, tar::EntryType::Directory);
+    archive.append_data(&mut header, format!("{name}/"), io::empty())?;
+
+    Ok(())
+}
+
+impl Files {
+    /// Write a `revision` of a `tree` at a given `path` to `writer` as a `.tar.gz` archive, with paths in the archive
+    /// relative to `path`
+    pub fn export(
+        &self,
+        tree: &str,
+        path: Path,
+        revision: Revision,
+        writer: impl Write,
+    ) -> Result<(), Error> {
+        log::info!("exporting '{path}' from tree '{tree}' at revision {revision:?}");
+
+        let mut archive = tar::Builder::new(GzEncoder::new(writer, flate2::Compression::default()));
+
+        self.append_tree(&mut archive, "", tree, path, revision)?;
+
+        archive.into_inner()?.finish()?;
+
+        Ok(())
+    }
+
+    /// Append a `revision` of a `tree` at a given `path` to an `archive`, with paths in the archive relative to `path`
+    /// and starting with `prefix`
+    fn append_tree<W: Write>(
+        &self,
+        archive: &mut tar::Builder<W>,
+        prefix: &str,
+        tree: &str,
+        path: Path,
+        revision: Revision,
+    ) -> Result<(), Error> {
+        let node = self
+            .get_node(tree, path, revision)?
+            .filter(|node| node.data().is_some())
+            .ok_or(Error::NotFound {
+                path: path.as_str().to_owned(),
+            })?;
+
+        if let Some(object) = node.file() {
+            // a single file is archived under it's own name
+            let (_, name) = path.parent_child();
+            let name = format!("{prefix}{}", name.unwrap_or_default());
+
+            self.append_file(archive, &name, &node, *object)?;
+        } else {
+            for (child, node) in node.iter() {
+                let name = child.trim_start_matches('/');
+
+                // skip the directory being exported, and anything that has been deleted
+                if name.is_empty() || node.data().is_none() {
+                    continue;
+                }
+
+                let name = format!("{prefix}{name}");
+
+                if let Some(object) = node.file() {
+                    self.append_file(archive, &name, node, *object)?;
+                } else {
+                    append_dir(archive, &name, node.timestamp())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Append the contents of a file to an `archive`
+    fn append_file<W: Write>(
+        &self,
+        archive: &mut tar::Builder<W>,
+        name: &str,
+        node: &Node,
+        object: Object,
+    ) -> Result<(), Error> {
+        let manifest = self.manifest(&object)?;
+
+        let reader = FileReader {
+            files: self,
+            chunks: manifest.chunks().iter().copied().collect(),
+            chunk: vec![],
+            offset: 0,
+        };
+
+        let mut header = header(
+            node.timestamp(),
+            manifest.size(),
+            0o644,
+            tar::EntryType::Regular,
+        );
+        archive.append_data(&mut header, name, reader)?;
+
+        Ok(())
+    }
+
+    /// Write an archive of the latest revision of every tree into the directory given by a [Backup] policy, removing
+    /// the oldest archives beyond the number it keeps. Each tree is archived under a directory named after it. Returns
+    /// the path of the new archive
+    pub fn backup(&self, policy: &Backup) -> Result<PathBuf, Error> {
+        self.backup_through(policy, |file| Box::new(file))
+    }
+
+    /// [Files::backup], writing the archive to it's temporary file through the writer returned by `wrap`
+    fn backup_through<F>(&self, policy: &Backup, wrap: F) -> Result<PathBuf, Error>
+    where
+        F: for<'f> FnOnce(&'f File) -> Box<dyn Write + 'f>,
+    {
+        std::fs::create_dir_all(&policy.dir)?;
+
+        let name = format!(
+            "{BACKUP_PREFIX}{}.tar.gz",
+            chrono::Utc::now().format("%Y-%m-%dT%H-%M-%S")
+        );
+        let path = policy.dir.join(name);
+
+        // write to a temporary file, so that a failed backup doesn't leave a partial archive behind
+        let tmp = tempfile::NamedTempFile::new_in(&policy.dir)?;
+        let writer = GzEncoder::new(
+            io::BufWriter::new(wrap(tmp.as_file())),
+            flate2::Compression::default(),
+        );
+        let mut archive = tar::Builder::new(writer);
+
+        for tree in self.list_trees()? {
+            append_dir(&mut archive, &tree.name, tree.modified)?;
+
+            let prefix = format!("{}/", tree.name);
+            let result = self.append_tree(
+                &mut archive,
+                &prefix,
+                &tree.name,
+                Path::new("/")?,
+                Revision::FromLatest(0),
+            );
+
+            match result {
+                // the tree was deleted since it was listed
+                Err(Error::TreeNotFound { .. }) => (),
+                result => result?,
+            }
+        }
+
+        // the end of the archive is only written when the buffer is flushed, which may fail
+        archive
+            .into_inner()?
+            .finish()?
+            .into_inner()
+            .map_err(|e| e.into_error())?;
+
+        tmp.as_file().sync_all()?;
+        tmp.persist(&path).map_err(|e| e.error)?;
+
+        log::info!("wrote backup to {path:?}");
+
+        if let Some(keep) = policy.keep {
+            // archives are named by the time they were written, so sorting them by name puts the oldest first
+            let mut archives = vec![];
+
+            for entry in std::fs::read_dir(&policy.dir)? {
+                let name = entry?.file_name().to_string_lossy().into_owned();
+
+                if name.starts_with(BACKUP_PREFIX) && name.ends_with(".tar.gz") {
+                    archives.push(name);
+                }
+            }
+
+            archives.sort();
+
+            for name in archives.iter().rev().skip(keep) {
+                log::info!("removing old backup '{name}'");
+                std::fs::remove_file(policy.dir.join(name))?;
+            }
+        }
+
+        Ok(path)
+    }
+}
+
+/// The entries of an archive that has been read, but not yet linked into the filesystem
+struct Entries {
+    dirs: Vec<String>,
+    files: Vec<(String, Object)>,
+    /// Stored files are not linked until the whole archive has been read, so must not be collected before then
+    pending: Pending,
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidArchive {
+        reason: reason.into(),
+    }
+}
+
+/// Resolve the `name` of an entry in an archive to a path under the one being imported to. Returns `None` for the
+/// directory being imported to itself
+fn join(path: Path, name: &str) -> Result<Option<String>, Error> {
+    let mut joined = path.as_str().trim_end_matches('/').to_owned();
+    let mut empty = true;
+
+    for part in name.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => return Err(invalid(format!("'{name}' escapes the archive"))),
+
+            part => {
+                joined.push('/');
+                joined.push_str(part);
+                empty = false;
+            }
+        }
+    }
+
+    Ok((!empty).then_some(joined))
+}
+
+impl Files {
+    /// Read a tar (or `.tar.gz`) archive from `reader`, and insert it's contents under a given `path` in a `tree` as a
+    /// single revision. The contents of each file are stored as they are read, but nothing is linked until the whole
+    /// archive has been. Returns the number of files imported
+    pub fn import(&self, tree: &str, path: Path, mut reader: impl Read) -> Result<usize, Error> {
+        // check the tree exists up front, rather than once the whole archive has been read
+        self.get_history(tree)?;
+
+        // every gzip stream starts with the same two bytes
+        let mut magic = vec![];
+        (&mut reader).take(2).read_to_end(&mut magic)?;
+        let input = (&magic[..]).chain(&mut reader);
+
+        let entries = if magic == [0x1f, 0x8b] {
+            self.read_archive(path, GzDecoder::new(input))?
+        } else {
+            self.read_archive(path, input)?
+        };
+
+        // anything after the end of the archive is ignored, but it must still arrive before the archive is linked
+        io::copy(&mut reader, &mut io::sink())?;
+
+        log::info!(
+            "importing {} files and {} directories to '{path}' in tree '{tree}'",
+            entries.files.len(),
+            entries.dirs.len(),
+        );
+
+        self.with_root_mut(tree, |tree| {
+            tree.make_dir_recursive(path)?;
+
+            for dir in &entries.dirs {
+                tree.make_dir_recursive(Path::new(dir)?)?;
+            }
+
+            for (path, object) in &entries.files {
+                let path = Path::new(path)?;
+                let (parent, _) = path.parent_child();
+
+                tree.make_dir_recursive(parent)?;
+                tree.insert(path, *object)?;
+            }
+
+            Ok(())
+        })?;
+
+        Ok(entries.files.len())
+    }
+
+    /// Store the contents of every file in a tar `archive`, returning where each of it's entries goes under `path`
+    fn read_archive(&self, path: Path, archive: impl Read) -> Result<Entries, Error> {
+        let mut archive = tar::Archive::new(archive);
+        let mut buf = vec![0; 64 * 1024];
+
+        let mut entries = Entries {
+            dirs: vec![],
+            files: vec![],
+            pending: self.collector.pending(),
+        };
+
+        for entry in archive.entries().map_err(|e| invalid(e.to_string()))? {
+            let mut entry = entry.map_err(|e| invalid(e.to_string()))?;
+
+            let name = String::from_utf8(entry.path_bytes().into_owned())
+                .map_err(|_| invalid("entry name is not valid UTF-8"))?;
+
+            match entry.header().entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    let Some(file) = join(path, &name)? else {
+                        return Err(invalid(format!("'{name}' is not a valid file name")));
+                    };
+
+                    let mut writer = self.file_writer();
+                    let mut remaining = entry.size();
+
+                    loop {
+                        let n = entry.read(&mut buf).map_err(|e| invalid(e.to_string()))?;
+
+                        if n == 0 {
+                            break;
+                        }
+
+                        writer.write(self, &buf[..n])?;
+                        remaining = remaining.saturating_sub(n as u64);
+                    }
+
+                    // the tar crate doesn't notice an archive that ends part-way through an entry
+                    if remaining > 0 {
+                        return Err(invalid("archive ended part-way through an entry"));
+                    }
+
+                    entries.files.push((file, writer.finish(self)?));
+                    entries.pending.extend(writer.into_pending());
+                }
+
+                tar::EntryType::Directory => {
+                    if let Some(dir) = join(path, &name)? {
+                        entries.dirs.push(dir);
+                    }
+                }
+
+                tar::EntryType::XGlobalHeader => (),
+                kind => log::warn!("skipping '{name}' of unsupported type {kind:?}"),
+            }
+        }
+
+        // read the rest of a compressed archive, so that it's checksum is checked
+        io::copy(&mut archive.into_inner(), &mut io::sink())?;
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::read::GzDecoder;
+
+    use std::io::{Read, Write};
+
+    use crate::config::Backup;
+    use crate::files::{tests, Error, Files, Path, Revision, DEFAULT_TREE};
+
+    /// The entries of a `.tar.gz` archive, along with the contents of each file
+    fn entries(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut archive = tar::Archive::new(GzDecoder::new(archive));
+
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+
+                let mut data = vec![];
+                entry.read_to_end(&mut data).unwrap();
+
+                (path, data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn export_subtree() {
+        let files = tests::open();
+        let big = tests::data(8, 700 * 1024);
+
+        files.insert(Path::new("/a/big").unwrap(), &big).unwrap();
+        files
+            .insert(Path::new("/a/b/small").unwrap(), b"small")
+            .unwrap();
+        files
+            .insert(Path::new("/a/gone").unwrap(), b"gone")
+            .unwrap();
+        files
+            .insert(Path::new("/other").unwrap(), b"other")
+            .unwrap();
+        files
+            .delete(DEFAULT_TREE, Path::new("/a/gone").unwrap(), None)
+            .unwrap();
+
+        let mut archive = vec![];
+        files
+            .export(
+                DEFAULT_TREE,
+                Path::new("/a").unwrap(),
+                Revision::FromLatest(0),
+                &mut archive,
+            )
+            .unwrap();
+
+        let mut entries = entries(&archive);
+        entries.sort();
+
+        assert_eq!(
+            entries,
+            [
+                ("b/".to_owned(), vec![]),
+                ("b/small".to_owned(), b"small".to_vec()),
+                ("big".to_owned(), big),
+            ]
+        );
+    }
+
+    #[test]
+    fn export_old_revision() {
+        let files = tests::open();
+        let path = Path::new("/file").unwrap();
+
+        files.insert(path, b"first").unwrap();
+        files.insert(path, b"second").unwrap();
+
+        let mut archive = vec![];
+        files
+            .export(DEFAULT_TREE, path, Revision::FromLatest(1), &mut archive)
+            .unwrap();
+
+        assert_eq!(entries(&archive), [("file".to_owned(), b"first".to_vec())]);
+    }
+
+    #[test]
+    fn backups_are_rotated() {
+        let files = tests::open();
+        let dir = tempfile::tempdir().unwrap();
+
+        files.insert(Path::new("/file").unwrap(), b"data").unwrap();
+
+        files.create_tree("photos").unwrap();
+        let photo = files.write_file(b"photo").unwrap();
+        files
+            .insert_object("photos", Path::new("/photo").unwrap(), photo, None)
+            .unwrap();
+
+        for old in [
+            "ksync-2000-01-01T00-00-00.tar.gz",
+            "ksync-2000-01-02T00-00-00.tar.gz",
+        ] {
+            std::fs::write(dir.path().join(old), b"").unwrap();
+        }
+
+        std::fs::write(dir.path().join("unrelated"), b"").unwrap();
+
+        let policy = Backup {
+            dir: dir.path().to_owned(),
+            interval: 1,
+            keep: Some(2),
+        };
+
+        let path = files.backup(&policy).unwrap();
+
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[0], "ksync-2000-01-02T00-00-00.tar.gz");
+        assert_eq!(names[2], "unrelated");
+
+        // every tree is archived under a directory named after it
+        let archive = std::fs::read(path).unwrap();
+        assert_eq!(
+            entries(&archive),
+            [
+                ("fs/".to_owned(), vec![]),
+                ("fs/file".to_owned(), b"data".to_vec()),
+                ("photos/".to_owned(), vec![]),
+                ("photos/photo".to_owned(), b"photo".to_vec()),
+            ]
+        );
+    }
+
+    /// Fails every write, as a full disk would
+    struct Full;
+
+    impl Write for Full {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::StorageFull.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_backups_are_not_kept() {
+        let files = tests::open();
+        let dir = tempfile::tempdir().unwrap();
+
+        files.insert(Path::new("/file").unwrap(), b"data").unwrap();
+
+        let policy = Backup {
+            dir: dir.path().to_owned(),
+            interval: 1,
+            keep: None,
+        };
+
+        // the archive is small enough that nothing is written until it's buffer is flushed
+        assert!(files.backup_through(&policy, |_| Box::new(Full)).is_err());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    /// Reads `data` in pieces of at most `size` bytes, as it would arrive from a client
+    struct Pieces<'a> {
+        data: &'a [u8],
+        size: usize,
+    }
+
+    impl<'a> Read for Pieces<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.size).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+
+            Ok(n)
+        }
+    }
+
+    /// Import an `archive` to a given `path`, reading it in pieces of `size` bytes
+    fn import(files: &Files, path: &str, archive: &[u8], size: usize) -> Result<usize, Error> {
+        let pieces = Pieces {
+            data: archive,
+            size,
+        };
+
+        files.import(DEFAULT_TREE, Path::new(path).unwrap(), pieces)
+    }
+
+    /// A tar archive of the given directories and files
+    fn archive(dirs: &[&str], entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = tar::Builder::new(vec![]);
+
+        for dir in dirs {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_size(0);
+
+            archive
+                .append_data(&mut header, dir, std::io::empty())
+                .unwrap();
+        }
+
+        for (path, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+
+            archive.append_data(&mut header, path, *data).unwrap();
+        }
+
+        archive.into_inner().unwrap()
+    }
+
+    #[test]
+    fn import_as_single_revision() {
+        let files = tests::open();
+        let big = tests::data(9, 600 * 1024);
+        let long = format!("./nested/{}", "x".repeat(150));
+
+        let archive = archive(
+            &["empty/"],
+            &[
+                ("./big", &big),
+                ("nested/small", b"small"),
+                (&long, b"long name"),
+            ],
+        );
+
+        let history = files.get_history(DEFAULT_TREE).unwrap().len();
+
+        // read the archive in small, odd-sized pieces, to split every header
+        assert_eq!(import(&files, "/imported", &archive, 7).unwrap(), 3);
+        assert_eq!(files.get_history(DEFAULT_TREE).unwrap().len(), history + 1);
+
+        let get = |path: &str| {
+            files
+                .get(Path::new(path).unwrap(), Revision::FromLatest(0))
+                .unwrap()
+        };
+
+        assert_eq!(get("/imported/big").unwrap(), big);
+        assert_eq!(get("/imported/nested/small").unwrap(), b"small");
+        assert_eq!(
+            get(&format!("/imported/{}", &long[2..])).unwrap(),
+            b"long name"
+        );
+
+        let empty = files
+            .get_node(
+                DEFAULT_TREE,
+                Path::new("/imported/empty").unwrap(),
+                Revision::FromLatest(0),
+            )
+            .unwrap()
+            .unwrap();
+        assert!(empty.is_dir());
+    }
+
+    #[test]
+    fn exports_can_be_imported() {
+        let files = tests::open();
+
+        files.insert(Path::new("/a/b").unwrap(), b"b").unwrap();
+        files.insert(Path::new("/a/c/d").unwrap(), b"d").unwrap();
+
+        let mut exported = vec![];
+        files
+            .export(
+                DEFAULT_TREE,
+                Path::new("/a").unwrap(),
+                Revision::FromLatest(0),
+                &mut exported,
+            )
+            .unwrap();
+
+        assert_eq!(import(&files, "/copy", &exported, 1000).unwrap(), 2);
+
+        let get = |path: &str| {
+            files
+                .get(Path::new(path).unwrap(), Revision::FromLatest(0))
+                .unwrap()
+        };
+
+        assert_eq!(get("/copy/b").unwrap(), b"b");
+        assert_eq!(get("/copy/c/d").unwrap(), b"d");
+    }
+
+    /// Reads an archive, running a collection before each piece of it
+    struct Collecting<'a> {
+        files: &'a Files,
+        pieces: Pieces<'a>,
+        removed: usize,
+    }
+
+    impl<'a> Read for Collecting<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.removed += self.files.collect_garbage().unwrap().removed;
+            self.pieces.read(buf)
+        }
+    }
+
+    #[test]
+    fn collection_runs_during_an_import() {
+        let files = tests::open();
+        let big = tests::data(10, 2 * 1024 * 1024);
+        let archive = archive(&[], &[("big", &big)]);
+
+        let mut reader = Collecting {
+            files: &files,
+            pieces: Pieces {
+                data: &archive,
+                size: 256 * 1024,
+            },
+            removed: 0,
+        };
+
+        files
+            .import(DEFAULT_TREE, Path::new("/").unwrap(), &mut reader)
+            .unwrap();
+
+        // the parts of the file stored so far are never collected
+        assert_eq!(reader.removed, 0);
+        assert_eq!(files.collect_garbage().unwrap().removed, 0);
+
+        let big_path = Path::new("/big").unwrap();
+        assert_eq!(
+            files
+                .get(big_path, Revision::FromLatest(0))
+                .unwrap()
+                .unwrap(),
+            big
+        );
+    }
+
+    #[test]
+    fn invalid_archives_are_refused() {
+        let files = tests::open();
+        let history = files.get_history(DEFAULT_TREE).unwrap().len();
+
+        // the tar crate won't write names with `..`, so set the name of the header directly
+        let mut header = tar::Header::new_gnu();
+        header.as_old_mut().name[..10].copy_from_slice(b"../outside");
+        header.set_size(4);
+        header.set_cksum();
+
+        let mut escaping = tar::Builder::new(vec![]);
+        escaping.append(&header, &b"data"[..]).unwrap();
+        let escaping = escaping.into_inner().unwrap();
+
+        assert!(import(&files, "/", &escaping, 1000).is_err());
+
+        let mut truncated = archive(&[], &[("file", &tests::data(1, 4096))]);
+        truncated.truncate(2048);
+        assert!(import(&files, "/", &truncated, 1000).is_err());
+
+        assert!(import(&files, "/", &tests::data(2, 4096), 1000).is_err());
+
+        // nothing is linked if the archive can't be imported
+        assert_eq!(files.get_history(DEFAULT_TREE).unwrap().len(), history);
+    }
+}
@@ -0,0 +1,482 @@
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::config;
+use crate::files::{self, compress, Files, RootHistory};
+
+/// Begins the header of every value in the database. The id of the key it was encrypted with follows
+const MAGIC: &[u8; 8] = b"ksyncar\0";
+
+/// The length of a key, in bytes
+pub const KEY_LEN: usize = 32;
+
+/// The length of the id that identifies the key a value was encrypted with
+const ID_LEN: usize = 8;
+
+/// Takes the place of a key's id in the header of a value stored unencrypted
+const PLAIN_ID: [u8; ID_LEN] = [0; ID_LEN];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("key must be {KEY_LEN} bytes long")]
+    InvalidKey,
+
+    #[error("the database is encrypted, but no key was given")]
+    NoKey,
+
+    #[error("value was encrypted with an unknown key")]
+    UnknownKey,
+
+    #[error("failed to decrypt value; it has been tampered with")]
+    Decrypt,
+
+    #[error("value has no header; it was stored by an older version of ksync")]
+    NoHeader,
+}
+
+/// A key that values are encrypted with while they are stored in the database
+pub struct Key {
+    cipher: LessSafeKey,
+    /// Identifies the key, without revealing it, so that values can be matched to the key they were encrypted with
+    id: [u8; ID_LEN],
+}
+
+impl Key {
+    pub fn new(key: &[u8]) -> Result<Key, Error> {
+        if key.len() != KEY_LEN {
+            return Err(Error::InvalidKey);
+        }
+
+        let master = hmac::Key::new(hmac::HMAC_SHA256, key);
+        let derive = |use_: &str| hmac::sign(&master, format!("ksync at rest {use_}").as_bytes());
+
+        let cipher = UnboundKey::new(&AES_256_GCM, derive("cipher").as_ref()).unwrap();
+
+        Ok(Key {
+            cipher: LessSafeKey::new(cipher),
+            id: derive("id").as_ref()[..ID_LEN].try_into().unwrap(),
+        })
+    }
+
+    /// Load the key named by a server's config, either from a file holding it's bytes, or from an environment
+    /// variable holding them as hex
+    pub fn load(config: &config::AtRest) -> anyhow::Result<Key> {
+        let key = match config {
+            config::AtRest {
+                key: Some(path),
+                key_env: None,
+            } => std::fs::read(path)?,
+            config::AtRest {
+                key: None,
+                key_env: Some(var),
+            } => {
+                let hex = std::env::var(var)?;
+                let hex = hex.trim();
+
+                (0..hex.len())
+                    .step_by(2)
+                    .map(|i| {
+                        hex.get(i..i + 2)
+                            .and_then(|b| u8::from_str_radix(b, 16).ok())
+                    })
+                    .collect::<Option<_>>()
+                    .ok_or(Error::InvalidKey)?
+            }
+            _ => anyhow::bail!("exactly one of `key` and `key_env` must be given"),
+        };
+
+        Ok(Key::new(&key)?)
+    }
+
+    /// Encrypt `data` under a random nonce, prefixed by a header naming this key
+    fn seal(&self, data: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).unwrap();
+
+        let mut sealed = Vec::with_capacity(MAGIC.len() + ID_LEN + NONCE_LEN + data.len() + 16);
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&self.id);
+        sealed.extend_from_slice(&nonce);
+
+        let header = sealed.len();
+        sealed.extend_from_slice(data);
+
+        let (header, body) = sealed.split_at_mut(header);
+
+        let tag = self
+            .cipher
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(&header[..MAGIC.len() + ID_LEN]),
+                body,
+            )
+            .unwrap();
+
+        sealed.extend_from_slice(tag.as_ref());
+
+        sealed
+    }
+
+    /// Decrypt the body of a value encrypted with [Key::seal], following it's header
+    fn open(&self, header: &[u8], body: &[u8]) -> Result<Vec<u8>, Error> {
+        if body.len() < NONCE_LEN {
+            return Err(Error::Decrypt);
+        }
+
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| Error::Decrypt)?;
+
+        let mut data = ciphertext.to_vec();
+        let len = self
+            .cipher
+            .open_in_place(nonce, Aad::from(header), &mut data)
+            .map_err(|_| Error::Decrypt)?
+            .len();
+
+        data.truncate(len);
+
+        Ok(data)
+    }
+}
+
+/// The keys a database is encrypted with. Values are written with the `current` key, and may be read with either
+/// it or the `previous` key, while a database is being moved from one to the other
+#[derive(Default)]
+pub struct Keys {
+    /// New values are stored unencrypted if there is no current key
+    pub current: Option<Key>,
+    pub previous: Option<Key>,
+}
+
+impl Keys {
+    /// Encrypt a value with the current key, if there is one
+    pub(super) fn seal(&self, data: Vec<u8>) -> Vec<u8> {
+        match &self.current {
+            Some(key) => key.seal(&data),
+            None => [&MAGIC[..], &PLAIN_ID, &data].concat(),
+        }
+    }
+
+    /// Decrypt a value, if it was encrypted
+    pub(super) fn open(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let Some(sealed) = data.strip_prefix(MAGIC) else {
+            return Err(Error::NoHeader);
+        };
+
+        if sealed.len() < ID_LEN {
+            return Err(Error::Decrypt);
+        }
+
+        let (id, body) = sealed.split_at(ID_LEN);
+
+        if id == PLAIN_ID {
+            return Ok(body.to_vec());
+        }
+
+        let key = [&self.current, &self.previous]
+            .into_iter()
+            .flatten()
+            .find(|key| key.id == id)
+            .ok_or(if self.current.is_none() && self.previous.is_none() {
+                Error::NoKey
+            } else {
+                Error::UnknownKey
+            })?;
+
+        key.open(&data[..MAGIC.len() + ID_LEN], body)
+    }
+
+    /// Decrypt a value that may have been stored by an older version of `ksync`, which stored values without a header
+    /// unless they were encrypted or looked like they were. Only for reading a database before it is migrated
+    pub(super) fn open_legacy(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.starts_with(MAGIC) {
+            self.open(data)
+        } else {
+            Ok(data.to_vec())
+        }
+    }
+
+    /// Check whether a value is stored as new values are
+    fn is_current(&self, data: &[u8]) -> bool {
+        match (&self.current, data.strip_prefix(MAGIC)) {
+            (Some(key), Some(sealed)) => sealed.starts_with(&key.id),
+            (None, Some(sealed)) => sealed.starts_with(&PLAIN_ID),
+            (_, None) => false,
+        }
+    }
+}
+
+/// The outcome of re-encrypting a database
+#[derive(Debug, Default)]
+pub struct ResealReport {
+    /// Values that were already encrypted with the current key
+    pub unchanged: usize,
+    /// Values that were re-encrypted
+    pub resealed: usize,
+}
+
+impl std::fmt::Display for ResealReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "re-encrypted {} values, {} were already up to date",
+            self.resealed, self.unchanged
+        )
+    }
+}
+
+// filesystem internals
+impl Files {
+    /// Deserialise the history of a root, as it is stored in the database
+    pub(super) fn decode_history(&self, history: &[u8]) -> Result<RootHistory, files::Error> {
+        Ok(bincode::deserialize(&self.keys.open(history)?)?)
+    }
+
+    /// Serialise the history of a root, in the form it is stored in the database
+    pub(super) fn encode_history(&self, history: &RootHistory) -> Result<Vec<u8>, files::Error> {
+        Ok(self.keys.seal(bincode::serialize(history)?))
+    }
+
+    /// Give every value stored without a header by an older version of `ksync` one, along with the codec header
+    /// inside it that every object now has. Values that already have their headers are left alone, so this can be ran
+    /// again if it is interrupted
+    pub(super) fn migrate_headers(&self) -> Result<(), files::Error> {
+        let _guard = self.collector.write();
+
+        for object in self.objects.objects() {
+            let object = object?;
+
+            let Some(old) = self.objects.get(&object)? else {
+                continue;
+            };
+
+            let data = self.keys.open_legacy(&old)?;
+
+            if old.starts_with(MAGIC) && compress::has_header(&data) {
+                continue;
+            }
+
+            let new = self.keys.seal(compress::add_header(data));
+            self.objects.insert(&object, new)?;
+        }
+
+        for tree in [&self.roots, &self.tags] {
+            for entry in tree.iter() {
+                let (key, old) = entry?;
+
+                if !old.starts_with(MAGIC) {
+                    tree.insert(key, self.keys.seal(old.to_vec()))?;
+                }
+            }
+        }
+
+        self.objects.flush()?;
+        self.db.flush()?;
+
+        Ok(())
+    }
+}
+
+// public helpers
+impl Files {
+    /// Re-encrypt every value in the database that is not stored with the current key, in place. Values are read
+    /// with either the current or previous key, so that this can be run again if it is interrupted. If there is no
+    /// current key, the database is decrypted
+    pub fn reseal(&self) -> Result<ResealReport, files::Error> {
+        let _guard = self.collector.write();
+
+        log::info!("re-encrypting database");
+
+        let mut report = ResealReport::default();
+
+        // objects never change once they are stored, so can simply be replaced
+        for object in self.objects.objects() {
+            let object = object?;
+
+            let Some(old) = self.objects.get(&object)? else {
+                continue;
+            };
+
+            if self.keys.is_current(&old) {
+                report.unchanged += 1;
+            } else {
+                let new = self.keys.seal(self.keys.open(&old)?);
+                self.objects.insert(&object, new)?;
+
+                report.resealed += 1;
+            }
+        }
+
+        for tree in [&self.roots, &self.tags] {
+            for entry in tree.iter() {
+                let (key, mut old) = entry?;
+
+                loop {
+                    if self.keys.is_current(&old) {
+                        report.unchanged += 1;
+                        break;
+                    }
+
+                    let new = self.keys.seal(self.keys.open(&old)?);
+
+                    match tree.compare_and_swap(&key, Some(old), Some(new))? {
+                        Ok(()) => {
+                            report.resealed += 1;
+                            break;
+                        }
+                        Err(e) => match e.current {
+                            Some(current) => old = current,
+                            // removed while we were re-encrypting
+                            None => break,
+                        },
+                    }
+                }
+            }
+        }
+
+        self.objects.flush()?;
+        self.db.flush()?;
+
+        log::info!("{report}");
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Key, Keys, KEY_LEN};
+    use crate::config::Storage;
+    use crate::files::{tests, Error, Path, Revision, DEFAULT_TREE};
+
+    fn keys(current: Option<u8>, previous: Option<u8>) -> Keys {
+        let key = |byte| Key::new(&[byte; KEY_LEN]).unwrap();
+
+        Keys {
+            current: current.map(key),
+            previous: previous.map(key),
+        }
+    }
+
+    /// Whether `data` appears anywhere in the files below `dir`
+    fn leaked(dir: &std::path::Path, data: &[u8]) -> bool {
+        std::fs::read_dir(dir).unwrap().any(|entry| {
+            let path = entry.unwrap().path();
+
+            if path.is_dir() {
+                leaked(&path, data)
+            } else {
+                std::fs::read(path)
+                    .unwrap()
+                    .windows(data.len())
+                    .any(|window| window == data)
+            }
+        })
+    }
+
+    #[test]
+    fn values_are_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = |current, previous| {
+            tests::reopen(dir.path(), &Storage::default(), || keys(current, previous))
+        };
+
+        let path = Path::new("/secret-name").unwrap();
+        let data = b"secret contents";
+
+        {
+            let files = open(Some(1), None).unwrap();
+
+            files.insert(path, data).unwrap();
+            files
+                .create_tag(DEFAULT_TREE, "secret-tag", Revision::FromLatest(0))
+                .unwrap();
+        }
+
+        assert!(!leaked(dir.path(), data));
+        assert!(!leaked(dir.path(), b"secret-name"));
+        assert!(!leaked(dir.path(), b"secret-tag"));
+
+        assert!(matches!(
+            open(None, None),
+            Err(Error::AtRest {
+                error: super::Error::NoKey
+            })
+        ));
+        assert!(matches!(
+            open(Some(2), None),
+            Err(Error::AtRest {
+                error: super::Error::UnknownKey
+            })
+        ));
+
+        let files = open(Some(1), None).unwrap();
+        assert_eq!(
+            files.get(path, Revision::FromLatest(0)).unwrap().unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn keys_are_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = |current, previous| {
+            tests::reopen(dir.path(), &Storage::default(), || keys(current, previous)).unwrap()
+        };
+
+        let path = Path::new("/file").unwrap();
+
+        open(None, None).insert(path, b"plain").unwrap();
+
+        // encrypt a database that was stored unencrypted, move it to another key, then decrypt it again
+        for (current, previous) in [(Some(1), None), (Some(2), Some(1)), (None, Some(2))] {
+            let report = open(current, previous).reseal().unwrap();
+            assert!(report.resealed > 0);
+
+            // everything is now stored with the current key alone
+            let files = open(current, None);
+
+            assert_eq!(files.reseal().unwrap().resealed, 0);
+            assert_eq!(
+                files.get(path, Revision::FromLatest(0)).unwrap().unwrap(),
+                b"plain"
+            );
+        }
+    }
+
+    #[test]
+    fn values_without_a_header_are_refused() {
+        let keys = keys(None, None);
+        let value = b"stored by an older version";
+
+        assert!(matches!(keys.open(value), Err(super::Error::NoHeader)));
+        assert!(!keys.is_current(value));
+
+        // until the database is migrated
+        assert_eq!(keys.open_legacy(value).unwrap(), value);
+    }
+
+    #[test]
+    fn values_like_headers_are_read() {
+        let value = [&super::MAGIC[..], b"not actually encrypted"].concat();
+
+        for current in [None, Some(1)] {
+            let keys = keys(current, None);
+            let sealed = keys.seal(value.clone());
+
+            assert!(keys.is_current(&sealed));
+            assert_eq!(keys.open(&sealed).unwrap(), value);
+        }
+
+        // such a value can also be stored as the contents of a file
+        let files = tests::open();
+        let path = Path::new("/file").unwrap();
+
+        files.insert(path, &value).unwrap();
+        assert_eq!(
+            files.get(path, Revision::FromLatest(0)).unwrap().unwrap(),
+            value
+        );
+    }
+}
@@ -0,0 +1,425 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::VecDeque;
+
+use crate::files::chunk::FileWriter;
+use crate::files::gc::Pending;
+use crate::files::{Error, Files, Object, Path};
+
+/// An operation applied to a tree as part of a [Batch]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Operation {
+    /// Insert a file of `size` bytes, creating any missing parent directories. The contents of each file inserted by a
+    /// batch follow one another in the stream sent to the server, in the order of the operations
+    Insert {
+        path: String,
+        size: u64,
+    },
+    Delete {
+        path: String,
+    },
+    /// Make a directory, along with any missing parent directories
+    MakeDir {
+        path: String,
+    },
+    Move {
+        from: String,
+        to: String,
+    },
+    /// Link a file that is already stored, by the [Object] of it's manifest, rather than sending it's contents again.
+    /// See [Files::have]
+    Link {
+        path: String,
+        object: Object,
+    },
+}
+
+impl Operation {
+    /// The paths the operation acts on
+    fn paths(&self) -> Vec<&str> {
+        match self {
+            Operation::Insert { path, .. }
+            | Operation::Delete { path }
+            | Operation::MakeDir { path }
+            | Operation::Link { path, .. } => vec![path],
+            Operation::Move { from, to } => vec![from, to],
+        }
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidBatch {
+        reason: reason.into(),
+    }
+}
+
+/// A list of [Operation]s that are applied to a tree all at once, as a single revision. The contents of the files it
+/// inserts are stored as they are written; if any operation fails, none of them are committed
+pub struct Batch {
+    tree: String,
+    operations: Vec<Operation>,
+    /// The sizes of the files still to be written, in order
+    sizes: VecDeque<u64>,
+    /// The file currently being written, and the number of bytes left to write to it
+    current: Option<(FileWriter, u64)>,
+    /// The files that have been written, in order
+    objects: Vec<Object>,
+    /// The files that have been written, and those the batch links, are kept from being collected until the batch has
+    /// been applied. Collection is not held off while the batch is written
+    pending: Pending,
+}
+
+impl Batch {
+    /// Write the next part of the contents of the files inserted by the batch
+    pub fn write(&mut self, files: &Files, mut data: &[u8]) -> Result<(), Error> {
+        while !data.is_empty() {
+            let (writer, remaining) = match &mut self.current {
+                Some(current) => current,
+                None => {
+                    let size = self
+                        .sizes
+                        .pop_front()
+                        .ok_or_else(|| invalid("more data was sent than the batch inserts"))?;
+
+                    self.current.insert((files.file_writer(), size))
+                }
+            };
+
+            let n = data.len().min(*remaining as usize);
+            writer.write(files, &data[..n])?;
+
+            *remaining -= n as u64;
+            data = &data[n..];
+
+            self.finish_file(files)?;
+        }
+
+        Ok(())
+    }
+
+    /// Finish the current file, if all of it has been written. Files that are empty are finished straight away
+    fn finish_file(&mut self, files: &Files) -> Result<(), Error> {
+        loop {
+            match &mut self.current {
+                Some((_, 0)) => {
+                    let (mut writer, _) = self.current.take().unwrap();
+
+                    self.objects.push(writer.finish(files)?);
+                    self.pending.extend(writer.into_pending());
+                }
+
+                Some(_) => return Ok(()),
+
+                None => match self.sizes.front() {
+                    Some(0) => {
+                        self.sizes.pop_front();
+                        self.current = Some((files.file_writer(), 0));
+                    }
+
+                    _ => return Ok(()),
+                },
+            }
+        }
+    }
+
+    /// Apply every operation of the batch to it's tree, as a single revision
+    pub fn finish(mut self, files: &Files) -> Result<(), Error> {
+        self.finish_file(files)?;
+
+        if self.current.is_some() || !self.sizes.is_empty() {
+            return Err(invalid("less data was sent than the batch inserts"));
+        }
+
+        log::info!(
+            "applying batch of {} operations to tree '{}'",
+            self.operations.len(),
+            self.tree
+        );
+
+        files.with_root_mut(&self.tree, |tree| {
+            let mut objects = self.objects.iter();
+
+            for operation in &self.operations {
+                match operation {
+                    Operation::Insert { path, .. } => {
+                        let path = Path::new(path)?;
+                        let (parent, _) = path.parent_child();
+
+                        tree.make_dir_recursive(parent)?;
+                        tree.insert(path, *objects.next().unwrap())?;
+                    }
+
+                    Operation::Delete { path } => tree.delete(Path::new(path)?)?,
+                    Operation::MakeDir { path } => tree.make_dir_recursive(Path::new(path)?)?,
+                    Operation::Move { from, to } => {
+                        tree.rename(Path::new(from)?, Path::new(to)?)?
+                    }
+                    Operation::Link { path, object } => {
+                        let path = Path::new(path)?;
+                        let (parent, _) = path.parent_child();
+
+                        tree.make_dir_recursive(parent)?;
+                        tree.insert(path, *object)?;
+                    }
+                }
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl Files {
+    /// Begin a [Batch] of `operations` on a `tree`
+    pub fn batch(&self, tree: &str, operations: Vec<Operation>) -> Result<Batch, Error> {
+        let mut pending = self.collector.pending();
+
+        // check the tree exists and every path is valid up front, rather than once every file has been written
+        self.get_history(tree)?;
+
+        for operation in &operations {
+            for path in operation.paths() {
+                Path::new(path)?;
+            }
+
+            // files that are linked can't be collected once the batch has begun, but may have been before
+            if let Operation::Link { object, .. } = operation {
+                if !self.keep_file(&mut pending, object)? {
+                    return Err(Error::MissingObject { object: *object });
+                }
+            }
+        }
+
+        let sizes = operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::Insert { size, .. } => Some(*size),
+                _ => None,
+            })
+            .collect();
+
+        let mut batch = Batch {
+            tree: tree.to_owned(),
+            operations,
+            sizes,
+            current: None,
+            objects: vec![],
+            pending,
+        };
+
+        // a batch may start with empty files, which won't have any data written to them
+        batch.finish_file(self)?;
+
+        Ok(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Operation;
+    use crate::files::{tests, Error, Files, Object, Path, Revision, DEFAULT_TREE};
+
+    /// Apply a batch of `operations` to the default tree, writing `data` in pieces of `size` bytes
+    fn apply(
+        files: &Files,
+        operations: Vec<Operation>,
+        data: &[u8],
+        size: usize,
+    ) -> Result<(), Error> {
+        let mut batch = files.batch(DEFAULT_TREE, operations)?;
+
+        for piece in data.chunks(size) {
+            batch.write(files, piece)?;
+        }
+
+        batch.finish(files)
+    }
+
+    fn insert(path: &str, size: usize) -> Operation {
+        Operation::Insert {
+            path: path.to_owned(),
+            size: size as u64,
+        }
+    }
+
+    fn get(files: &Files, path: &str) -> Option<Vec<u8>> {
+        files
+            .get(Path::new(path).unwrap(), Revision::FromLatest(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn batch_is_one_revision() {
+        let files = tests::open();
+        let big = tests::data(3, 300 * 1024);
+
+        files
+            .insert(Path::new("/old/file").unwrap(), b"old")
+            .unwrap();
+        files.insert(Path::new("/gone").unwrap(), b"gone").unwrap();
+
+        let history = files.get_history(DEFAULT_TREE).unwrap().len();
+
+        let operations = vec![
+            insert("/a/empty", 0),
+            insert("/a/big", big.len()),
+            insert("/b/small", 5),
+            insert("/c", 0),
+            Operation::Delete {
+                path: "/gone".to_owned(),
+            },
+            Operation::MakeDir {
+                path: "/d/e".to_owned(),
+            },
+            Operation::Move {
+                from: "/old".to_owned(),
+                to: "/d/e/new".to_owned(),
+            },
+        ];
+
+        let data = [&big[..], b"small"].concat();
+        apply(&files, operations, &data, 4000).unwrap();
+
+        assert_eq!(files.get_history(DEFAULT_TREE).unwrap().len(), history + 1);
+
+        assert_eq!(get(&files, "/a/empty").unwrap(), b"");
+        assert_eq!(get(&files, "/a/big").unwrap(), big);
+        assert_eq!(get(&files, "/b/small").unwrap(), b"small");
+        assert_eq!(get(&files, "/c").unwrap(), b"");
+        assert_eq!(get(&files, "/d/e/new/file").unwrap(), b"old");
+        assert!(get(&files, "/gone").is_none());
+        assert!(files
+            .get_node(
+                DEFAULT_TREE,
+                Path::new("/old").unwrap(),
+                Revision::FromLatest(0)
+            )
+            .unwrap()
+            .is_some_and(|node| node.data().is_none()));
+    }
+
+    #[test]
+    fn failed_batch_commits_nothing() {
+        let files = tests::open();
+
+        files.insert(Path::new("/file").unwrap(), b"file").unwrap();
+
+        let history = files.get_history(DEFAULT_TREE).unwrap().len();
+
+        // the move fails, as it's destination already exists
+        let operations = vec![
+            insert("/new", 3),
+            Operation::Move {
+                from: "/new".to_owned(),
+                to: "/file".to_owned(),
+            },
+        ];
+
+        assert!(matches!(
+            apply(&files, operations, b"new", 1),
+            Err(Error::AlreadyExists { .. })
+        ));
+
+        // the size of the data doesn't match the files inserted
+        assert!(matches!(
+            apply(&files, vec![insert("/new", 3)], b"ne", 1),
+            Err(Error::InvalidBatch { .. })
+        ));
+        assert!(matches!(
+            apply(&files, vec![insert("/new", 3)], b"newer", 1),
+            Err(Error::InvalidBatch { .. })
+        ));
+
+        assert_eq!(files.get_history(DEFAULT_TREE).unwrap().len(), history);
+        assert!(get(&files, "/new").is_none());
+    }
+
+    #[test]
+    fn directories_cannot_move_inside_themselves() {
+        let files = tests::open();
+
+        files.insert(Path::new("/a/b").unwrap(), b"b").unwrap();
+
+        for to in ["/a", "/a/c"] {
+            let operations = vec![Operation::Move {
+                from: "/a".to_owned(),
+                to: to.to_owned(),
+            }];
+
+            assert!(matches!(
+                apply(&files, operations, b"", 1),
+                Err(Error::InvalidMove { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn stored_files_are_linked() {
+        let files = tests::open();
+
+        files
+            .insert(Path::new("/original").unwrap(), b"stored")
+            .unwrap();
+        let object = files
+            .get_manifest(
+                DEFAULT_TREE,
+                Path::new("/original").unwrap(),
+                Revision::FromLatest(0),
+            )
+            .unwrap()
+            .unwrap()
+            .object();
+
+        let link = |object| {
+            vec![
+                insert("/new", 3),
+                Operation::Link {
+                    path: "/a/linked".to_owned(),
+                    object,
+                },
+            ]
+        };
+
+        assert!(matches!(
+            apply(&files, link(Object::of(b"unknown")), b"new", 1),
+            Err(Error::MissingObject { .. })
+        ));
+
+        apply(&files, link(object), b"new", 1).unwrap();
+
+        assert_eq!(get(&files, "/new").unwrap(), b"new");
+        assert_eq!(get(&files, "/a/linked").unwrap(), b"stored");
+    }
+
+    #[test]
+    fn collection_runs_during_a_batch() {
+        let files = tests::open();
+        let big = tests::data(4, 2 * 1024 * 1024);
+
+        // stored, but not linked anywhere yet
+        let stored = files.write_file(b"stored").unwrap();
+
+        let operations = vec![
+            insert("/big", big.len()),
+            Operation::Link {
+                path: "/linked".to_owned(),
+                object: stored,
+            },
+        ];
+
+        let mut batch = files.batch(DEFAULT_TREE, operations).unwrap();
+        batch.write(&files, &big[..1024 * 1024]).unwrap();
+
+        // neither the part of the file written so far, nor the file being linked, are collected
+        assert_eq!(files.collect_garbage().unwrap().removed, 0);
+
+        batch.write(&files, &big[1024 * 1024..]).unwrap();
+        assert_eq!(files.collect_garbage().unwrap().removed, 0);
+
+        batch.finish(&files).unwrap();
+
+        assert_eq!(files.collect_garbage().unwrap().removed, 0);
+        assert_eq!(get(&files, "/big").unwrap(), big);
+        assert_eq!(get(&files, "/linked").unwrap(), b"stored");
+    }
+}
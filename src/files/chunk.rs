@@ -0,0 +1,457 @@
+use fastcdc::v2020::FastCDC;
+use serde::{Deserialize, Serialize};
+
+use std::convert::Infallible;
+
+use crate::files::{gc::Pending, Error, Files, Object};
+
+/// The smallest chunk that will be cut from a file, unless it is the last chunk
+pub const MIN_CHUNK: u32 = 16 * 1024;
+/// The size chunks are normalised around
+pub const AVG_CHUNK: u32 = 64 * 1024;
+/// The largest chunk that will be cut from a file
+pub const MAX_CHUNK: u32 = 256 * 1024;
+
+/// Marks an [Object] as containing a [Manifest], rather than the contents of a file stored as a single object by an
+/// older version of `ksync`, before it was migrated
+const MAGIC: &[u8; 8] = b"ksyncmf\0";
+
+/// A [Manifest] lists the chunks that make up the contents of a file, in order
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Manifest {
+    size: u64,
+    chunks: Vec<Object>,
+}
+
+impl Manifest {
+    /// Append a chunk to the end of the file
+    pub fn push(&mut self, chunk: &[u8]) -> Object {
+        let object = Object::of(chunk);
+
+        self.size += chunk.len() as u64;
+        self.chunks.push(object);
+
+        object
+    }
+
+    /// The total size of the file, in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The chunks that make up the file
+    pub fn chunks(&self) -> &[Object] {
+        &self.chunks
+    }
+
+    /// Serialize the [Manifest] to the format it is stored in
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        bincode::serialize_into(&mut data, self).unwrap();
+
+        data
+    }
+
+    /// Deserialize a [Manifest] from an [Object]'s data. Returns `None` if the data is not a [Manifest]
+    pub fn decode(data: &[u8]) -> Option<Manifest> {
+        let data = data.strip_prefix(MAGIC)?;
+
+        bincode::deserialize(data).ok()
+    }
+
+    /// The [Object] the [Manifest] will be stored as; this is what a file [super::Node] points to
+    pub fn object(&self) -> Object {
+        Object::of(self.encode())
+    }
+}
+
+/// Computes the [Manifest] of a file incrementally, without storing anything
+#[derive(Default)]
+pub struct ManifestBuilder {
+    chunker: Chunker,
+    manifest: Manifest,
+}
+
+impl ManifestBuilder {
+    /// Feed the next part of the file's contents into the builder
+    pub fn update(&mut self, data: &[u8]) {
+        let manifest = &mut self.manifest;
+
+        self.chunker
+            .push(data, |chunk| {
+                manifest.push(chunk);
+
+                Ok::<_, Infallible>(())
+            })
+            .unwrap();
+    }
+
+    /// Produce the [Manifest] of the file
+    pub fn finish(mut self) -> Manifest {
+        let manifest = &mut self.manifest;
+
+        self.chunker
+            .finish(|chunk| {
+                manifest.push(chunk);
+
+                Ok::<_, Infallible>(())
+            })
+            .unwrap();
+
+        self.manifest
+    }
+}
+
+/// Splits a stream of bytes into content-defined chunks.
+///
+/// Where a chunk is cut only depends on the bytes since the previous cut, and never on more than [MAX_CHUNK] bytes of
+/// them. We only cut a chunk once that many bytes are buffered, so data pushed in pieces is cut in exactly the same
+/// places as the same data chunked all at once.
+#[derive(Default)]
+pub struct Chunker {
+    buf: Vec<u8>,
+}
+
+impl Chunker {
+    /// Feed `data` into the chunker, calling `sink` with each chunk that can be cut
+    pub fn push<E>(
+        &mut self,
+        data: &[u8],
+        mut sink: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        self.buf.extend_from_slice(data);
+
+        let mut offset = 0;
+
+        while self.buf.len() - offset >= MAX_CHUNK as usize {
+            let chunk = FastCDC::new(&self.buf[offset..], MIN_CHUNK, AVG_CHUNK, MAX_CHUNK)
+                .next()
+                .unwrap();
+
+            sink(&self.buf[offset..offset + chunk.length])?;
+            offset += chunk.length;
+        }
+
+        self.buf.drain(..offset);
+
+        Ok(())
+    }
+
+    /// Cut the remaining buffered data into chunks, calling `sink` with each of them
+    pub fn finish<E>(self, mut sink: impl FnMut(&[u8]) -> Result<(), E>) -> Result<(), E> {
+        for chunk in FastCDC::new(&self.buf, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK) {
+            sink(&self.buf[chunk.offset..chunk.offset + chunk.length])?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Stores a file incrementally as it's contents are written, so that it never has to be held in memory all at once.
+/// Chunks that have been stored will not be collected until the [FileWriter] is dropped, so it should be kept alive
+/// until the file has been linked into a tree. Collection is not held off while the file is written
+pub struct FileWriter {
+    chunker: Chunker,
+    manifest: Manifest,
+    pending: Pending,
+}
+
+impl FileWriter {
+    /// Write the next part of the file's contents
+    pub fn write(&mut self, files: &Files, data: &[u8]) -> Result<(), Error> {
+        let manifest = &mut self.manifest;
+        let pending = &mut self.pending;
+
+        self.chunker.push(data, |chunk| {
+            files.create_pending_object(pending, chunk)?;
+            manifest.push(chunk);
+
+            Ok(())
+        })
+    }
+
+    /// Store the rest of the file, and it's [Manifest]. Returns the [Object] the file can be linked to
+    pub fn finish(&mut self, files: &Files) -> Result<Object, Error> {
+        let manifest = &mut self.manifest;
+        let pending = &mut self.pending;
+
+        std::mem::take(&mut self.chunker).finish(|chunk| {
+            files.create_pending_object(pending, chunk)?;
+            manifest.push(chunk);
+
+            Ok::<_, Error>(())
+        })?;
+
+        files.create_pending_object(pending, self.manifest.encode())
+    }
+
+    /// The objects the file has been stored as, which are kept from being collected until they are dropped
+    pub fn into_pending(self) -> Pending {
+        self.pending
+    }
+}
+
+impl Files {
+    /// Begin writing a new file. See [FileWriter] for details
+    pub fn file_writer(&self) -> FileWriter {
+        FileWriter {
+            chunker: Chunker::default(),
+            manifest: Manifest::default(),
+            pending: self.collector.pending(),
+        }
+    }
+
+    /// Load the [Manifest] of a file
+    pub fn manifest(&self, object: &Object) -> Result<Manifest, Error> {
+        Manifest::decode(&self.load(object)?).ok_or(Error::NotAFile { object: *object })
+    }
+
+    /// Store a file that an older version of `ksync` stored as a single object as chunks and a [Manifest], returning
+    /// the [Object] it can be linked to. Files that already have a [Manifest] are returned as they are
+    pub(super) fn upgrade_file(&self, object: Object) -> Result<Object, Error> {
+        let data = self.load(&object)?;
+
+        if Manifest::decode(&data).is_some() {
+            Ok(object)
+        } else {
+            self.write_file(&data)
+        }
+    }
+
+    /// Load the contents of a single chunk of a file
+    pub fn read_chunk(&self, object: &Object) -> Result<Vec<u8>, Error> {
+        self.load(object)
+    }
+
+    /// Load the [Manifest] of a file a client wants to link. `None` if `object` is stored, but is not a file, such as a
+    /// chunk or a directory, as it can't be linked either
+    fn manifest_to_link(&self, object: &Object) -> Result<Option<Manifest>, Error> {
+        match self.manifest(object) {
+            Ok(manifest) => Ok(Some(manifest)),
+            Err(Error::NotAFile { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Check whether the whole of the file stored as `object` is stored; it's [Manifest], and every chunk it lists
+    pub fn has_file(&self, object: &Object) -> Result<bool, Error> {
+        if !self.objects.contains(object)? {
+            return Ok(false);
+        }
+
+        let Some(manifest) = self.manifest_to_link(object)? else {
+            return Ok(false);
+        };
+
+        for chunk in manifest.chunks() {
+            if !self.objects.contains(chunk)? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Check whether the whole of the file stored as `object` is stored, as with [Files::has_file], keeping it from
+    /// being collected until `pending` is dropped
+    pub(super) fn keep_file(&self, pending: &mut Pending, object: &Object) -> Result<bool, Error> {
+        // each object must be pending before we look for it, or it could be swept after we find it
+        pending.add(*object);
+
+        if !self.objects.contains(object)? {
+            return Ok(false);
+        }
+
+        let Some(manifest) = self.manifest_to_link(object)? else {
+            return Ok(false);
+        };
+
+        for chunk in manifest.chunks() {
+            pending.add(*chunk);
+
+            if !self.objects.contains(chunk)? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Check which of the files stored as `objects` are stored in whole, so that they can be linked without being sent
+    /// again. See [Files::has_file]
+    pub fn have(&self, objects: &[Object]) -> Result<Vec<bool>, Error> {
+        objects.iter().map(|object| self.has_file(object)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::tests::{data, open};
+    use crate::files::{Path, Revision, DEFAULT_TREE};
+
+    /// Chunk `data`, fed to the chunker `piece` bytes at a time
+    fn chunks(data: &[u8], piece: usize) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::default();
+        let mut chunks = vec![];
+
+        for piece in data.chunks(piece) {
+            chunker
+                .push(piece, |chunk| {
+                    chunks.push(chunk.to_vec());
+                    Ok::<_, Infallible>(())
+                })
+                .unwrap();
+        }
+
+        chunker
+            .finish(|chunk| {
+                chunks.push(chunk.to_vec());
+                Ok::<_, Infallible>(())
+            })
+            .unwrap();
+
+        chunks
+    }
+
+    #[test]
+    fn chunks_are_deterministic() {
+        let data = data(1, 3 * 1024 * 1024);
+        let first = chunks(&data, data.len());
+
+        assert!(first.len() > 1);
+        assert_eq!(first.concat(), data);
+        assert_eq!(chunks(&data, data.len()), first);
+
+        let (last, rest) = first.split_last().unwrap();
+
+        for chunk in rest {
+            assert!((MIN_CHUNK as usize..=MAX_CHUNK as usize).contains(&chunk.len()));
+        }
+
+        assert!(last.len() <= MAX_CHUNK as usize);
+    }
+
+    #[test]
+    fn piecewise_matches_one_shot() {
+        let data = data(2, 2 * 1024 * 1024 + 12345);
+        let one_shot = chunks(&data, data.len());
+
+        for piece in [
+            1000,
+            4096,
+            65_537,
+            MAX_CHUNK as usize,
+            MAX_CHUNK as usize + 1,
+        ] {
+            assert_eq!(chunks(&data, piece), one_shot, "pieces of {piece} bytes");
+        }
+    }
+
+    #[test]
+    fn edits_only_change_nearby_chunks() {
+        let data = data(3, 2 * 1024 * 1024);
+        let mut edited = data.clone();
+        edited.splice(1024 * 1024..1024 * 1024, *b"inserted");
+
+        let before = chunks(&data, data.len());
+        let after = chunks(&edited, edited.len());
+
+        let shared = after.iter().filter(|chunk| before.contains(chunk)).count();
+        assert!(
+            shared + 3 >= after.len(),
+            "only {shared} of {} chunks shared",
+            after.len()
+        );
+    }
+
+    #[test]
+    fn small_files() {
+        assert!(chunks(&[], 1).is_empty());
+        assert_eq!(chunks(b"hello", 1), vec![b"hello".to_vec()]);
+    }
+
+    #[test]
+    fn builder_matches_writer() {
+        let files = open();
+        let data = data(4, 1024 * 1024 + 7);
+
+        let mut builder = ManifestBuilder::default();
+
+        for piece in data.chunks(10_000) {
+            builder.update(piece);
+        }
+
+        let manifest = builder.finish();
+
+        let mut writer = files.file_writer();
+        writer.write(&files, &data).unwrap();
+        let object = writer.finish(&files).unwrap();
+
+        assert_eq!(manifest.object(), object);
+        assert_eq!(manifest.size(), data.len() as u64);
+        assert_eq!(files.read_file(&object).unwrap(), data);
+
+        let stored = files.manifest(&object).unwrap();
+        assert_eq!(stored.chunks(), manifest.chunks());
+    }
+
+    #[test]
+    fn only_manifests_are_files() {
+        let files = open();
+        let object = files.create_object(b"not a manifest").unwrap();
+
+        assert!(Manifest::decode(b"not a manifest").is_none());
+        assert!(matches!(
+            files.manifest(&object),
+            Err(Error::NotAFile { object: o }) if o == object
+        ));
+    }
+
+    #[test]
+    fn files_are_linked_only_if_stored() {
+        let files = open();
+        let data = data(5, 1024 * 1024);
+
+        let mut builder = ManifestBuilder::default();
+        builder.update(&data);
+        let manifest = builder.finish();
+
+        let object = manifest.object();
+        let path = Path::new("/linked").unwrap();
+
+        assert_eq!(files.have(&[object]).unwrap(), [false]);
+        assert!(!files.link_file(DEFAULT_TREE, path, object, None).unwrap());
+        assert!(files.get(path, Revision::FromLatest(0)).unwrap().is_none());
+
+        files
+            .insert(Path::new("/original").unwrap(), &data)
+            .unwrap();
+
+        // a chunk is stored, but isn't a file
+        let chunk = manifest.chunks()[0];
+
+        assert_eq!(
+            files
+                .have(&[object, chunk, Object::of(b"unknown")])
+                .unwrap(),
+            [true, false, false]
+        );
+        assert!(!files
+            .link_file(DEFAULT_TREE, Path::new("/chunk").unwrap(), chunk, None)
+            .unwrap());
+        assert!(files.link_file(DEFAULT_TREE, path, object, None).unwrap());
+        assert_eq!(
+            files.get(path, Revision::FromLatest(0)).unwrap().unwrap(),
+            data
+        );
+
+        // a file missing any one of it's chunks can't be linked
+        files.objects.remove(&manifest.chunks()[1]).unwrap();
+
+        assert!(!files.has_file(&object).unwrap());
+        assert!(!files
+            .link_file(DEFAULT_TREE, Path::new("/again").unwrap(), object, None)
+            .unwrap());
+    }
+}
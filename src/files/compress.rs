@@ -0,0 +1,133 @@
+use std::io;
+
+use crate::config::{Codec, Compression};
+
+/// Begins the codec header every object's data is prefixed with. The byte after it identifies the [Codec] used
+const MAGIC: &[u8; 7] = b"ksynccz";
+
+impl Codec {
+    /// The byte that identifies the [Codec] in an object's header
+    fn id(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Prefix `body` with the header for a given `codec`
+fn with_header(codec: Codec, body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(MAGIC.len() + 1 + body.len());
+
+    data.extend_from_slice(MAGIC);
+    data.push(codec.id());
+    data.extend_from_slice(body);
+
+    data
+}
+
+/// Encode `data` in the format it is stored in the database
+pub fn encode(compression: &Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    if compression.codec == Codec::Zstd {
+        let compressed = zstd::bulk::compress(data, compression.level)?;
+
+        // data that is already compressed, such as video, often doesn't get any smaller
+        if compressed.len() < data.len() {
+            return Ok(with_header(Codec::Zstd, &compressed));
+        }
+    }
+
+    Ok(with_header(Codec::None, data))
+}
+
+/// Check whether an object's data has a codec header. Older versions of `ksync` stored data as-is, without one,
+/// unless it was compressed or looked like it was
+pub fn has_header(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Give an object's data stored by an older version of `ksync` a codec header, if it has none
+pub fn add_header(data: Vec<u8>) -> Vec<u8> {
+    if has_header(&data) {
+        data
+    } else {
+        with_header(Codec::None, &data)
+    }
+}
+
+/// Decode an object's data, as stored in the database, back to it's original contents
+pub fn decode(data: &[u8]) -> io::Result<Vec<u8>> {
+    let Some(data) = data.strip_prefix(MAGIC) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "object has no codec header",
+        ));
+    };
+
+    let (&id, body) = data
+        .split_first()
+        .ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?;
+
+    match Codec::from_id(id) {
+        Some(Codec::None) => Ok(body.to_vec()),
+        Some(Codec::Zstd) => zstd::stream::decode_all(body),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown codec {id}"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::tests;
+
+    #[test]
+    fn round_trip() {
+        let compressible = vec![b'a'; 64 * 1024];
+        let incompressible = tests::data(6, 64 * 1024);
+
+        for codec in [Codec::None, Codec::Zstd] {
+            let compression = Compression { codec, level: 3 };
+
+            for data in [&compressible, &incompressible] {
+                let encoded = encode(&compression, data).unwrap();
+                assert_eq!(&decode(&encoded).unwrap(), data);
+            }
+        }
+
+        let encoded = encode(&Compression::default(), &compressible).unwrap();
+        assert!(encoded.len() < compressible.len());
+
+        // there is nothing to gain from compressing random data
+        let encoded = encode(&Compression::default(), &incompressible).unwrap();
+        assert_eq!(encoded, with_header(Codec::None, &incompressible));
+    }
+
+    #[test]
+    fn data_that_looks_like_a_header() {
+        let data = with_header(Codec::Zstd, b"not zstd");
+
+        let encoded = encode(&Compression::default(), &data).unwrap();
+        assert_eq!(decode(&encoded).unwrap(), data);
+    }
+
+    #[test]
+    fn objects_without_a_header_are_upgraded() {
+        let data = b"stored before compression".to_vec();
+        assert!(decode(&data).is_err());
+
+        let upgraded = add_header(data.clone());
+        assert_eq!(decode(&upgraded).unwrap(), data);
+        assert_eq!(add_header(upgraded.clone()), upgraded);
+    }
+}
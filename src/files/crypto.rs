@@ -2,8 +2,6 @@ use serde::{Deserialize, Serialize};
 
 use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
 
-use std::io;
-
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("invalid certificate")]
@@ -71,7 +69,7 @@ impl Key {
         match &with.kind {
             KeyKind::Pair(key) => {
                 let pair =
-                    Ed25519KeyPair::from_pkcs8(&key).map_err(|_| Error::InvalidCertificate)?;
+                    Ed25519KeyPair::from_pkcs8(key).map_err(|_| Error::InvalidCertificate)?;
 
                 let signature = pair.sign(&bytes);
 
@@ -91,7 +89,7 @@ impl Key {
             Some(signature) => {
                 let data = bincode::serialize(&self.kind).unwrap();
 
-                Ok(pub_key.verify(&data, &signature).is_ok())
+                Ok(pub_key.verify(&data, signature).is_ok())
             }
 
             None => Ok(false),
@@ -102,7 +100,7 @@ impl Key {
         let data = match &self.kind {
             KeyKind::Public(key) => key.clone(),
             KeyKind::Pair(key) => {
-                let pair = Ed25519KeyPair::from_pkcs8(&key).unwrap();
+                let pair = Ed25519KeyPair::from_pkcs8(key).unwrap();
                 let key = pair.public_key();
 
                 key.as_ref().to_owned()
@@ -116,7 +114,7 @@ impl Key {
         }
     }
 
-    pub fn raw<'a>(&'a self) -> &'a [u8] {
+    pub fn raw(&self) -> &[u8] {
         match &self.kind {
             KeyKind::Public(data) | KeyKind::Pair(data) => &data[..],
         }
@@ -0,0 +1,296 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::files::{tree::EntryData, Error, Files, Object, Path, Revision};
+
+/// A file that differs between two revisions of a tree
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added {
+        path: String,
+        object: Object,
+    },
+    Modified {
+        path: String,
+        old: Object,
+        new: Object,
+    },
+    Deleted {
+        path: String,
+        object: Object,
+    },
+    /// A file that was deleted from one path, and added at another with the same contents
+    Moved {
+        from: String,
+        to: String,
+        object: Object,
+    },
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Added { path, object } => write!(f, "A {path} {object:?}"),
+            Change::Modified { path, old, new } => write!(f, "M {path} {old:?} -> {new:?}"),
+            Change::Deleted { path, object } => write!(f, "D {path} {object:?}"),
+            Change::Moved { from, to, object } => write!(f, "R {from} -> {to} {object:?}"),
+        }
+    }
+}
+
+/// Join the name of a child onto the path of it's parent
+fn join(parent: &str, name: &str) -> String {
+    if parent == "/" {
+        format!("/{name}")
+    } else {
+        format!("{parent}/{name}")
+    }
+}
+
+/// Pair up files that were deleted with files that were added with the same contents, as they were moved
+fn find_moves(changes: Vec<Change>) -> Vec<Change> {
+    let mut added: HashMap<Object, Vec<usize>> = HashMap::new();
+
+    for (i, change) in changes.iter().enumerate().rev() {
+        if let Change::Added { object, .. } = change {
+            added.entry(*object).or_default().push(i);
+        }
+    }
+
+    // the index of each added file that was moved, and where it was moved from
+    let mut moved: HashMap<usize, String> = HashMap::new();
+    let mut deleted = vec![false; changes.len()];
+
+    for (i, change) in changes.iter().enumerate() {
+        if let Change::Deleted { path, object } = change {
+            if let Some(to) = added.get_mut(object).and_then(|added| added.pop()) {
+                moved.insert(to, path.clone());
+                deleted[i] = true;
+            }
+        }
+    }
+
+    changes
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !deleted[*i])
+        .map(|(i, change)| match (change, moved.remove(&i)) {
+            (Change::Added { path, object }, Some(from)) => Change::Moved {
+                from,
+                to: path,
+                object,
+            },
+            (change, _) => change,
+        })
+        .collect()
+}
+
+impl Files {
+    /// Find the entry at a given `path` in the tree stored as `root`
+    pub(super) fn resolve(&self, root: Object, path: Path) -> Result<Option<EntryData>, Error> {
+        let mut entry = EntryData::Dir(root);
+
+        if path.as_str() == "/" {
+            return Ok(Some(entry));
+        }
+
+        for part in path.parts() {
+            let EntryData::Dir(object) = entry else {
+                return Ok(None);
+            };
+
+            let child = self
+                .read_dir(&object)?
+                .into_iter()
+                .find(|(name, _)| name == part);
+
+            match child {
+                Some((_, child)) => entry = child,
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(entry))
+    }
+
+    /// Compare the entries at a given `path` in two revisions, along with everything below them. Directories that are
+    /// stored as the same [Object] are identical, so are skipped
+    fn diff_entries(
+        &self,
+        path: &str,
+        old: Option<EntryData>,
+        new: Option<EntryData>,
+        changes: &mut Vec<Change>,
+    ) -> Result<(), Error> {
+        let split = |entry| match entry {
+            Some(EntryData::File(object)) => (Some(object), None),
+            Some(EntryData::Dir(object)) => (None, Some(object)),
+            None => (None, None),
+        };
+
+        let (old_file, old_dir) = split(old);
+        let (new_file, new_dir) = split(new);
+
+        let change = match (old_file, new_file) {
+            (Some(old), Some(new)) if old != new => Some(Change::Modified {
+                path: path.to_owned(),
+                old,
+                new,
+            }),
+            (Some(object), None) => Some(Change::Deleted {
+                path: path.to_owned(),
+                object,
+            }),
+            (None, Some(object)) => Some(Change::Added {
+                path: path.to_owned(),
+                object,
+            }),
+            _ => None,
+        };
+
+        changes.extend(change);
+
+        if old_dir == new_dir {
+            return Ok(());
+        }
+
+        let children = |dir: Option<Object>| -> Result<BTreeMap<String, EntryData>, Error> {
+            match dir {
+                Some(dir) => Ok(self.read_dir(&dir)?.into_iter().collect()),
+                None => Ok(BTreeMap::new()),
+            }
+        };
+
+        let old = children(old_dir)?;
+        let new = children(new_dir)?;
+        let names: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+
+        for name in names {
+            let child = join(path, name);
+
+            self.diff_entries(
+                &child,
+                old.get(name).copied(),
+                new.get(name).copied(),
+                changes,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+// public helpers
+impl Files {
+    /// List the files that differ at or below a given `path`, between the `from` and `to` revisions of a `tree`, in
+    /// order of their paths
+    pub fn diff(
+        &self,
+        tree: &str,
+        from: Revision,
+        to: Revision,
+        path: Path,
+    ) -> Result<Vec<Change>, Error> {
+        Files::check_tree_name(tree)?;
+
+        log::debug!("comparing {path} @ {from:?} to {to:?} in tree '{tree}'");
+
+        let (_, old_root) = self.get_revision(tree, from)?;
+        let (_, new_root) = self.get_revision(tree, to)?;
+
+        let old = self.resolve(old_root, path)?;
+        let new = self.resolve(new_root, path)?;
+
+        let mut changes = vec![];
+        self.diff_entries(path.as_str(), old, new, &mut changes)?;
+
+        Ok(find_moves(changes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Change;
+    use crate::files::{tests, Files, Object, Path, Revision, DEFAULT_TREE};
+
+    /// The object of the file at a given `path` in the latest revision
+    fn object(files: &Files, path: &str) -> Object {
+        let node = files
+            .get_node(
+                DEFAULT_TREE,
+                Path::new(path).unwrap(),
+                Revision::FromLatest(0),
+            )
+            .unwrap()
+            .unwrap();
+
+        *node.file().unwrap()
+    }
+
+    #[test]
+    fn changes_are_listed() {
+        let files = tests::open();
+        let path = |path| Path::new(path).unwrap();
+
+        files.insert(path("/a/same"), b"same").unwrap();
+        files.insert(path("/a/changed"), b"old").unwrap();
+        files.insert(path("/a/gone"), b"gone").unwrap();
+        files.insert(path("/b/moved"), b"moved").unwrap();
+        files.insert(path("/untouched/file"), b"file").unwrap();
+
+        let old = object(&files, "/a/changed");
+        let gone = object(&files, "/a/gone");
+        let moved = object(&files, "/b/moved");
+
+        files.insert(path("/a/changed"), b"new").unwrap();
+        files.insert(path("/a/new/file"), b"added").unwrap();
+        files.delete(DEFAULT_TREE, path("/a/gone"), None).unwrap();
+        files
+            .rename(DEFAULT_TREE, path("/b/moved"), path("/c"), None)
+            .unwrap();
+
+        // the revision before the last 4 changes
+        let from = Revision::FromLatest(4);
+
+        let changes = files
+            .diff(DEFAULT_TREE, from, Revision::FromLatest(0), path("/"))
+            .unwrap();
+
+        assert_eq!(
+            changes,
+            vec![
+                Change::Modified {
+                    path: "/a/changed".to_owned(),
+                    old,
+                    new: object(&files, "/a/changed"),
+                },
+                Change::Deleted {
+                    path: "/a/gone".to_owned(),
+                    object: gone,
+                },
+                Change::Added {
+                    path: "/a/new/file".to_owned(),
+                    object: object(&files, "/a/new/file"),
+                },
+                Change::Moved {
+                    from: "/b/moved".to_owned(),
+                    to: "/c".to_owned(),
+                    object: moved,
+                },
+            ]
+        );
+
+        // only changes below the path are listed
+        let changes = files
+            .diff(DEFAULT_TREE, from, Revision::FromLatest(0), path("/a/new"))
+            .unwrap();
+
+        assert_eq!(changes.len(), 1);
+
+        // a revision doesn't differ from itself
+        let changes = files.diff(DEFAULT_TREE, from, from, path("/")).unwrap();
+
+        assert!(changes.is_empty());
+    }
+}
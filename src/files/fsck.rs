@@ -0,0 +1,472 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::files::{tree::EntryData, Error, Files, Object};
+
+/// How a revision that references a damaged [Object] is reached from it's root
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Referrer {
+    /// The index of the revision in the root's history, from the earliest
+    Revision(usize),
+    /// The name of a tag pinning the revision, which may no longer be in the root's history
+    Tag(String),
+}
+
+impl std::fmt::Display for Referrer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Referrer::Revision(index) => write!(f, "revision {index}"),
+            Referrer::Tag(name) => write!(f, "the revision tagged '{name}'"),
+        }
+    }
+}
+
+/// A revision of a tree that references a damaged [Object], and the path it is referenced at
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub root: String,
+    pub revision: Referrer,
+    pub path: String,
+}
+
+/// An [Object] that could not be read back as it was stored
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Damaged {
+    pub object: Object,
+    /// Why the object is damaged
+    pub reason: String,
+    /// Everything that references the object. Empty if it is not referenced at all, in which case it can simply be
+    /// collected
+    pub references: Vec<Reference>,
+}
+
+/// The outcome of checking the integrity of the database
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FsckReport {
+    /// Number of objects checked
+    pub objects: usize,
+    /// Objects that are stored, but are corrupt
+    pub corrupt: Vec<Damaged>,
+    /// Objects that are referenced, but not stored
+    pub missing: Vec<Damaged>,
+    /// Roots whose history could not be read, along with the error encountered
+    pub histories: Vec<(String, String)>,
+    /// Trees whose tags could not be read, along with the error encountered
+    pub tags: Vec<(String, String)>,
+}
+
+impl FsckReport {
+    /// Whether no damage was found
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty()
+            && self.missing.is_empty()
+            && self.histories.is_empty()
+            && self.tags.is_empty()
+    }
+}
+
+impl std::fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (root, error) in &self.histories {
+            writeln!(f, "history of root '{root}' is unreadable: {error}")?;
+        }
+
+        for (tree, error) in &self.tags {
+            writeln!(f, "tags of tree '{tree}' are unreadable: {error}")?;
+        }
+
+        for (kind, damaged) in [("corrupt", &self.corrupt), ("missing", &self.missing)] {
+            for damaged in damaged {
+                writeln!(f, "{kind} object {:?}: {}", damaged.object, damaged.reason)?;
+
+                if damaged.references.is_empty() {
+                    writeln!(f, "    not referenced")?;
+                }
+
+                for reference in &damaged.references {
+                    writeln!(
+                        f,
+                        "    referenced by '{}' in {} of root '{}'",
+                        reference.path, reference.revision, reference.root
+                    )?;
+                }
+            }
+        }
+
+        write!(
+            f,
+            "checked {} objects: {} corrupt, {} missing, {} unreadable histories, {} unreadable tags",
+            self.objects,
+            self.corrupt.len(),
+            self.missing.len(),
+            self.histories.len(),
+            self.tags.len()
+        )
+    }
+}
+
+/// The damaged objects found below a directory or file, by their path relative to it. Cached for each [Object], so
+/// that trees shared between revisions are only checked once
+type Found = Vec<(String, Object)>;
+
+/// State kept while checking the trees of the database
+struct Checker<'a> {
+    files: &'a Files,
+    /// Damaged objects, along with the reason they are damaged
+    damaged: HashMap<Object, String>,
+    checked: HashMap<Object, Found>,
+}
+
+impl<'a> Checker<'a> {
+    /// Record `object` as damaged, unless it is already known to be
+    fn damage(&mut self, object: Object, reason: impl FnOnce() -> String) {
+        self.damaged.entry(object).or_insert_with(reason);
+    }
+
+    /// Check that an object referenced from a tree is stored, and was not found to be corrupt
+    fn intact(&mut self, object: Object) -> bool {
+        if self.damaged.contains_key(&object) {
+            return false;
+        }
+
+        match self.files.objects.contains(&object) {
+            Ok(true) => true,
+            Ok(false) => {
+                self.damage(object, || "not found".to_owned());
+                false
+            }
+            Err(e) => {
+                self.damage(object, || e.to_string());
+                false
+            }
+        }
+    }
+
+    /// Check a revision of a `root`, noting where it references damaged objects
+    fn references(
+        &mut self,
+        references: &mut BTreeMap<[u8; 32], Vec<Reference>>,
+        root: &str,
+        revision: Referrer,
+        object: Object,
+    ) {
+        for (path, object) in self.dir(object) {
+            let path = if path.is_empty() {
+                "/".to_owned()
+            } else {
+                path
+            };
+
+            references
+                .entry(*object.hash())
+                .or_default()
+                .push(Reference {
+                    root: root.to_owned(),
+                    revision: revision.clone(),
+                    path,
+                });
+        }
+    }
+
+    /// Check a stored directory, and everything below it
+    fn dir(&mut self, object: Object) -> Found {
+        if let Some(found) = self.checked.get(&object) {
+            return found.clone();
+        }
+
+        let found = if !self.intact(object) {
+            vec![(String::new(), object)]
+        } else {
+            match self.files.read_dir(&object) {
+                Ok(children) => {
+                    let mut found = vec![];
+
+                    for (name, child) in children {
+                        let below = match child {
+                            EntryData::Dir(object) => self.dir(object),
+                            EntryData::File(object) => self.file(object),
+                        };
+
+                        found.extend(
+                            below
+                                .into_iter()
+                                .map(|(path, object)| (format!("/{name}{path}"), object)),
+                        );
+                    }
+
+                    found
+                }
+
+                Err(e) => {
+                    self.damage(object, || e.to_string());
+                    vec![(String::new(), object)]
+                }
+            }
+        };
+
+        self.checked.insert(object, found.clone());
+
+        found
+    }
+
+    /// Check a file's [Manifest], and all of it's chunks
+    fn file(&mut self, object: Object) -> Found {
+        if let Some(found) = self.checked.get(&object) {
+            return found.clone();
+        }
+
+        let found = if !self.intact(object) {
+            vec![(String::new(), object)]
+        } else {
+            match self.files.manifest(&object) {
+                Ok(manifest) => manifest
+                    .chunks()
+                    .iter()
+                    .filter(|chunk| !self.intact(**chunk))
+                    .map(|chunk| (String::new(), *chunk))
+                    .collect(),
+
+                Err(e) => {
+                    self.damage(object, || e.to_string());
+                    vec![(String::new(), object)]
+                }
+            }
+        };
+
+        self.checked.insert(object, found.clone());
+
+        found
+    }
+}
+
+impl Files {
+    /// Check the integrity of the database; that every object's contents still hash to it's key, that every object
+    /// referenced by a tree exists, and that the history of every root can be read
+    pub fn fsck(&self) -> Result<FsckReport, Error> {
+        let _guard = self.collector.write();
+
+        log::info!("checking database integrity");
+
+        let mut report = FsckReport::default();
+        let mut checker = Checker {
+            files: self,
+            damaged: HashMap::new(),
+            checked: HashMap::new(),
+        };
+
+        // check the contents of every stored object
+        for object in self.objects.objects() {
+            let object = object?;
+            report.objects += 1;
+
+            // the object may have been removed since it was listed
+            let Some(data) = self.objects.get(&object)? else {
+                continue;
+            };
+
+            match self.decode_object(&data) {
+                Ok(data) if Object::of(&data) == object => (),
+                Ok(data) => checker.damage(object, || {
+                    format!("contents hash to {:?}", Object::of(data))
+                }),
+                Err(e) => checker.damage(object, || format!("failed to decode: {e}")),
+            }
+        }
+
+        // check every revision of every root, and note where damaged objects are referenced
+        let mut references: BTreeMap<[u8; 32], Vec<Reference>> = BTreeMap::new();
+
+        for entry in self.roots.iter() {
+            let (root, history) = entry?;
+            let root = String::from_utf8_lossy(&root).into_owned();
+
+            let history = match self.decode_history(&history) {
+                Ok(history) => history,
+                Err(e) => {
+                    report.histories.push((root, e.to_string()));
+                    continue;
+                }
+            };
+
+            for (revision, (_, object)) in history.into_iter().enumerate() {
+                checker.references(&mut references, &root, Referrer::Revision(revision), object);
+            }
+        }
+
+        // tagged revisions are kept from being collected even if they are no longer in history
+        for entry in self.tags.iter() {
+            let (tree, tags) = entry?;
+            let tree = String::from_utf8_lossy(&tree).into_owned();
+
+            let tags = match self.decode_tags(Some(tags)) {
+                Ok(tags) => tags,
+                Err(e) => {
+                    report.tags.push((tree, e.to_string()));
+                    continue;
+                }
+            };
+
+            for (name, (_, object)) in tags {
+                checker.references(&mut references, &tree, Referrer::Tag(name), object);
+            }
+        }
+
+        // sort damaged objects in to those that are stored, and those that are missing
+        let mut damaged: Vec<_> = checker.damaged.into_iter().collect();
+        damaged.sort_by_key(|(object, _)| *object.hash());
+
+        for (object, reason) in damaged {
+            let damaged = Damaged {
+                object,
+                reason,
+                references: references.remove(object.hash()).unwrap_or_default(),
+            };
+
+            if self.objects.contains(&object)? {
+                report.corrupt.push(damaged);
+            } else {
+                report.missing.push(damaged);
+            }
+        }
+
+        log::info!(
+            "integrity check finished: {} corrupt, {} missing",
+            report.corrupt.len(),
+            report.missing.len()
+        );
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Damaged, Referrer};
+    use crate::files::{tests, Object, Path, Revision, DEFAULT_TREE};
+
+    #[test]
+    fn clean_database() {
+        let files = tests::open();
+
+        files.insert(Path::new("/a/b").unwrap(), b"fine").unwrap();
+
+        let report = files.fsck().unwrap();
+        assert!(report.is_clean(), "{report}");
+        assert!(report.objects > 0);
+    }
+
+    /// The revisions and paths an object is referenced at
+    fn referenced(damaged: &Damaged) -> Vec<(Referrer, &str)> {
+        damaged
+            .references
+            .iter()
+            .map(|reference| (reference.revision.clone(), reference.path.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn corrupt_and_missing_objects() {
+        let files = tests::open();
+        let data = tests::data(7, 512 * 1024);
+
+        files.insert(Path::new("/dir/big").unwrap(), &data).unwrap();
+        files
+            .insert(Path::new("/small").unwrap(), b"small")
+            .unwrap();
+
+        // corrupt one of the chunks of the big file
+        let big = files.write_file(&data).unwrap();
+        let chunk = files.manifest(&big).unwrap().chunks()[1];
+        files.objects.insert(&chunk, b"garbage".to_vec()).unwrap();
+
+        // and remove the only chunk of the small file
+        let small = Object::of(b"small");
+        files.objects.remove(&small).unwrap();
+
+        let report = files.fsck().unwrap();
+        assert!(!report.is_clean());
+
+        assert_eq!(report.corrupt.len(), 1);
+        assert_eq!(report.corrupt[0].object, chunk);
+        assert_eq!(
+            referenced(&report.corrupt[0]),
+            [
+                (Referrer::Revision(1), "/dir/big"),
+                (Referrer::Revision(2), "/dir/big")
+            ]
+        );
+
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].object, small);
+        assert_eq!(
+            referenced(&report.missing[0]),
+            [(Referrer::Revision(2), "/small")]
+        );
+    }
+
+    #[test]
+    fn tagged_revisions_are_checked() {
+        let files = tests::open();
+
+        files.insert(Path::new("/old").unwrap(), b"old").unwrap();
+        files
+            .create_tag(DEFAULT_TREE, "old", Revision::FromLatest(0))
+            .unwrap();
+        files
+            .delete(DEFAULT_TREE, Path::new("/old").unwrap(), None)
+            .unwrap();
+
+        // as if the tagged revision had been pruned just before it was tagged
+        let tagged = files.tagged(DEFAULT_TREE.as_bytes()).unwrap();
+        let mut history = files.get_history(DEFAULT_TREE).unwrap();
+        history.retain(|(_, object)| !tagged.contains(object));
+
+        files
+            .roots
+            .insert(DEFAULT_TREE, files.encode_history(&history).unwrap())
+            .unwrap();
+
+        // corrupt the only chunk of the file, which only the tagged revision still references
+        let old = Object::of(b"old");
+        files.objects.insert(&old, b"garbage".to_vec()).unwrap();
+
+        // and tag a revision that was never stored
+        let mut tags = files
+            .decode_tags(files.tags.get(DEFAULT_TREE).unwrap())
+            .unwrap();
+        let lost = Object::of(b"never stored");
+        tags.insert("lost".to_owned(), (0, lost));
+        files
+            .tags
+            .insert(DEFAULT_TREE, files.encode_tags(&tags).unwrap())
+            .unwrap();
+
+        let report = files.fsck().unwrap();
+
+        assert_eq!(report.corrupt.len(), 1);
+        assert_eq!(report.corrupt[0].object, old);
+        assert_eq!(
+            referenced(&report.corrupt[0]),
+            [(Referrer::Tag("old".to_owned()), "/old")]
+        );
+
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].object, lost);
+        assert_eq!(
+            referenced(&report.missing[0]),
+            [(Referrer::Tag("lost".to_owned()), "/")]
+        );
+    }
+
+    #[test]
+    fn unreadable_history() {
+        let files = tests::open();
+
+        files.roots.insert("broken", &b"not a history"[..]).unwrap();
+
+        let report = files.fsck().unwrap();
+        assert_eq!(report.histories.len(), 1);
+        assert_eq!(report.histories[0].0, "broken");
+    }
+}
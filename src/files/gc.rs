@@ -0,0 +1,404 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::ThreadId;
+
+use crate::files::{tree::EntryData, Error, Files, Object};
+
+/// Co-ordinates writers to the database with the garbage collector.
+///
+/// An object is created before it is linked into a tree, so a collection running between the two would sweep it. Writers
+/// hold a [WriteGuard] while they link objects into a tree, and a collection waits for there to be no writers before it
+/// starts marking. Once a collection is waiting, new writers wait for it to finish sweeping, so a steady stream of writers
+/// can't hold it off forever. Files received from a client are stored long before they are linked, so rather than
+/// holding a guard all that time, the objects they are stored as are kept in a [Pending] set, which the collector never
+/// sweeps.
+#[derive(Default)]
+pub struct Collector {
+    state: Mutex<State>,
+    /// Notified whenever the last writer finishes, or a collection finishes
+    changed: Condvar,
+    /// Objects that have been stored but not yet linked, along with the number of [Pending] sets holding each
+    pending: Mutex<HashMap<[u8; 32], usize>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// The number of [WriteGuard]s held by each thread
+    writers: HashMap<ThreadId, usize>,
+    /// Whether a collection is waiting for writers to finish, or running
+    collecting: bool,
+}
+
+/// Marks an operation that may create new objects. See [Collector] for details. A thread that already holds a guard can
+/// take more without waiting, so a guard must be dropped by the thread that took it
+pub struct WriteGuard {
+    collector: Arc<Collector>,
+    _thread: PhantomData<*const ()>,
+}
+
+/// Held by a running collection, holding off new writers until it is dropped
+struct CollectGuard<'a>(&'a Collector);
+
+/// Objects that have been stored, but not yet linked into a tree. They are kept by the collector until the set is
+/// dropped, so it should be kept alive until they have been linked
+pub struct Pending {
+    collector: Arc<Collector>,
+    objects: HashSet<[u8; 32]>,
+}
+
+impl Collector {
+    /// Register a new writer. Unless the current thread is already a writer, this blocks while a collection is waiting
+    /// or running, so must not be called from an async worker
+    pub fn write(self: &Arc<Collector>) -> WriteGuard {
+        let thread = std::thread::current().id();
+        let state = self.state.lock().unwrap();
+
+        // a nested guard must not wait, as the collection it would wait for is waiting for the outer guard
+        let mut state = self
+            .changed
+            .wait_while(state, |state| {
+                state.collecting && !state.writers.contains_key(&thread)
+            })
+            .unwrap();
+
+        *state.writers.entry(thread).or_default() += 1;
+
+        WriteGuard {
+            collector: self.clone(),
+            _thread: PhantomData,
+        }
+    }
+
+    /// Begin a new, empty [Pending] set
+    pub fn pending(self: &Arc<Collector>) -> Pending {
+        Pending {
+            collector: self.clone(),
+            objects: HashSet::new(),
+        }
+    }
+
+    /// Run `sweep` on an `object`, unless it is pending. Objects can't be added to a [Pending] set in the meantime, so
+    /// one that is about to be stored again is either kept, or stored again once it has been swept
+    fn unless_pending<T>(&self, object: &Object, sweep: impl FnOnce() -> T) -> Option<T> {
+        let pending = self.pending.lock().unwrap();
+
+        (!pending.contains_key(object.hash())).then(sweep)
+    }
+
+    /// Wait for any other collection, and then for all writers, to finish. New writers wait until the returned guard is
+    /// dropped
+    fn collect(&self) -> CollectGuard<'_> {
+        let state = self.state.lock().unwrap();
+        let mut state = self
+            .changed
+            .wait_while(state, |state| state.collecting)
+            .unwrap();
+
+        state.collecting = true;
+
+        let _state = self
+            .changed
+            .wait_while(state, |state| !state.writers.is_empty())
+            .unwrap();
+
+        CollectGuard(self)
+    }
+}
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        let thread = std::thread::current().id();
+        let mut state = self.collector.state.lock().unwrap();
+
+        if let Some(count) = state.writers.get_mut(&thread) {
+            *count -= 1;
+
+            if *count == 0 {
+                state.writers.remove(&thread);
+            }
+        }
+
+        if state.writers.is_empty() {
+            self.collector.changed.notify_all();
+        }
+    }
+}
+
+impl<'a> Drop for CollectGuard<'a> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().collecting = false;
+        self.0.changed.notify_all();
+    }
+}
+
+impl Pending {
+    /// Keep an `object` from being collected. This must be done before it is stored, or found to be stored already
+    pub fn add(&mut self, object: Object) {
+        if self.objects.insert(*object.hash()) {
+            *self
+                .collector
+                .pending
+                .lock()
+                .unwrap()
+                .entry(*object.hash())
+                .or_default() += 1;
+        }
+    }
+
+    /// Move the objects of `other` into this set
+    pub fn extend(&mut self, mut other: Pending) {
+        for hash in std::mem::take(&mut other.objects) {
+            if !self.objects.insert(hash) {
+                // both sets were holding it, and now only this one is, so `other` lets go of it as it is dropped
+                other.objects.insert(hash);
+            }
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let mut pending = self.collector.pending.lock().unwrap();
+
+        for hash in &self.objects {
+            if let Some(count) = pending.get_mut(hash) {
+                *count -= 1;
+
+                if *count == 0 {
+                    pending.remove(hash);
+                }
+            }
+        }
+    }
+}
+
+/// The outcome of a garbage collection pass
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct GcReport {
+    /// Number of objects still referenced by a root
+    pub live: usize,
+    /// Number of objects that were removed
+    pub removed: usize,
+    /// Total size of the objects that were removed, in bytes
+    pub reclaimed: u64,
+}
+
+impl std::fmt::Display for GcReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "removed {} objects ({} bytes), {} objects still live",
+            self.removed, self.reclaimed, self.live
+        )
+    }
+}
+
+impl Files {
+    /// Remove every [Object] that is not reachable from any revision of any root, or from any tag
+    pub fn collect_garbage(&self) -> Result<GcReport, Error> {
+        let _guard = self.collector.collect();
+
+        log::info!("collecting garbage");
+
+        // mark every object reachable from a root
+        let mut marked = HashSet::new();
+
+        for entry in self.roots.iter() {
+            let (root, history) = entry?;
+            let history = self.decode_history(&history)?;
+
+            log::debug!(
+                "marking {} revisions of root '{}'",
+                history.len(),
+                String::from_utf8_lossy(&root)
+            );
+
+            for (_, object) in history {
+                self.mark_tree(object, &mut marked)?;
+            }
+        }
+
+        // tagged revisions are normally kept in history, but may have been pruned just before they were tagged
+        for object in self.all_tagged()? {
+            self.mark_tree(object, &mut marked)?;
+        }
+
+        // sweep every object that was not marked
+        let mut report = GcReport {
+            live: marked.len(),
+            ..GcReport::default()
+        };
+
+        for object in self.objects.objects() {
+            let object = object?;
+
+            if marked.contains(object.hash()) {
+                continue;
+            }
+
+            let removed = self
+                .collector
+                .unless_pending(&object, || self.objects.remove(&object))
+                .transpose()?
+                .flatten();
+
+            if let Some(len) = removed {
+                report.removed += 1;
+                report.reclaimed += len;
+            }
+        }
+
+        self.objects.flush()?;
+        self.db.flush()?;
+
+        log::info!("garbage collection finished: {report}");
+
+        Ok(report)
+    }
+
+    /// Mark a stored directory, and every [Object] below it
+    fn mark_tree(&self, object: Object, marked: &mut HashSet<[u8; 32]>) -> Result<(), Error> {
+        if !marked.insert(*object.hash()) {
+            // we've already seen this directory in another revision
+            return Ok(());
+        }
+
+        for (_, child) in self.read_dir(&object)? {
+            match child {
+                EntryData::Dir(object) => self.mark_tree(object, marked)?,
+                EntryData::File(object) => self.mark_file(object, marked)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Mark a file's [Manifest], and all of it's chunks
+    fn mark_file(&self, object: Object, marked: &mut HashSet<[u8; 32]>) -> Result<(), Error> {
+        if !marked.insert(*object.hash()) {
+            return Ok(());
+        }
+
+        for chunk in self.manifest(&object)?.chunks() {
+            marked.insert(*chunk.hash());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    use crate::files::{tests, Path, Revision, DEFAULT_TREE};
+
+    #[test]
+    fn unreferenced_objects_are_swept() {
+        let files = tests::open();
+
+        files.insert(Path::new("/a").unwrap(), b"linked").unwrap();
+        let orphan = files.create_object(b"never linked").unwrap();
+        let stored = files.objects.get(&orphan).unwrap().unwrap();
+
+        let report = files.collect_garbage().unwrap();
+
+        assert_eq!(report.removed, 1);
+        assert_eq!(report.reclaimed, stored.len() as u64);
+        assert!(files.objects.get(&orphan).unwrap().is_none());
+
+        // a second pass finds nothing left to remove
+        let report = files.collect_garbage().unwrap();
+        assert_eq!(report.removed, 0);
+    }
+
+    #[test]
+    fn history_survives() {
+        let files = tests::open();
+        let path = Path::new("/a").unwrap();
+
+        files.insert(path, b"first").unwrap();
+        files.insert(path, b"second").unwrap();
+        files.delete(DEFAULT_TREE, path, None).unwrap();
+
+        let report = files.collect_garbage().unwrap();
+        assert_eq!(report.removed, 0);
+
+        let first = files.get(path, Revision::FromEarliest(1)).unwrap();
+        let second = files.get(path, Revision::FromEarliest(2)).unwrap();
+
+        assert_eq!(first.as_deref(), Some(&b"first"[..]));
+        assert_eq!(second.as_deref(), Some(&b"second"[..]));
+    }
+
+    #[test]
+    fn files_being_written_are_kept() {
+        let files = tests::open();
+        let path = Path::new("/a").unwrap();
+        let data = tests::data(1, 3 * 1024 * 1024);
+
+        // a file being written doesn't hold off collection, but what it has stored is kept
+        let mut writer = files.file_writer();
+        writer.write(&files, &data[..2 * 1024 * 1024]).unwrap();
+
+        assert_eq!(files.collect_garbage().unwrap().removed, 0);
+
+        writer.write(&files, &data[2 * 1024 * 1024..]).unwrap();
+        let object = writer.finish(&files).unwrap();
+
+        assert_eq!(files.collect_garbage().unwrap().removed, 0);
+
+        files
+            .insert_object(DEFAULT_TREE, path, object, None)
+            .unwrap();
+        drop(writer);
+
+        assert_eq!(files.collect_garbage().unwrap().removed, 0);
+        assert_eq!(
+            files.get(path, Revision::FromLatest(0)).unwrap().unwrap(),
+            data
+        );
+
+        // once a file is abandoned, what it stored is collected
+        let mut writer = files.file_writer();
+        writer.write(&files, &tests::data(2, 1024 * 1024)).unwrap();
+        drop(writer);
+
+        assert!(files.collect_garbage().unwrap().removed > 0);
+    }
+
+    #[test]
+    fn collection_waits_for_writers() {
+        let files = Arc::new(tests::open());
+        let path = Path::new("/a").unwrap();
+
+        let guard = files.collector.write();
+        let (done, finished) = mpsc::channel();
+
+        let collection = {
+            let files = files.clone();
+
+            std::thread::spawn(move || {
+                let report = files.collect_garbage().unwrap();
+                done.send(()).unwrap();
+                report
+            })
+        };
+
+        assert!(finished.recv_timeout(Duration::from_millis(200)).is_err());
+
+        // the writer can still take more guards while the collection waits for it
+        files.insert(path, b"data").unwrap();
+        drop(guard);
+
+        finished.recv().unwrap();
+        assert_eq!(collection.join().unwrap().removed, 0);
+
+        let data = files.get(path, Revision::FromLatest(0)).unwrap();
+        assert_eq!(data.as_deref(), Some(&b"data"[..]));
+    }
+}
@@ -0,0 +1,209 @@
+use crate::files::{Error, Files};
+
+/// The version of the format databases are written in. Whenever the way anything is stored changes, this is bumped and
+/// a [Migration] that upgrades databases from the version before is added to [MIGRATIONS]
+pub const VERSION: u32 = 5;
+
+/// The key the version of the database is recorded under, in the database's default tree
+const VERSION_KEY: &[u8] = b"version";
+
+/// Upgrades a database from the version before `version`. Migrations are ran again if they are interrupted, so must
+/// leave alone anything they have already upgraded
+struct Migration {
+    version: u32,
+    description: &'static str,
+    run: fn(&Files) -> Result<(), Error>,
+}
+
+/// Every [Migration], in order of their versions. Databases written before versions were recorded are version 0
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "store each revision of each root as a tree of directories",
+        run: Files::migrate_trees,
+    },
+    Migration {
+        version: 2,
+        description: "keep the tags of each tree",
+        run: nothing_to_migrate,
+    },
+    Migration {
+        version: 3,
+        description: "record when each tombstone was left",
+        run: nothing_to_migrate,
+    },
+    Migration {
+        version: 4,
+        description: "give every value and object a header",
+        run: Files::migrate_headers,
+    },
+    Migration {
+        version: 5,
+        description: "store every file as chunks and a manifest",
+        run: Files::migrate_files,
+    },
+];
+
+/// For versions that only add to what is stored, so nothing needs upgrading, but older versions of `ksync` must still
+/// refuse the database rather than misreading or losing what was added
+fn nothing_to_migrate(_: &Files) -> Result<(), Error> {
+    Ok(())
+}
+
+impl Files {
+    /// The version of the format the database is written in, if one has been recorded
+    fn recorded_version(&self) -> Result<Option<u32>, Error> {
+        let Some(version) = self.db.get(VERSION_KEY)? else {
+            return Ok(None);
+        };
+
+        let version = <[u8; 4]>::try_from(&version[..]).map_err(|_| Error::InvalidVersion)?;
+
+        Ok(Some(u32::from_be_bytes(version)))
+    }
+
+    fn set_version(&self, version: u32) -> Result<(), Error> {
+        self.db.insert(VERSION_KEY, &version.to_be_bytes())?;
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    /// Upgrade the database to the current [VERSION], one [Migration] at a time. Databases written by a newer version
+    /// of `ksync` are refused, rather than being misread
+    pub(super) fn migrate(&self) -> Result<(), Error> {
+        let version = match self.recorded_version()? {
+            Some(version) => version,
+            // nothing has been written yet, so there is nothing to upgrade
+            None if self.roots.is_empty() => return self.set_version(VERSION),
+            None => 0,
+        };
+
+        if version > VERSION {
+            return Err(Error::UnsupportedVersion { version });
+        }
+
+        for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+            log::info!(
+                "migrating database to version {}: {}",
+                migration.version,
+                migration.description
+            );
+
+            (migration.run)(self)?;
+            self.set_version(migration.version)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{MIGRATIONS, VERSION, VERSION_KEY};
+    use crate::files::tags::TagName;
+    use crate::files::{tests, Error, Node, Object, Path, Revision, RootHistory, DEFAULT_TREE};
+
+    #[test]
+    fn migrations_are_in_order() {
+        let versions: Vec<_> = MIGRATIONS.iter().map(|m| m.version).collect();
+        let expected: Vec<_> = (1..=VERSION).collect();
+
+        assert_eq!(versions, expected);
+    }
+
+    #[test]
+    fn old_databases_are_upgraded() {
+        let files = tests::open();
+        assert_eq!(files.recorded_version().unwrap(), Some(VERSION));
+
+        // a root stored as a single node, as it was before versions were recorded
+        let mut node = Node::new_dir();
+        let file = files.write_file(b"old").unwrap();
+        node.make_dir(Path::new("/old").unwrap()).unwrap();
+        node.insert(Path::new("/old/file").unwrap(), file).unwrap();
+
+        let object = files
+            .create_object(bincode::serialize(&node).unwrap())
+            .unwrap();
+        files.roots.merge("fs", object.hash()).unwrap();
+        files.db.remove(VERSION_KEY).unwrap();
+
+        files.migrate().unwrap();
+
+        assert_eq!(files.recorded_version().unwrap(), Some(VERSION));
+        assert_eq!(
+            files
+                .get(Path::new("/old/file").unwrap(), Revision::FromLatest(0))
+                .unwrap()
+                .unwrap(),
+            b"old"
+        );
+    }
+
+    #[test]
+    fn values_without_headers_are_upgraded() {
+        let files = tests::open();
+        let path = Path::new("/file").unwrap();
+
+        // stored as an older version would have; values had no headers, and files were a single object
+        let legacy = |data: &[u8]| {
+            let object = Object::of(data);
+            files.objects.insert(&object, data.to_vec()).unwrap();
+
+            object
+        };
+
+        let file = legacy(b"old");
+        let mut node = Node::new_dir();
+        node.insert(path, file).unwrap();
+        let root = legacy(&bincode::serialize(&node).unwrap());
+
+        let history: RootHistory = vec![(1, root)];
+        let tags = BTreeMap::from([("old".to_owned(), (1u128, root))]);
+
+        files
+            .roots
+            .insert(DEFAULT_TREE, bincode::serialize(&history).unwrap())
+            .unwrap();
+        files
+            .tags
+            .insert(DEFAULT_TREE, bincode::serialize(&tags).unwrap())
+            .unwrap();
+        files.db.remove(VERSION_KEY).unwrap();
+
+        assert!(files.get(path, Revision::FromLatest(0)).is_err());
+
+        files.migrate().unwrap();
+
+        let tag = Revision::Tag(TagName::parse("old").unwrap());
+
+        for revision in [Revision::FromLatest(0), tag] {
+            assert_eq!(files.get(path, revision).unwrap().unwrap(), b"old");
+
+            let manifest = files.get_manifest(DEFAULT_TREE, path, revision).unwrap();
+            assert_eq!(manifest.unwrap().size(), 3);
+        }
+
+        for object in files.objects.objects() {
+            files.load(&object.unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn newer_databases_are_refused() {
+        let files = tests::open();
+
+        files.set_version(VERSION + 1).unwrap();
+
+        assert!(matches!(
+            files.migrate(),
+            Err(Error::UnsupportedVersion { version }) if version == VERSION + 1
+        ));
+
+        files.db.insert(VERSION_KEY, b"?").unwrap();
+        assert!(matches!(files.migrate(), Err(Error::InvalidVersion)));
+    }
+}
@@ -1,24 +1,42 @@
+pub mod archive;
+pub mod at_rest;
+pub mod batch;
+pub mod chunk;
+pub mod compress;
 pub mod crypto;
+pub mod diff;
+pub mod fsck;
+pub mod gc;
+pub mod migrate;
 pub mod node;
+pub mod prune;
+pub mod revision;
+pub mod store;
+pub mod tags;
+pub mod tombstones;
+pub mod tree;
+pub mod versions;
 
 pub use node::*;
-
-use sled::IVec;
+pub use tree::Tree;
 
 use std::fmt::Debug;
 use std::io;
 use std::path::Path as SysPath;
+use std::sync::Arc;
 use std::time::SystemTime;
 
 use chrono::TimeZone;
 use digest::Digest;
+use sled::Transactional;
 
-use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Serialize};
 
+use crate::config::{Compression, Storage};
 use crate::util::fmt;
 
 /// An [Object] represents a content-addressable chunk of data in the database, via a SHA-256 hash
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Object([u8; 32]);
 
 impl Object {
@@ -27,13 +45,21 @@ impl Object {
         Object(hash)
     }
 
+    /// Create the [Object] that would reference `data`
+    pub fn of(data: impl AsRef<[u8]>) -> Object {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(data.as_ref());
+
+        Object(hasher.finalize().into())
+    }
+
     /// Retrieve the hash value of an [Object]
     pub fn hash(&self) -> &[u8; 32] {
         &self.0
     }
 
     /// Display the [Object] as a hex string
-    pub fn hex(&self) -> fmt::HexSlice {
+    pub fn hex(&self) -> fmt::HexSlice<'_> {
         fmt::HexSlice::from(&self.0[..])
     }
 }
@@ -44,6 +70,29 @@ impl Debug for Object {
     }
 }
 
+#[derive(thiserror::Error, Debug)]
+#[error("object must be a hash of 64 hexadecimal digits")]
+pub struct InvalidObject;
+
+impl std::str::FromStr for Object {
+    type Err = InvalidObject;
+
+    fn from_str(s: &str) -> Result<Object, InvalidObject> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(InvalidObject);
+        }
+
+        let mut hash = [0; 32];
+
+        for (byte, digits) in hash.iter_mut().zip(s.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).map_err(|_| InvalidObject)?;
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| InvalidObject)?;
+        }
+
+        Ok(Object(hash))
+    }
+}
+
 impl<'a> TryFrom<&'a str> for Path<'a> {
     type Error = InvalidPath;
 
@@ -85,7 +134,7 @@ impl<'a> Path<'a> {
     pub fn new(str: &'a str) -> Result<Path<'a>, InvalidPath> {
         // count the number of double slashes in the path
         // we skip the first empty string before the first slash
-        let double_slashes = str.split('/').skip(1).filter(|&p| p == "").count();
+        let double_slashes = str.split('/').skip(1).filter(|&p| p.is_empty()).count();
 
         // check validity of path
         if !str.starts_with('/') || (double_slashes != 0 && str != "/") {
@@ -114,7 +163,7 @@ impl<'a> Path<'a> {
     /// assert!(parts.next().is_none());
     /// ```
     pub fn parts(&self) -> impl DoubleEndedIterator<Item = &'a str> {
-        (&self.0[1..]).split('/')
+        self.0[1..].split('/')
     }
 
     /// Splits a [Path] into a parent and child pair
@@ -191,8 +240,7 @@ impl<'a> Iterator for Ancestors<'a> {
                 .char_indices()
                 .filter(|&(_, c)| c == '/')
                 .map(|(i, _)| i)
-                .skip(self.iter)
-                .next();
+                .nth(self.iter);
 
             index.map(|index| Path::new(&self.path.0[0..index]).unwrap())
         } else {
@@ -211,9 +259,13 @@ impl<'a> std::fmt::Display for Path<'a> {
     }
 }
 
-fn root_merge(_key: &[u8], old_value: Option<&[u8]>, merged_bytes: &[u8]) -> Option<Vec<u8>> {
+fn root_merge(
+    keys: &at_rest::Keys,
+    old_value: Option<&[u8]>,
+    merged_bytes: &[u8],
+) -> Option<Vec<u8>> {
     let mut list: RootHistory = if let Some(bytes) = old_value {
-        bincode::deserialize(bytes).unwrap()
+        bincode::deserialize(&keys.open(bytes).unwrap()).unwrap()
     } else {
         vec![]
     };
@@ -223,15 +275,55 @@ fn root_merge(_key: &[u8], old_value: Option<&[u8]>, merged_bytes: &[u8]) -> Opt
 
     list.push((timestamp, object));
 
-    Some(bincode::serialize(&list).unwrap())
+    Some(keys.seal(bincode::serialize(&list).unwrap()))
+}
+
+/// The tree used by clients that don't name one, which is created along with the database
+pub const DEFAULT_TREE: &str = "fs";
+
+/// The root holding the server's keys. It is not a filesystem tree, so can't be accessed or managed as one
+pub(super) const KEYRING: &str = "keyring";
+
+/// A summary of a named filesystem tree
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TreeInfo {
+    pub name: String,
+    /// Number of revisions in the tree's history
+    pub revisions: usize,
+    /// When the latest revision was created
+    pub modified: u128,
+    /// The [Object] the latest revision is stored as, which writers may give as the parent of their changes
+    pub latest: Object,
+}
+
+impl std::fmt::Display for TreeInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let date = chrono::Local.timestamp_nanos(self.modified as i64);
+
+        write!(
+            f,
+            "{}: {} revisions, modified {date}, latest {}",
+            self.name,
+            self.revisions,
+            self.latest.hex()
+        )
+    }
 }
 
 pub struct Files {
     db: sled::Db,
-    /// A tree that maps an [Object] to it's data
-    objects: sled::Tree,
-    /// A tree that maps a string "root" name, to an [Object] containing a filesystem [Node]
+    /// Maps an [Object] to it's data
+    objects: Box<dyn store::ObjectStore>,
+    /// A tree that maps a string "root" name, to the history of the [Object]s containing the root directory of a [Tree]
     roots: sled::Tree,
+    /// A tree that maps the name of a root to it's tags, each pinning a name to a revision
+    tags: sled::Tree,
+    /// Keeps the garbage collector from sweeping objects that are in the process of being linked
+    collector: Arc<gc::Collector>,
+    /// How new objects are compressed
+    compression: Compression,
+    /// The keys values are encrypted with while they are stored
+    keys: Arc<at_rest::Keys>,
 }
 
 pub type RootHistory = Vec<(u128, Object)>;
@@ -241,6 +333,21 @@ pub enum Revision {
     FromLatest(usize),
     FromEarliest(usize),
     AsOfTime(u128),
+    /// The revision stored as the [Object] whose hash starts with the given digits
+    Prefix(revision::ObjectPrefix),
+    /// The revision pinned by a tag
+    Tag(tags::TagName),
+}
+
+/// A change was made to a revision of a tree that is no longer it's latest, as another change was committed first
+#[derive(thiserror::Error, Serialize, Deserialize, Debug, Clone)]
+#[error("tree '{tree}' has changed; expected revision {expected:?}, but the latest is {actual:?}")]
+pub struct Conflict {
+    pub tree: String,
+    /// The revision the change was made to
+    pub expected: Object,
+    /// The latest revision of the tree
+    pub actual: Object,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -268,6 +375,66 @@ pub enum Error {
 
     #[error("authentication failed: {error}")]
     Authentication { error: crypto::Error },
+
+    #[error("encryption at rest: {error}")]
+    AtRest { error: at_rest::Error },
+
+    #[error("object {object:?} not found")]
+    MissingObject { object: Object },
+
+    #[error("object {object:?} is not a file")]
+    NotAFile { object: Object },
+
+    #[error("invalid archive: {reason}")]
+    InvalidArchive { reason: String },
+
+    #[error("invalid tree name '{name}'")]
+    InvalidTreeName { name: String },
+
+    #[error("tree '{name}' not found")]
+    TreeNotFound { name: String },
+
+    #[error("tree '{name}' already exists")]
+    TreeExists { name: String },
+
+    #[error("node '{path}' already exists")]
+    AlreadyExists { path: String },
+
+    #[error("can't move '{from}' to '{to}'")]
+    InvalidMove { from: String, to: String },
+
+    #[error("invalid batch: {reason}")]
+    InvalidBatch { reason: String },
+
+    #[error("revision {revision} not found")]
+    RevisionNotFound { revision: String },
+
+    #[error("revision {revision} is ambiguous")]
+    AmbiguousRevision { revision: String },
+
+    #[error("{conflict}")]
+    Conflict { conflict: Conflict },
+
+    #[error("invalid tag name '{name}'")]
+    InvalidTagName { name: String },
+
+    #[error("tag '{name}' already exists")]
+    TagExists { name: String },
+
+    #[error("tag '{name}' not found in tree '{tree}'")]
+    TagNotFound { tree: String, name: String },
+
+    #[error("database was written in format version {version} by a newer version of ksync, but only up to version {} can be read", migrate::VERSION)]
+    UnsupportedVersion { version: u32 },
+
+    #[error("database has an unreadable format version")]
+    InvalidVersion,
+
+    #[error("database stores objects with the '{recorded}' storage backend, but '{configured}' is configured")]
+    StorageMismatch {
+        recorded: String,
+        configured: String,
+    },
 }
 
 impl From<io::Error> for Error {
@@ -294,26 +461,87 @@ impl From<InvalidPath> for Error {
     }
 }
 
+impl From<Conflict> for Error {
+    fn from(conflict: Conflict) -> Error {
+        Error::Conflict { conflict }
+    }
+}
+
+impl From<sled::transaction::TransactionError<Error>> for Error {
+    fn from(error: sled::transaction::TransactionError<Error>) -> Error {
+        match error {
+            sled::transaction::TransactionError::Abort(error) => error,
+            sled::transaction::TransactionError::Storage(error) => error.into(),
+        }
+    }
+}
+
 impl From<crypto::Error> for Error {
     fn from(error: crypto::Error) -> Error {
         Error::Authentication { error }
     }
 }
 
+impl From<at_rest::Error> for Error {
+    fn from(error: at_rest::Error) -> Error {
+        Error::AtRest { error }
+    }
+}
+
 // filesystem internals
 impl Files {
-    /// Opens a [Files] database from a given path, and initialises it
-    // TODO: stop re-initialising the database on each open
-    pub fn open(path: impl AsRef<SysPath>) -> Result<Files, Error> {
+    /// Opens a [Files] database from a given path, and initialises it. Objects are kept wherever `storage` names, new
+    /// objects are compressed with `compression`, and every value is encrypted with the current of the given `keys`, if
+    /// there is one
+    pub fn open(
+        path: impl AsRef<SysPath>,
+        storage: &Storage,
+        compression: Compression,
+        keys: at_rest::Keys,
+    ) -> Result<Files, Error> {
         log::info!("opening db at {:?}", path.as_ref());
         let db = sled::open(path)?;
-        log::info!("opening objects and roots trees");
-        let objects = db.open_tree("objects")?;
-        let roots = db.open_tree("roots")?;
 
-        let files = Files { objects, roots, db };
+        store::check_backend(&db, storage)?;
+
+        let objects: Box<dyn store::ObjectStore> = match storage {
+            Storage::Sled => Box::new(store::SledStore(db.open_tree("objects")?)),
+            Storage::Dir { dir } => {
+                log::info!("storing objects in {dir:?}");
+                Box::new(store::DirStore::open(dir)?)
+            }
+        };
+
+        Files::with_objects(db, objects, compression, keys)
+    }
+
+    /// Initialise a [Files] database whose objects are kept in a given store, and everything else in `db`
+    fn with_objects(
+        db: sled::Db,
+        objects: Box<dyn store::ObjectStore>,
+        compression: Compression,
+        keys: at_rest::Keys,
+    ) -> Result<Files, Error> {
+        log::info!("opening roots and tags trees");
+        let roots = db.open_tree("roots")?;
+        let tags = db.open_tree("tags")?;
+
+        let files = Files {
+            objects,
+            roots,
+            tags,
+            db,
+            collector: Arc::default(),
+            compression,
+            keys: Arc::new(keys),
+        };
 
-        files.roots.set_merge_operator(root_merge);
+        let keys = files.keys.clone();
+        files
+            .roots
+            .set_merge_operator(move |_key: &[u8], old: Option<&[u8]>, merged: &[u8]| {
+                root_merge(&keys, old, merged)
+            });
 
         files.initialise()?;
 
@@ -325,22 +553,26 @@ impl Files {
     }
 
     fn initialise(&self) -> Result<(), Error> {
-        // if root node does not exist, create it
-        if self.roots.get("fs")?.is_none() {
-            let dir = Node::new_dir();
-            let object = self.serialize(&dir)?;
-            self.roots.merge("fs", object.hash())?;
+        // make sure the database can be read with the keys it was opened with, before anything is written to it
+        if let Some(history) = self.roots.get(KEYRING)? {
+            self.keys.open_legacy(&history)?;
         }
 
-        // initialise keyring
-        if self.roots.get("keyring")?.is_none() {
-            let dir = Node::new_dir();
-            let object = self.serialize(&dir)?;
-            self.roots.merge("keyring", object.hash())?;
+        self.migrate()?;
+
+        // initialise keyring. the default tree is only created along with it, so that it stays deleted if it is
+        if self.roots.get(KEYRING)?.is_none() {
+            if self.roots.get(DEFAULT_TREE)?.is_none() {
+                let object = self.store_empty_dir()?;
+                self.roots.merge(DEFAULT_TREE, object.hash())?;
+            }
+
+            let object = self.store_empty_dir()?;
+            self.roots.merge(KEYRING, object.hash())?;
 
-            self.with_root_mut("keyring", |node| {
-                node.make_dir(Path::new("/self")?)?;
-                node.make_dir(Path::new("/trusted")?)?;
+            self.with_root_mut(KEYRING, |tree| {
+                tree.make_dir(Path::new("/self")?)?;
+                tree.make_dir(Path::new("/trusted")?)?;
 
                 Ok(())
             })?;
@@ -351,127 +583,270 @@ impl Files {
 
     fn get_root_history(&self, root: &str) -> Result<RootHistory, Error> {
         log::info!("loading root '{root}' history");
-        let history = self
-            .roots
-            .get(root)?
-            .ok_or(io::Error::new(io::ErrorKind::NotFound, "root not found"))?;
+        let history = self.roots.get(root)?.ok_or(Error::TreeNotFound {
+            name: root.to_owned(),
+        })?;
 
         // deserialise root into it's history
-        let history: RootHistory = bincode::deserialize(&history[..])?;
+        let history = self.decode_history(&history)?;
 
         Ok(history)
     }
 
-    fn get_root(&self, root: &str, revision: Revision) -> Result<Node, Error> {
-        // load root node from database
+    /// Find the [Object] a given `revision` of a `root` is stored as, along with the time it was created
+    fn get_revision(&self, root: &str, revision: Revision) -> Result<(u128, Object), Error> {
+        // load root history from database
         let history = self.get_root_history(root)?;
 
         // get the right node based on the query
-        // TODO: proper handling of no history errors
-        let (timestamp, object) = match revision {
-            Revision::FromLatest(n) => history.iter().nth_back(n).unwrap(),
-            Revision::FromEarliest(n) => history.iter().nth(n).unwrap(),
-            Revision::AsOfTime(n) => history.iter().take_while(|(t, _)| t < &n).last().unwrap(),
+        let found = match revision {
+            Revision::FromLatest(n) => history.iter().nth_back(n).copied(),
+            Revision::FromEarliest(n) => history.get(n).copied(),
+            Revision::AsOfTime(n) => history.iter().take_while(|(t, _)| t < &n).last().copied(),
+            Revision::Prefix(prefix) => {
+                let mut matching = history.iter().filter(|(_, object)| prefix.matches(object));
+                let found = matching.next_back();
+
+                // the same root may appear more than once, once it has been rolled back to
+                if matching.any(|(_, object)| Some(object) != found.map(|(_, found)| found)) {
+                    return Err(Error::AmbiguousRevision {
+                        revision: revision.to_string(),
+                    });
+                }
+
+                found.copied()
+            }
+            // tags are looked up on their own, so that they still resolve if their revision is dropped from history
+            Revision::Tag(name) => self.get_tag(root, name)?,
         };
 
+        let (timestamp, object) = found.ok_or_else(|| Error::RevisionNotFound {
+            revision: revision.to_string(),
+        })?;
+
         // get date-time
-        let timestamp = chrono::Local.timestamp_nanos(*timestamp as i64);
+        let date = chrono::Local.timestamp_nanos(timestamp as i64);
 
         log::info!(
-            "found node {} for root '{root}', created {timestamp}",
+            "found node {} for root '{root}', created {date}",
             object.hex()
         );
 
-        // deserialise node
-        let node = self.deserialize(object)?;
+        Ok((timestamp, object))
+    }
 
-        Ok(node)
+    fn get_root(&self, root: &str, revision: Revision) -> Result<Tree<'_>, Error> {
+        let (timestamp, object) = self.get_revision(root, revision)?;
+
+        Tree::load(self, object, timestamp)
     }
 
-    fn set_root(&self, root: &str, node: Node) -> Result<(), Error> {
-        let object = self.serialize(&node)?;
-        self.roots.merge(root, object.hash())?;
+    /// Append `object` to the history of a `root`, as long as it's latest revision is still `parent`. Fails with a
+    /// [Conflict] otherwise, rather than discarding whatever was committed in the meantime
+    fn set_root(&self, root: &str, parent: Object, object: Object) -> Result<(), Error> {
+        let mut current = self.roots.get(root)?;
+
+        loop {
+            let old = current.ok_or(Error::TreeNotFound {
+                name: root.to_owned(),
+            })?;
+
+            let mut history = self.decode_history(&old)?;
+
+            match history.last() {
+                Some(&(_, latest)) if latest != parent => {
+                    return Err(Conflict {
+                        tree: root.to_owned(),
+                        expected: parent,
+                        actual: latest,
+                    }
+                    .into());
+                }
+                _ => (),
+            }
+
+            let timestamp = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_nanos();
+            history.push((timestamp, object));
+
+            // the history may also have been changed by pruning, which leaves the latest revision as it is
+            match self.roots.compare_and_swap(
+                root,
+                Some(old),
+                Some(self.encode_history(&history)?),
+            )? {
+                Ok(()) => break,
+                Err(e) => current = e.current,
+            }
+        }
 
         log::info!("appended node {} to history of root '{root}'", object.hex());
 
         Ok(())
     }
 
-    /// Perform operations on a given `root`
+    /// Perform operations on the latest revision of a given `root`. If another change is committed to the root in the
+    /// meantime, the operations are retried on top of it
     fn with_root_mut<T>(
         &self,
         root: &str,
-        op: impl Fn(&mut Node) -> Result<T, Error>,
+        op: impl Fn(&mut Tree) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.with_root_mut_from(root, None, op)
+    }
+
+    /// Perform operations on a given `root`. If an `expected` revision is given, the operations are only committed if it
+    /// is still the latest revision of the root, and fail with a [Conflict] otherwise
+    fn with_root_mut_from<T>(
+        &self,
+        root: &str,
+        expected: Option<Object>,
+        op: impl Fn(&mut Tree) -> Result<T, Error>,
     ) -> Result<T, Error> {
         log::info!("mutating root '{root}'");
 
-        // we can only mutate the latest revision of the filesystem.
-        let mut node = self.get_root(root, Revision::FromLatest(0))?;
+        let _guard = self.collector.write();
 
-        // perform operation on node
-        let result = op(&mut node)?;
+        loop {
+            // we can only mutate the latest revision of the filesystem.
+            let (timestamp, latest) = self.get_revision(root, Revision::FromLatest(0))?;
 
-        // re-serialize and store new root
-        self.set_root(root, node)?;
+            if let Some(expected) = expected.filter(|expected| *expected != latest) {
+                return Err(Conflict {
+                    tree: root.to_owned(),
+                    expected,
+                    actual: latest,
+                }
+                .into());
+            }
 
-        Ok(result)
+            let mut tree = Tree::load(self, latest, timestamp)?;
+
+            // perform operation on tree
+            let result = op(&mut tree)?;
+
+            // store the changed directories, and the new root
+            let object = tree.store()?;
+
+            match self.set_root(root, latest, object) {
+                Ok(()) => return Ok(result),
+                Err(Error::Conflict { .. }) => {
+                    log::info!("root '{root}' changed while it was being mutated, retrying");
+                }
+                Err(e) => return Err(e),
+            }
+        }
     }
 
     fn with_root<T>(
         &self,
         root: &str,
         revision: Revision,
-        op: impl Fn(&Node) -> Result<T, Error>,
+        op: impl Fn(&mut Tree) -> Result<T, Error>,
     ) -> Result<T, Error> {
         log::info!("accessing root '{root}'");
 
-        let node = self.get_root(root, revision)?;
+        let mut tree = self.get_root(root, revision)?;
 
-        // perform operation on node
-        let result = op(&node)?;
+        // perform operation on tree
+        let result = op(&mut tree)?;
 
         Ok(result)
     }
 
     /// Create a new [Object] containing `data`, referenced by it's hash
-    fn create_object(&self, data: impl AsRef<[u8]>) -> sled::Result<Object> {
+    fn create_object(&self, data: impl AsRef<[u8]>) -> Result<Object, Error> {
         // generate a hash of data
-        let mut hasher = sha2::Sha256::new();
-        hasher.update(data.as_ref());
-        let hash = hasher.finalize();
+        let object = Object::of(data.as_ref());
+
+        self.store_object(object, data.as_ref())?;
+
+        Ok(object)
+    }
+
+    /// Create a new [Object] containing `data`, which is kept in a [gc::Pending] set until it is linked, rather than
+    /// being protected by a [gc::WriteGuard]
+    fn create_pending_object(
+        &self,
+        pending: &mut gc::Pending,
+        data: impl AsRef<[u8]>,
+    ) -> Result<Object, Error> {
+        let object = Object::of(data.as_ref());
+
+        // the object must be pending before we look for it, or it could be swept after we find it
+        pending.add(object);
+        self.store_object(object, data.as_ref())?;
+
+        Ok(object)
+    }
+
+    /// Store the `data` of an `object`, unless it is already stored
+    fn store_object(&self, object: Object, data: &[u8]) -> Result<(), Error> {
+        // if there is no object with a given hash, then compress and encrypt data and store it in objects store
+        if !self.objects.contains(&object)? {
+            let data = compress::encode(&self.compression, data)?;
+            self.objects.insert(&object, self.keys.seal(data))?;
+        }
+
+        Ok(())
+    }
+
+    /// Split the contents of a file into chunks, and store them along with a [chunk::Manifest] listing them. Returns
+    /// the [Object] of the manifest
+    fn write_file(&self, data: &[u8]) -> Result<Object, Error> {
+        let mut writer = self.file_writer();
+
+        writer.write(self, data)?;
+        writer.finish(self)
+    }
+
+    /// Read the contents of a file, re-assembling it from it's chunks
+    fn read_file(&self, object: &Object) -> Result<Vec<u8>, Error> {
+        self.read_manifest(&self.manifest(object)?)
+    }
+
+    /// Read the contents of a file described by a given [chunk::Manifest]
+    fn read_manifest(&self, manifest: &chunk::Manifest) -> Result<Vec<u8>, Error> {
+        let mut file = Vec::with_capacity(manifest.size() as usize);
 
-        // if there is no object with a given hash, then store data in objects store
-        if self.objects.get(hash)?.is_none() {
-            self.objects.insert(hash, data.as_ref())?;
+        for chunk in manifest.chunks() {
+            file.extend_from_slice(&self.load(chunk)?);
         }
 
-        Ok(Object::from_hash(hash.try_into().unwrap()))
+        Ok(file)
     }
 
-    /// Serialize `value`, and store it as an [Object]
-    fn serialize<T: Serialize>(&self, value: &T) -> Result<Object, Error> {
-        let data = bincode::serialize(value)?;
+    /// Load an [Object] from the database, decrypting and decompressing it
+    fn load(&self, object: &Object) -> Result<Vec<u8>, Error> {
+        let data = self
+            .objects
+            .get(object)?
+            .ok_or(Error::MissingObject { object: *object })?;
 
-        Ok(self.create_object(&data)?)
+        self.decode_object(&data)
     }
 
-    /// Load an [Object] from the database
-    fn load(&self, object: &Object) -> sled::Result<sled::IVec> {
-        self.objects.get(&object.hash()).map(|obj| obj.unwrap())
+    /// Decode an [Object]'s data, as stored in the database, back to it's original contents
+    fn decode_object(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(compress::decode(&self.keys.open(data)?)?)
     }
 
-    /// Load an [Object] and deserialize it
-    fn deserialize<T: DeserializeOwned>(&self, object: &Object) -> Result<T, Error> {
-        let data = self.load(object)?.to_vec();
-        let value = bincode::deserialize(&data)?;
-        Ok(value)
+    /// Load an [Object] that may have been stored without headers by an older version of `ksync`. Only for migrations
+    /// that run before [Files::migrate_headers]
+    fn load_legacy(&self, object: &Object) -> Result<Vec<u8>, Error> {
+        let data = self
+            .objects
+            .get(object)?
+            .ok_or(Error::MissingObject { object: *object })?;
+
+        let data = self.keys.open_legacy(&data)?;
+
+        Ok(compress::decode(&compress::add_header(data))?)
     }
 
     fn set_key(&self, path: Path, key: crypto::Key) -> Result<(), Error> {
-        let object = self.serialize(&key)?;
-
-        self.with_root_mut("keyring", |node| {
-            node.insert(path, object)?;
+        self.with_root_mut(KEYRING, |tree| {
+            let object = self.write_file(&bincode::serialize(&key)?)?;
+            tree.insert(path, object)?;
 
             Ok(())
         })?;
@@ -480,20 +855,38 @@ impl Files {
     }
 
     fn get_key(&self, path: Path) -> Result<crypto::Key, Error> {
-        let object = self.with_root("keyring", Revision::FromLatest(0), |node| {
-            let node = node.traverse(path)?.and_then(|node| node.file());
+        let object = self.with_root(KEYRING, Revision::FromLatest(0), |tree| {
+            let node = tree.traverse(path)?.and_then(|node| node.file());
 
             Ok(node.cloned())
         })?;
 
         if let Some(object) = object {
-            let key = self.deserialize(&object)?;
+            let key = bincode::deserialize(&self.read_file(&object)?)?;
 
             Ok(key)
         } else {
             Err(io::Error::new(io::ErrorKind::NotFound, format!("key '{path}' not found")).into())
         }
     }
+
+    /// Check that `name` is a valid name for a filesystem tree; made up of ASCII letters, digits, `-`, `_` and `.`, and
+    /// not the name of the keyring
+    fn check_tree_name(name: &str) -> Result<(), Error> {
+        let valid = !name.is_empty()
+            && name != KEYRING
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+
+        if valid {
+            Ok(())
+        } else {
+            Err(Error::InvalidTreeName {
+                name: name.to_owned(),
+            })
+        }
+    }
 }
 
 // public helpers
@@ -501,26 +894,146 @@ impl Files {
     /// Clears the files database
     pub fn clear(&self) -> Result<(), Error> {
         log::info!("clearing database");
+
+        let _guard = self.collector.write();
+
         self.objects.clear()?;
         self.roots.clear()?;
+        self.tags.clear()?;
 
         // create a new root node
-        let dir = Node::new_dir();
-        let object = self.serialize(&dir)?;
-        self.roots.merge("fs", object.hash())?;
+        let object = self.store_empty_dir()?;
+        self.roots.merge(DEFAULT_TREE, object.hash())?;
+
+        Ok(())
+    }
+
+    /// Create a new, empty filesystem tree called `name`
+    pub fn create_tree(&self, name: &str) -> Result<(), Error> {
+        Files::check_tree_name(name)?;
+
+        log::info!("creating tree '{name}'");
+
+        let _guard = self.collector.write();
+
+        let object = self.store_empty_dir()?;
+        let timestamp = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_nanos();
+        let history: RootHistory = vec![(timestamp, object)];
+
+        self.roots
+            .compare_and_swap(
+                name,
+                None as Option<&[u8]>,
+                Some(self.encode_history(&history)?),
+            )?
+            .map_err(|_| Error::TreeExists {
+                name: name.to_owned(),
+            })?;
 
         Ok(())
     }
 
-    pub fn insert(&self, path: Path, data: &[u8]) -> Result<(), Error> {
-        log::debug!("inserting to file {path}");
+    /// List every filesystem tree, in order of their names
+    pub fn list_trees(&self) -> Result<Vec<TreeInfo>, Error> {
+        let mut trees = vec![];
+
+        for entry in self.roots.iter() {
+            let (name, history) = entry?;
+            let name = String::from_utf8_lossy(&name).into_owned();
+
+            if name == KEYRING {
+                continue;
+            }
+
+            let history = self.decode_history(&history)?;
+
+            let Some(&(modified, latest)) = history.last() else {
+                continue;
+            };
+
+            trees.push(TreeInfo {
+                name,
+                revisions: history.len(),
+                modified,
+                latest,
+            });
+        }
+
+        Ok(trees)
+    }
+
+    /// Rename the filesystem tree `from` to `to`, along with it's history and tags
+    pub fn rename_tree(&self, from: &str, to: &str) -> Result<(), Error> {
+        Files::check_tree_name(from)?;
+        Files::check_tree_name(to)?;
+
+        log::info!("renaming tree '{from}' to '{to}'");
+
+        (&self.roots, &self.tags)
+            .transaction(|(roots, tags)| {
+                use sled::transaction::ConflictableTransactionError::Abort;
+
+                let history = roots.get(from)?.ok_or(Abort(Error::TreeNotFound {
+                    name: from.to_owned(),
+                }))?;
+
+                if roots.get(to)?.is_some() {
+                    return Err(Abort(Error::TreeExists {
+                        name: to.to_owned(),
+                    }));
+                }
+
+                roots.remove(from)?;
+                roots.insert(to, history)?;
+
+                if let Some(tagged) = tags.remove(from)? {
+                    tags.insert(to, tagged)?;
+                }
+
+                Ok(())
+            })
+            .map_err(Error::from)
+    }
+
+    /// Delete the filesystem tree `name`, along with it's history and tags. The objects it referenced are removed by
+    /// the next garbage collection
+    pub fn delete_tree(&self, name: &str) -> Result<(), Error> {
+        Files::check_tree_name(name)?;
+
+        log::info!("deleting tree '{name}'");
+
+        (&self.roots, &self.tags)
+            .transaction(|(roots, tags)| {
+                use sled::transaction::ConflictableTransactionError::Abort;
+
+                roots.remove(name)?.ok_or(Abort(Error::TreeNotFound {
+                    name: name.to_owned(),
+                }))?;
+                tags.remove(name)?;
+
+                Ok(())
+            })
+            .map_err(Error::from)
+    }
+
+    /// Link a file stored with a [chunk::FileWriter] to a given `path` in a `tree`, creating any missing parent
+    /// directories. If an `expected` revision is given, the file is only linked if it is still the latest revision
+    pub fn insert_object(
+        &self,
+        tree: &str,
+        path: Path,
+        object: Object,
+        expected: Option<Object>,
+    ) -> Result<(), Error> {
+        Files::check_tree_name(tree)?;
+
+        log::debug!("inserting to file {path} in tree '{tree}'");
 
-        let object = self.create_object(data)?;
         let (parent, _) = path.parent_child();
 
-        self.with_root_mut("fs", |node| {
-            node.make_dir_recursive(parent)?;
-            node.insert(path, object)?;
+        self.with_root_mut_from(tree, expected, |tree| {
+            tree.make_dir_recursive(parent)?;
+            tree.insert(path, object)?;
             Ok(())
         })?;
 
@@ -529,11 +1042,41 @@ impl Files {
         Ok(())
     }
 
-    pub fn get(&self, path: Path, revision: Revision) -> Result<Option<IVec>, Error> {
-        log::debug!("retrieving file {path}");
+    /// Link a file that is already stored to a given `path` in a `tree`, by the [Object] of it's [chunk::Manifest],
+    /// so that it's contents don't have to be sent again. Returns `false`, changing nothing, if the whole file is not
+    /// stored. As with [Files::insert_object], an `expected` revision may be given
+    pub fn link_file(
+        &self,
+        tree: &str,
+        path: Path,
+        object: Object,
+        expected: Option<Object>,
+    ) -> Result<bool, Error> {
+        // the file must not be collected between checking for it and linking it
+        let _guard = self.collector.write();
+
+        if !self.has_file(&object)? {
+            return Ok(false);
+        }
+
+        self.insert_object(tree, path, object, expected)?;
+
+        Ok(true)
+    }
 
-        let object = self.with_root("fs", revision, |node| {
-            let node = node.traverse(path)?;
+    /// Get the [chunk::Manifest] of the file at a given `path` in a `tree`, so that it can be read one chunk at a time
+    pub fn get_manifest(
+        &self,
+        tree: &str,
+        path: Path,
+        revision: Revision,
+    ) -> Result<Option<chunk::Manifest>, Error> {
+        Files::check_tree_name(tree)?;
+
+        log::debug!("retrieving file {path} from tree '{tree}'");
+
+        let object = self.with_root(tree, revision, |tree| {
+            let node = tree.traverse(path)?;
             let object = node.and_then(|node| node.file());
 
             Ok(object.cloned())
@@ -542,18 +1085,22 @@ impl Files {
         if let Some(object) = object {
             log::debug!("got object {} for file '{path}'", object.hex());
 
-            Ok(Some(self.load(&object)?))
+            Ok(Some(self.manifest(&object)?))
         } else {
             log::error!("file '{path}' not found");
             Ok(None)
         }
     }
 
-    pub fn delete(&self, path: Path) -> Result<(), Error> {
-        log::debug!("deleting file '{path}'");
+    /// Delete the node at a given `path` in a `tree`. If an `expected` revision is given, the node is only deleted if
+    /// it is still the latest revision
+    pub fn delete(&self, tree: &str, path: Path, expected: Option<Object>) -> Result<(), Error> {
+        Files::check_tree_name(tree)?;
+
+        log::debug!("deleting file '{path}' from tree '{tree}'");
 
-        self.with_root_mut("fs", |node| {
-            node.delete(path)?;
+        self.with_root_mut_from(tree, expected, |tree| {
+            tree.delete(path)?;
 
             Ok(())
         })?;
@@ -561,20 +1108,88 @@ impl Files {
         Ok(())
     }
 
-    pub fn rollback(&self, revision: Revision) -> Result<(), Error> {
-        // the node to roll back to
-        let old_root = self.get_root("fs", revision)?;
+    /// Move the file or directory at `from` to `to` in a `tree` as a single revision, relinking it's existing contents
+    /// rather than copying them. The parent of `to` must already exist, and nothing may exist at `to`. If an `expected`
+    /// revision is given, the node is only moved if it is still the latest revision
+    pub fn rename(
+        &self,
+        tree: &str,
+        from: Path,
+        to: Path,
+        expected: Option<Object>,
+    ) -> Result<(), Error> {
+        Files::check_tree_name(tree)?;
 
-        self.set_root("fs", old_root)?;
+        log::debug!("moving '{from}' to '{to}' in tree '{tree}'");
 
-        Ok(())
+        self.with_root_mut_from(tree, expected, |tree| tree.rename(from, to))
+    }
+
+    /// Copy the file or directory at `from` in a given `revision` of a `tree` to `to` in it's latest revision, as a single
+    /// revision. Only the directories on the way to `to` are written; the copy shares everything else with `from`. The
+    /// parent of `to` must already exist, and nothing may exist at `to`. If an `expected` revision is given, the node is
+    /// only copied if it is still the latest revision
+    pub fn copy(
+        &self,
+        tree: &str,
+        from: Path,
+        revision: Revision,
+        to: Path,
+        expected: Option<Object>,
+    ) -> Result<(), Error> {
+        Files::check_tree_name(tree)?;
+
+        log::debug!("copying '{from}' @ {revision:?} to '{to}' in tree '{tree}'");
+
+        // the copy isn't linked until the latest revision is changed, and may come from a revision that is being pruned
+        let _guard = self.collector.write();
+
+        let node = self.with_root(tree, revision, |tree| {
+            let node = tree.traverse(from)?.filter(|node| node.data().is_some());
+
+            Ok(node.cloned())
+        })?;
+
+        let node = node.ok_or_else(|| Error::NotFound {
+            path: from.as_str().to_owned(),
+        })?;
+
+        self.with_root_mut_from(tree, expected, |tree| tree.link(to, node.clone()))
+    }
+
+    pub fn rollback(&self, tree: &str, revision: Revision) -> Result<(), Error> {
+        Files::check_tree_name(tree)?;
+
+        let _guard = self.collector.write();
+
+        loop {
+            // the tree to roll back to; it's directories are already stored, so it only needs to be appended to the
+            // history
+            let (_, old_root) = self.get_revision(tree, revision)?;
+            let (_, latest) = self.get_revision(tree, Revision::FromLatest(0))?;
+
+            match self.set_root(tree, latest, old_root) {
+                Ok(()) => return Ok(()),
+                Err(Error::Conflict { .. }) => {
+                    log::info!("tree '{tree}' changed while it was being rolled back, retrying");
+                }
+                Err(e) => return Err(e),
+            }
+        }
     }
 
-    pub fn get_node(&self, path: Path, revision: Revision) -> Result<Option<Node>, Error> {
-        log::debug!("retrieving node '{path}'");
+    pub fn get_node(
+        &self,
+        tree: &str,
+        path: Path,
+        revision: Revision,
+    ) -> Result<Option<Node>, Error> {
+        Files::check_tree_name(tree)?;
+
+        log::debug!("retrieving node '{path}' from tree '{tree}'");
 
-        let node = self.with_root("fs", revision, |node| {
-            let node = node.traverse(path)?;
+        let node = self.with_root(tree, revision, |tree| {
+            let node = tree.traverse_all(path)?;
 
             Ok(node.cloned())
         })?;
@@ -582,8 +1197,10 @@ impl Files {
         Ok(node)
     }
 
-    pub fn get_history(&self) -> Result<RootHistory, Error> {
-        self.get_root_history("fs")
+    pub fn get_history(&self, tree: &str) -> Result<RootHistory, Error> {
+        Files::check_tree_name(tree)?;
+
+        self.get_root_history(tree)
     }
 
     pub fn set_admin_key(&self, key: crypto::Key) -> Result<(), Error> {
@@ -643,6 +1260,13 @@ impl Files {
         Ok(())
     }
 
+    /// Returns whether `key` is the admin key, which may run administrative operations on a live server
+    pub fn verify_admin(&self, key: &crypto::Key) -> Result<bool, Error> {
+        let admin_key = self.get_admin_key()?;
+
+        Ok(admin_key.pub_key().raw() == key.pub_key().raw())
+    }
+
     pub fn verify_client(&self, key: &crypto::Key) -> Result<bool, Error> {
         let server_key = self.get_server_key()?;
 
@@ -654,3 +1278,401 @@ impl Files {
         Ok(is_verified && is_equal)
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A [Files] database that is held in memory, and is gone once it is dropped
+    pub struct TempFiles {
+        files: Files,
+    }
+
+    impl std::ops::Deref for TempFiles {
+        type Target = Files;
+
+        fn deref(&self) -> &Files {
+            &self.files
+        }
+    }
+
+    impl Files {
+        /// Store `data` as the file at a given `path` in the default tree all at once
+        pub fn insert(&self, path: Path, data: &[u8]) -> Result<(), Error> {
+            // the file is written inside of the guard, so it can't be collected before it is linked
+            let _guard = self.collector.write();
+            let object = self.write_file(data)?;
+
+            self.insert_object(DEFAULT_TREE, path, object, None)
+        }
+
+        /// Read the whole file at a given `path` in the default tree
+        pub fn get(&self, path: Path, revision: Revision) -> Result<Option<Vec<u8>>, Error> {
+            self.get_manifest(DEFAULT_TREE, path, revision)?
+                .map(|manifest| self.read_manifest(&manifest))
+                .transpose()
+        }
+    }
+
+    /// Open an empty database for a test
+    pub fn open() -> TempFiles {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let objects = Box::<store::MemoryStore>::default();
+        let files =
+            Files::with_objects(db, objects, Compression::default(), Default::default()).unwrap();
+
+        TempFiles { files }
+    }
+
+    /// Open the database at `path` with the `keys` given by a function, as [Files::open] does. Sled releases it's lock
+    /// on a database in the background once it is closed, so this waits for the lock if it was only just closed
+    pub fn reopen(
+        path: &SysPath,
+        storage: &Storage,
+        keys: impl Fn() -> at_rest::Keys,
+    ) -> Result<Files, Error> {
+        for _ in 0..100 {
+            match Files::open(path, storage, Compression::default(), keys()) {
+                Err(Error::Database {
+                    error: sled::Error::Io(e),
+                }) if e.to_string().contains("could not acquire lock") => {
+                    std::thread::sleep(std::time::Duration::from_millis(10))
+                }
+                result => return result,
+            }
+        }
+
+        Files::open(path, storage, Compression::default(), keys())
+    }
+
+    /// Some data that doesn't compress, and is the same for a given `seed`
+    pub fn data(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9e3779b97f4a7c15) | 1;
+
+        (0..len)
+            .map(|_| {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn trees_are_independent() {
+        let files = open();
+        let path = Path::new("/file").unwrap();
+
+        files.create_tree("photos").unwrap();
+
+        let object = files.write_file(b"photo").unwrap();
+        files.insert_object("photos", path, object, None).unwrap();
+        files.insert(path, b"default").unwrap();
+
+        assert_eq!(files.get_history("photos").unwrap().len(), 2);
+        assert_eq!(
+            files.get(path, Revision::FromLatest(0)).unwrap().unwrap(),
+            b"default"
+        );
+
+        let manifest = files
+            .get_manifest("photos", path, Revision::FromLatest(0))
+            .unwrap()
+            .unwrap();
+        assert_eq!(files.read_manifest(&manifest).unwrap(), b"photo");
+
+        files.delete("photos", path, None).unwrap();
+        assert!(files.get(path, Revision::FromLatest(0)).unwrap().is_some());
+
+        let names: Vec<_> = files
+            .list_trees()
+            .unwrap()
+            .into_iter()
+            .map(|tree| tree.name)
+            .collect();
+        assert_eq!(names, ["fs", "photos"]);
+    }
+
+    #[test]
+    fn trees_are_renamed_and_deleted() {
+        let files = open();
+        let path = Path::new("/file").unwrap();
+
+        files.create_tree("a").unwrap();
+        files.create_tree("b").unwrap();
+        assert!(matches!(
+            files.create_tree("a"),
+            Err(Error::TreeExists { .. })
+        ));
+
+        let object = files.write_file(b"data").unwrap();
+        files.insert_object("a", path, object, None).unwrap();
+
+        assert!(matches!(
+            files.rename_tree("a", "b"),
+            Err(Error::TreeExists { .. })
+        ));
+        files.delete_tree("b").unwrap();
+        files.rename_tree("a", "b").unwrap();
+
+        // the history moves along with the tree
+        assert_eq!(files.get_history("b").unwrap().len(), 2);
+        assert!(matches!(
+            files.get_history("a"),
+            Err(Error::TreeNotFound { .. })
+        ));
+
+        files.delete_tree("b").unwrap();
+        assert!(matches!(
+            files.delete_tree("b"),
+            Err(Error::TreeNotFound { .. })
+        ));
+        assert!(files.insert_object("b", path, object, None).is_err());
+    }
+
+    #[test]
+    fn keyring_is_not_a_tree() {
+        let files = open();
+
+        for name in ["keyring", "", "a/b", "a b"] {
+            assert!(matches!(
+                files.get_history(name),
+                Err(Error::InvalidTreeName { .. })
+            ));
+        }
+
+        assert!(matches!(
+            files.delete_tree("keyring"),
+            Err(Error::InvalidTreeName { .. })
+        ));
+        assert!(files
+            .list_trees()
+            .unwrap()
+            .iter()
+            .all(|tree| tree.name != "keyring"));
+    }
+
+    #[test]
+    fn concurrent_commits_are_not_lost() {
+        let files = open();
+        let history = files.get_history(DEFAULT_TREE).unwrap().len();
+
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let files = &files;
+
+                scope.spawn(move || {
+                    for i in 0..10 {
+                        let path = format!("/{thread}/{i}");
+                        files.insert(Path::new(&path).unwrap(), b"data").unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(files.get_history(DEFAULT_TREE).unwrap().len(), history + 40);
+
+        for thread in 0..4 {
+            for i in 0..10 {
+                let path = format!("/{thread}/{i}");
+                let data = files.get(Path::new(&path).unwrap(), Revision::FromLatest(0));
+
+                assert_eq!(data.unwrap().unwrap(), b"data");
+            }
+        }
+    }
+
+    #[test]
+    fn stale_commits_conflict() {
+        let files = open();
+        let path = Path::new("/file").unwrap();
+        let object = files.write_file(b"file").unwrap();
+
+        let latest = |files: &Files| files.get_history(DEFAULT_TREE).unwrap().last().unwrap().1;
+        let parent = latest(&files);
+
+        files
+            .insert_object(DEFAULT_TREE, path, object, Some(parent))
+            .unwrap();
+
+        let current = latest(&files);
+
+        // both commits were made to the revision before the insert
+        let insert = files.insert_object(DEFAULT_TREE, path, object, Some(parent));
+        let delete = files.delete(DEFAULT_TREE, path, Some(parent));
+
+        for result in [insert, delete] {
+            match result {
+                Err(Error::Conflict { conflict }) => {
+                    assert_eq!(conflict.expected, parent);
+                    assert_eq!(conflict.actual, current);
+                }
+                result => panic!("expected a conflict, got {result:?}"),
+            }
+        }
+
+        assert_eq!(latest(&files), current);
+
+        // hashes can be given as hex, e.g. on the command line
+        let hex = current.hex().to_string();
+        assert_eq!(hex.parse::<Object>().unwrap(), current);
+        assert!(hex[1..].parse::<Object>().is_err());
+
+        files.delete(DEFAULT_TREE, path, Some(current)).unwrap();
+        assert!(files.get(path, Revision::FromLatest(0)).unwrap().is_none());
+    }
+
+    #[test]
+    fn moves_relink_existing_contents() {
+        let files = open();
+
+        files
+            .insert(Path::new("/a/b/file").unwrap(), b"file")
+            .unwrap();
+        files.insert(Path::new("/c").unwrap(), b"c").unwrap();
+        files.insert(Path::new("/d/x").unwrap(), b"x").unwrap();
+
+        let node = |files: &Files, path| {
+            files
+                .get_node(
+                    DEFAULT_TREE,
+                    Path::new(path).unwrap(),
+                    Revision::FromLatest(0),
+                )
+                .unwrap()
+                .filter(|node| node.data().is_some())
+        };
+
+        let file = node(&files, "/a/b/file").unwrap().file().copied();
+        let history = files.get_history(DEFAULT_TREE).unwrap().len();
+
+        // rename a file in place, and move a whole directory
+        files
+            .rename(
+                DEFAULT_TREE,
+                Path::new("/c").unwrap(),
+                Path::new("/e").unwrap(),
+                None,
+            )
+            .unwrap();
+        files
+            .rename(
+                DEFAULT_TREE,
+                Path::new("/a/b").unwrap(),
+                Path::new("/d/b").unwrap(),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(files.get_history(DEFAULT_TREE).unwrap().len(), history + 2);
+        assert_eq!(node(&files, "/d/b/file").unwrap().file().copied(), file);
+        assert_eq!(
+            files
+                .get(Path::new("/e").unwrap(), Revision::FromLatest(0))
+                .unwrap()
+                .unwrap(),
+            b"c"
+        );
+        assert!(node(&files, "/a/b").is_none());
+        assert!(node(&files, "/c").is_none());
+
+        // the destination's parent must exist, and nothing may be at the destination
+        assert!(matches!(
+            files.rename(
+                DEFAULT_TREE,
+                Path::new("/e").unwrap(),
+                Path::new("/f/e").unwrap(),
+                None
+            ),
+            Err(Error::NotFound { .. })
+        ));
+        assert!(matches!(
+            files.rename(
+                DEFAULT_TREE,
+                Path::new("/e").unwrap(),
+                Path::new("/d/b").unwrap(),
+                None
+            ),
+            Err(Error::AlreadyExists { .. })
+        ));
+        assert!(matches!(
+            files.rename(
+                DEFAULT_TREE,
+                Path::new("/gone").unwrap(),
+                Path::new("/f").unwrap(),
+                None
+            ),
+            Err(Error::NotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn copies_share_contents() {
+        let files = open();
+        let big = data(7, 300 * 1024);
+
+        files
+            .insert(Path::new("/config/big").unwrap(), &big)
+            .unwrap();
+        files
+            .insert(Path::new("/config/small").unwrap(), b"v1")
+            .unwrap();
+
+        let objects = files.objects.objects().count();
+        let history = files.get_history(DEFAULT_TREE).unwrap().len();
+
+        files
+            .copy(
+                DEFAULT_TREE,
+                Path::new("/config").unwrap(),
+                Revision::FromLatest(0),
+                Path::new("/release-1").unwrap(),
+                None,
+            )
+            .unwrap();
+
+        // only the root directory is rewritten; the copy is stored as the same object as the original
+        assert_eq!(files.get_history(DEFAULT_TREE).unwrap().len(), history + 1);
+        assert!(files.objects.objects().count() <= objects + 1);
+
+        // copy an older revision of the directory, from before it was changed
+        files
+            .insert(Path::new("/config/small").unwrap(), b"v2")
+            .unwrap();
+        files
+            .copy(
+                DEFAULT_TREE,
+                Path::new("/config").unwrap(),
+                Revision::FromLatest(2),
+                Path::new("/release-2").unwrap(),
+                None,
+            )
+            .unwrap();
+
+        let get = |path| {
+            files
+                .get(Path::new(path).unwrap(), Revision::FromLatest(0))
+                .unwrap()
+                .unwrap()
+        };
+
+        assert_eq!(get("/release-1/big"), big);
+        assert_eq!(get("/release-1/small"), b"v1");
+        assert_eq!(get("/release-2/small"), b"v1");
+        assert_eq!(get("/config/small"), b"v2");
+
+        assert!(matches!(
+            files.copy(
+                DEFAULT_TREE,
+                Path::new("/config").unwrap(),
+                Revision::FromLatest(0),
+                Path::new("/release-1").unwrap(),
+                None,
+            ),
+            Err(Error::AlreadyExists { .. })
+        ));
+    }
+}
@@ -1,16 +1,21 @@
 use serde::{Deserialize, Serialize};
 
-use std::collections::HashMap;
-use std::io;
+use std::collections::{BTreeMap, HashMap};
 use std::time::SystemTime;
 
 use crate::files::{Error, Object, Path};
 
 /// A [Node] represents a filesystem tree
+///
+/// A directory's children are kept in order of their names, so that identical trees always serialize to the same bytes,
+/// and are stored as the same [Object]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum NodeData {
-    Dir(HashMap<String, Node>),
+    Dir(BTreeMap<String, Node>),
     File(Object),
+    /// A directory that has not yet been loaded from the [Object] it is stored as. Only used on the server, as nodes are
+    /// fully loaded before they are sent to a client
+    Stored(Object),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -27,6 +32,11 @@ impl Node {
         }
     }
 
+    /// Create a [Node] with a given `timestamp`, such as when it is loaded from the database
+    pub fn from_parts(data: Option<NodeData>, timestamp: u128) -> Node {
+        Node { data, timestamp }
+    }
+
     pub fn data_mut(&mut self) -> &mut Option<NodeData> {
         &mut self.data
     }
@@ -37,7 +47,7 @@ impl Node {
 
     /// Create a new empty [Node::Dir]
     pub fn new_dir() -> Node {
-        Node::new(NodeData::Dir(HashMap::new()))
+        Node::new(NodeData::Dir(BTreeMap::new()))
     }
 
     /// Create a new [Node::File] referencing a given [Object]
@@ -46,7 +56,7 @@ impl Node {
     }
 
     /// Returns `Some(map)` if `self` is [Node::Dir]
-    pub fn dir_mut(&mut self) -> Option<&mut HashMap<String, Node>> {
+    pub fn dir_mut(&mut self) -> Option<&mut BTreeMap<String, Node>> {
         if let Some(NodeData::Dir(map)) = &mut self.data {
             Some(map)
         } else {
@@ -54,7 +64,7 @@ impl Node {
         }
     }
 
-    pub fn dir(&self) -> Option<&HashMap<String, Node>> {
+    pub fn dir(&self) -> Option<&BTreeMap<String, Node>> {
         if let Some(NodeData::Dir(map)) = &self.data {
             Some(map)
         } else {
@@ -62,7 +72,7 @@ impl Node {
         }
     }
 
-    /// Returns `Some(object)` if `self` is [Node::Dir]
+    // /// Returns `Some(object)` if `self` is [Node::Dir]
     // pub fn file_mut(&mut self) -> Option<&mut Object> {
     //     if let Some(NodeData::File(object)) = &mut self.data {
     //         Some(object)
@@ -91,15 +101,6 @@ impl Node {
         self.timestamp
     }
 
-    /// Checks to see if a node contains a given child `name`
-    pub fn has_child(&mut self, name: &str) -> Result<bool, Error> {
-        if let Some(map) = self.dir_mut() {
-            Ok(map.contains_key(&name.to_string()))
-        } else {
-            Err(Error::NotADirectory)
-        }
-    }
-
     /// Returns a mutable reference to a given child. Will error if `self` is not a directory
     pub fn get_child_mut(&mut self, name: &str) -> Result<Option<&mut Node>, Error> {
         if let Some(map) = self.dir_mut() {
@@ -134,7 +135,12 @@ impl Node {
             let mut current = self;
 
             for part in path.parts() {
-                current = if let Some(node) = current.get_child_mut(&part)? {
+                // nothing is below a deleted node
+                if current.data().is_none() {
+                    return Ok(None);
+                }
+
+                current = if let Some(node) = current.get_child_mut(part)? {
                     node
                 } else {
                     return Ok(None);
@@ -152,7 +158,12 @@ impl Node {
             let mut current = self;
 
             for part in path.parts() {
-                current = if let Some(node) = current.get_child(&part)? {
+                // nothing is below a deleted node
+                if current.data().is_none() {
+                    return Ok(None);
+                }
+
+                current = if let Some(node) = current.get_child(part)? {
                     node
                 } else {
                     return Ok(None);
@@ -180,7 +191,11 @@ impl Node {
                 path: path.as_str().to_owned(),
             })?;
 
-            if !node.has_child(name)? {
+            // a deleted directory is made again in place of it's tombstone
+            if !node
+                .get_child(name)?
+                .is_some_and(|child| child.data().is_some())
+            {
                 node.insert_child(name, Node::new_dir())?;
             }
         }
@@ -221,12 +236,89 @@ impl Node {
         }
     }
 
+    /// Delete the [Node] at a given [Path], leaving a tombstone in it's place that records when it was deleted
     pub fn delete(&mut self, path: Path) -> Result<(), Error> {
         let node = self.traverse_mut(path)?.ok_or(Error::NotFound {
             path: path.as_str().to_owned(),
         })?;
 
-        *node.data_mut() = None;
+        // deleting a tombstone again keeps the time it was first deleted
+        if node.data_mut().take().is_some() {
+            node.timestamp = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_nanos();
+        }
+
+        Ok(())
+    }
+
+    /// Remove every tombstone below `self` that was deleted before a given `time`, returning the number removed. As
+    /// with [Node::iter], nothing below a tombstone is visited; a deleted directory keeps none of it's children
+    pub fn purge(&mut self, time: u128) -> usize {
+        let Some(map) = self.dir_mut() else {
+            return 0;
+        };
+
+        let before = map.len();
+        map.retain(|_, child| child.data.is_some() || child.timestamp >= time);
+
+        let purged = before - map.len();
+
+        purged
+            + map
+                .values_mut()
+                .filter(|child| child.data.is_some())
+                .map(|child| child.purge(time))
+                .sum::<usize>()
+    }
+
+    /// Link `node` at `to`, as though it was just created. The parent of `to` must already exist, and nothing may exist
+    /// at `to`
+    pub fn link(&mut self, to: Path, mut node: Node) -> Result<(), Error> {
+        let (to_parent, Some(to_name)) = to.parent_child() else {
+            return Err(Error::AlreadyExists {
+                path: to.as_str().to_owned(),
+            });
+        };
+
+        node.timestamp = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_nanos();
+
+        let parent = self
+            .traverse_mut(to_parent)?
+            .ok_or_else(|| Error::NotFound {
+                path: to_parent.as_str().to_owned(),
+            })?;
+
+        if parent
+            .get_child(to_name)?
+            .is_some_and(|node| node.data().is_some())
+        {
+            return Err(Error::AlreadyExists {
+                path: to.as_str().to_owned(),
+            });
+        }
+
+        parent.insert_child(to_name, node)
+    }
+
+    /// Move the [Node] at `from` to `to`, leaving `from` deleted. The parent of `to` must already exist, and nothing may
+    /// exist at `to`
+    pub fn rename(&mut self, from: Path, to: Path) -> Result<(), Error> {
+        // a directory can't be moved inside of itself
+        if from.as_str() == "/" || to.ancestors().chain([to]).any(|ancestor| ancestor == from) {
+            return Err(Error::InvalidMove {
+                from: from.as_str().to_owned(),
+                to: to.as_str().to_owned(),
+            });
+        }
+
+        let node = self
+            .traverse(from)?
+            .filter(|node| node.data().is_some())
+            .ok_or_else(|| Error::NotFound {
+                path: from.as_str().to_owned(),
+            })?;
+
+        self.link(to, node.clone())?;
+        self.delete(from)?;
 
         Ok(())
     }
@@ -242,7 +334,7 @@ impl Node {
         }
     }
 
-    pub fn iter(&self) -> NodeIter {
+    pub fn iter(&self) -> NodeIter<'_> {
         NodeIter {
             node_stack: vec![("".to_owned(), self)],
             output_stack: vec![],
@@ -301,7 +393,7 @@ pub struct FileList<'a> {
 }
 
 impl<'a> FileList<'a> {
-    pub fn as_map(self) -> HashMap<String, (Object, u128)> {
+    pub fn into_map(self) -> HashMap<String, (Object, u128)> {
         self.map(|(path, object, timestamp)| (path, (object, timestamp)))
             .collect()
     }
@@ -331,7 +423,8 @@ impl<'a> Iterator for FileList<'a> {
                             node.timestamp(),
                         )),
 
-                        None => continue,
+                        // deleted nodes are skipped, and stored nodes are never sent to a client
+                        Some(NodeData::Stored(_)) | None => continue,
                     }
                 }
 
@@ -0,0 +1,372 @@
+use chrono::TimeZone;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashSet;
+use std::time::SystemTime;
+
+use crate::config::Retention;
+use crate::files::{Error, Files, Object, RootHistory, KEYRING};
+
+/// The outcome of pruning the history of every filesystem tree
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PruneReport {
+    /// Number of revisions kept
+    pub kept: usize,
+    /// Number of revisions dropped from history
+    pub removed: usize,
+}
+
+impl std::fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "removed {} revisions, kept {} revisions",
+            self.removed, self.kept
+        )
+    }
+}
+
+/// Format a timestamp so that revisions that fall in the same period produce the same string
+fn bucket(timestamp: u128, format: &str) -> String {
+    chrono::Local
+        .timestamp_nanos(timestamp as i64)
+        .format(format)
+        .to_string()
+}
+
+/// Determine which revisions in a `history` are kept under a given `policy`. Revisions stored as a `tagged` [Object]
+/// are always kept
+fn retain(
+    history: &RootHistory,
+    policy: &Retention,
+    now: u128,
+    tagged: &HashSet<Object>,
+) -> RootHistory {
+    let rules = [
+        policy.keep_last,
+        policy.keep_hourly,
+        policy.keep_daily,
+        policy.keep_weekly,
+        policy.keep_monthly,
+    ];
+
+    if rules.iter().all(Option::is_none) && policy.keep_within.is_none() {
+        // an empty policy keeps everything
+        return history.clone();
+    }
+
+    let mut keep: Vec<bool> = history
+        .iter()
+        .map(|(_, object)| tagged.contains(object))
+        .collect();
+
+    // the latest revision is always kept
+    if let Some(latest) = keep.last_mut() {
+        *latest = true;
+    }
+
+    // history is stored oldest-first, so walk it in reverse to see the newest revisions first
+    let newest_first = || history.iter().enumerate().rev();
+
+    if let Some(n) = policy.keep_last {
+        for (i, _) in newest_first().take(n) {
+            keep[i] = true;
+        }
+    }
+
+    if let Some(secs) = policy.keep_within {
+        let cutoff = now.saturating_sub(secs as u128 * 1_000_000_000);
+
+        for (i, _) in newest_first().take_while(|(_, (t, _))| *t >= cutoff) {
+            keep[i] = true;
+        }
+    }
+
+    let buckets = [
+        (policy.keep_hourly, "%Y-%m-%d %H"),
+        (policy.keep_daily, "%Y-%m-%d"),
+        (policy.keep_weekly, "%G-%V"),
+        (policy.keep_monthly, "%Y-%m"),
+    ];
+
+    for (n, format) in buckets {
+        let Some(n) = n else { continue };
+
+        let mut last = None;
+        let mut kept = 0;
+
+        for (i, (timestamp, _)) in newest_first() {
+            if kept == n {
+                break;
+            }
+
+            // keep the newest revision in each period
+            let bucket = bucket(*timestamp, format);
+
+            if last.as_ref() != Some(&bucket) {
+                keep[i] = true;
+                kept += 1;
+                last = Some(bucket);
+            }
+        }
+    }
+
+    history
+        .iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|(entry, _)| *entry)
+        .collect()
+}
+
+impl Files {
+    /// Drop revisions from the history of every filesystem tree that are not kept by a retention `policy`, or tagged.
+    /// The trees that are dropped can then be removed with [Files::collect_garbage]
+    pub fn prune(&self, policy: &Retention) -> Result<PruneReport, Error> {
+        let _guard = self.collector.write();
+
+        log::info!("pruning history with policy {policy:?}");
+
+        let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_nanos();
+        let mut report = PruneReport::default();
+
+        for entry in self.roots.iter() {
+            let (root, _) = entry?;
+
+            if &root[..] == KEYRING.as_bytes() {
+                // never drop keys
+                continue;
+            }
+
+            loop {
+                let Some(old) = self.roots.get(&root)? else {
+                    // root was removed while we were pruning
+                    break;
+                };
+
+                let history = self.decode_history(&old)?;
+                let tagged = self.tagged(&root)?;
+                let kept = retain(&history, policy, now, &tagged);
+                let removed = history.len() - kept.len();
+
+                let new = self.encode_history(&kept)?;
+
+                // only swap in the pruned history if no revisions were added in the meantime
+                if self
+                    .roots
+                    .compare_and_swap(&root, Some(old), Some(new))?
+                    .is_ok()
+                {
+                    log::info!(
+                        "pruned {removed} revisions from root '{}'",
+                        String::from_utf8_lossy(&root)
+                    );
+
+                    report.kept += kept.len();
+                    report.removed += removed;
+                    break;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use std::collections::HashSet;
+
+    use super::retain;
+    use crate::config::Retention;
+    use crate::files::{tests, Object, Path, Revision, RootHistory, DEFAULT_TREE, KEYRING};
+
+    /// A timestamp at a fixed time in March 2023. Revisions are placed around midday, a few minutes past the hour, so
+    /// that they fall in the same periods in any local timezone
+    fn at(day: u32, hour: u32, minute: u32) -> u128 {
+        in_month(3, day, hour, minute)
+    }
+
+    /// A timestamp at a fixed time in a given `month` of 2023
+    fn in_month(month: u32, day: u32, hour: u32, minute: u32) -> u128 {
+        chrono::Utc
+            .with_ymd_and_hms(2023, month, day, hour, minute, 0)
+            .unwrap()
+            .timestamp_nanos() as u128
+    }
+
+    /// Build a history from a list of timestamps, where each revision is identified by it's index
+    fn history(timestamps: &[u128]) -> RootHistory {
+        timestamps
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (*t, Object::from_hash([i as u8; 32])))
+            .collect()
+    }
+
+    /// The indices of the revisions kept from `history`
+    fn kept(history: &RootHistory, policy: &Retention, now: u128) -> Vec<u8> {
+        retain(history, policy, now, &HashSet::new())
+            .iter()
+            .map(|(_, object)| object.hash()[0])
+            .collect()
+    }
+
+    #[test]
+    fn empty_policy_keeps_everything() {
+        let history = history(&[at(1, 12, 1), at(2, 12, 1), at(3, 12, 1)]);
+
+        assert_eq!(
+            kept(&history, &Retention::default(), at(4, 12, 1)),
+            [0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn keep_last() {
+        let history = history(&[at(1, 12, 1), at(2, 12, 1), at(3, 12, 1), at(4, 12, 1)]);
+        let policy = Retention {
+            keep_last: Some(2),
+            ..Retention::default()
+        };
+
+        assert_eq!(kept(&history, &policy, at(5, 12, 1)), [2, 3]);
+    }
+
+    #[test]
+    fn keep_within() {
+        let history = history(&[at(1, 12, 1), at(2, 12, 1), at(3, 11, 1), at(3, 12, 1)]);
+        let policy = Retention {
+            keep_within: Some(2 * 60 * 60),
+            ..Retention::default()
+        };
+
+        assert_eq!(kept(&history, &policy, at(3, 13, 1)), [2, 3]);
+
+        // the latest revision is kept even once it falls outside of the window
+        assert_eq!(kept(&history, &policy, at(9, 12, 1)), [3]);
+    }
+
+    #[test]
+    fn keep_hourly() {
+        let history = history(&[
+            at(1, 12, 1),
+            at(1, 12, 2),
+            at(1, 13, 1),
+            at(1, 13, 2),
+            at(1, 14, 1),
+        ]);
+        let policy = Retention {
+            keep_hourly: Some(2),
+            ..Retention::default()
+        };
+
+        // the newest revision of each of the last two hours
+        assert_eq!(kept(&history, &policy, at(2, 12, 1)), [3, 4]);
+    }
+
+    #[test]
+    fn keep_daily() {
+        let history = history(&[
+            at(1, 12, 1),
+            at(1, 12, 2),
+            at(2, 12, 1),
+            at(3, 12, 1),
+            at(3, 12, 2),
+        ]);
+        let two = Retention {
+            keep_daily: Some(2),
+            ..Retention::default()
+        };
+        let three = Retention {
+            keep_daily: Some(3),
+            ..Retention::default()
+        };
+
+        assert_eq!(kept(&history, &two, at(4, 12, 1)), [2, 4]);
+        assert_eq!(kept(&history, &three, at(4, 12, 1)), [1, 2, 4]);
+    }
+
+    #[test]
+    fn keep_weekly_and_monthly() {
+        // the 13th and 20th of March 2023 are Mondays
+        let weeks = history(&[at(13, 12, 1), at(15, 12, 1), at(20, 12, 1), at(21, 12, 1)]);
+        let weekly = Retention {
+            keep_weekly: Some(2),
+            ..Retention::default()
+        };
+
+        assert_eq!(kept(&weeks, &weekly, at(22, 12, 1)), [1, 3]);
+
+        let months = history(&[
+            in_month(1, 10, 12, 1),
+            in_month(2, 10, 12, 1),
+            in_month(2, 20, 12, 1),
+            at(10, 12, 1),
+        ]);
+        let monthly = Retention {
+            keep_monthly: Some(2),
+            ..Retention::default()
+        };
+
+        assert_eq!(kept(&months, &monthly, at(22, 12, 1)), [2, 3]);
+    }
+
+    #[test]
+    fn rules_are_combined() {
+        let history = history(&[
+            at(1, 12, 1),
+            at(2, 12, 1),
+            at(2, 12, 2),
+            at(3, 12, 1),
+            at(3, 12, 2),
+        ]);
+        let policy = Retention {
+            keep_last: Some(2),
+            keep_daily: Some(2),
+            ..Retention::default()
+        };
+
+        // the last two revisions are both from the 3rd, so the daily rule adds the newest from the 2nd
+        assert_eq!(kept(&history, &policy, at(4, 12, 1)), [2, 3, 4]);
+    }
+
+    #[test]
+    fn prune_drops_revisions() {
+        let files = tests::open();
+        let path = Path::new("/a").unwrap();
+
+        files.insert(path, b"first").unwrap();
+        files.insert(path, b"second").unwrap();
+
+        let keys = files.get_root_history(KEYRING).unwrap().len();
+
+        let policy = Retention {
+            keep_last: Some(1),
+            ..Retention::default()
+        };
+
+        let report = files.prune(&policy).unwrap();
+        assert_eq!(report.removed, 2);
+
+        let history = files.get_history(DEFAULT_TREE).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(
+            files.get(path, Revision::FromLatest(0)).unwrap().as_deref(),
+            Some(&b"second"[..])
+        );
+
+        // the dropped trees, and the first version of the file, can now be collected
+        let report = files.collect_garbage().unwrap();
+        assert!(report.removed > 0);
+        assert_eq!(
+            files.get(path, Revision::FromLatest(0)).unwrap().as_deref(),
+            Some(&b"second"[..])
+        );
+
+        // keys are never pruned
+        assert_eq!(files.get_root_history(KEYRING).unwrap().len(), keys);
+    }
+}
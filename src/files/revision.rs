@@ -0,0 +1,328 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::files::{tags::TagName, Object, Revision};
+
+/// The formats local dates and times may be given in, as accepted by [NaiveDateTime::parse_from_str]
+const LOCAL_FORMATS: [&str; 4] = [
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%dT%H:%M",
+];
+
+/// The fewest hex digits of a revision's hash that may be given to identify it
+const MIN_PREFIX: usize = 4;
+
+#[derive(thiserror::Error, Debug)]
+#[error(
+    "revision must be one of 'latest', '~N' (N revisions before the latest), '#N' (the Nth revision from the earliest), \
+     a date and time (e.g. '2023-10-01 14:00', or RFC 3339), 'N minutes/hours/days/weeks ago', 'yesterday', \
+     'tag:NAME', or the start of a revision's hash"
+)]
+pub struct InvalidRevision;
+
+/// The first hex digits of the [Object] a revision is stored as. It is sent as it's digits, so that a prefix received
+/// from a client is checked in the same way as one parsed from the command line
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct ObjectPrefix {
+    /// The digits, packed into bytes. If there are an odd number of digits, the last is in the high half of it's byte
+    bytes: [u8; 32],
+    /// Number of digits
+    len: usize,
+}
+
+impl ObjectPrefix {
+    /// Parse a prefix of hex digits, at least [MIN_PREFIX] long
+    fn parse(s: &str) -> Option<ObjectPrefix> {
+        if s.len() < MIN_PREFIX || s.len() > 64 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        // pad the prefix out to a whole hash, so it can be parsed as one
+        let padded = format!("{s:0<64}");
+        let object: Object = padded.parse().ok()?;
+
+        Some(ObjectPrefix {
+            bytes: *object.hash(),
+            len: s.len(),
+        })
+    }
+
+    /// Whether `object`'s hash starts with the prefix
+    pub fn matches(&self, object: &Object) -> bool {
+        let hash = object.hash();
+        let whole = self.len / 2;
+
+        if hash[..whole] != self.bytes[..whole] {
+            return false;
+        }
+
+        self.len.is_multiple_of(2) || hash[whole] >> 4 == self.bytes[whole] >> 4
+    }
+}
+
+impl TryFrom<String> for ObjectPrefix {
+    type Error = InvalidRevision;
+
+    fn try_from(s: String) -> Result<ObjectPrefix, InvalidRevision> {
+        ObjectPrefix::parse(&s).ok_or(InvalidRevision)
+    }
+}
+
+impl From<ObjectPrefix> for String {
+    fn from(prefix: ObjectPrefix) -> String {
+        prefix.to_string()
+    }
+}
+
+impl std::fmt::Display for ObjectPrefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hex = Object::from_hash(self.bytes).hex().to_string();
+
+        write!(f, "{}", &hex[..self.len])
+    }
+}
+
+impl std::fmt::Display for Revision {
+    /// Display the [Revision] in a form it can be parsed from
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Revision::FromLatest(0) => write!(f, "latest"),
+            Revision::FromLatest(n) => write!(f, "~{n}"),
+            Revision::FromEarliest(n) => write!(f, "#{n}"),
+            Revision::AsOfTime(time) => write!(f, "time:{time}"),
+            Revision::Prefix(prefix) => write!(f, "{prefix}"),
+            Revision::Tag(name) => write!(f, "tag:{name}"),
+        }
+    }
+}
+
+/// Nanoseconds since the UNIX epoch of a given date and time
+fn nanos<Tz: TimeZone>(time: DateTime<Tz>) -> Result<u128, InvalidRevision> {
+    let seconds = u128::try_from(time.timestamp()).map_err(|_| InvalidRevision)?;
+
+    Ok(seconds * 1_000_000_000 + time.timestamp_subsec_nanos() as u128)
+}
+
+/// Parse a time relative to now, such as "3 hours ago" or "yesterday"
+fn parse_relative(s: &str) -> Option<chrono::Duration> {
+    if s == "yesterday" {
+        return Some(chrono::Duration::days(1));
+    }
+
+    let mut words = s.split_whitespace();
+    let (n, unit, "ago", None) = (words.next()?, words.next()?, words.next()?, words.next()) else {
+        return None;
+    };
+
+    let n: i64 = n.parse().ok()?;
+
+    let duration = match unit.strip_suffix('s').unwrap_or(unit) {
+        "second" | "sec" => chrono::Duration::seconds(n),
+        "minute" | "min" => chrono::Duration::minutes(n),
+        "hour" => chrono::Duration::hours(n),
+        "day" => chrono::Duration::days(n),
+        "week" => chrono::Duration::weeks(n),
+        _ => return None,
+    };
+
+    Some(duration)
+}
+
+/// Parse a date and time in the local timezone. A date on it's own is taken as midnight
+fn parse_local(s: &str) -> Option<DateTime<Local>> {
+    let time = LOCAL_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .or_else(|| {
+            let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+            date.and_hms_opt(0, 0, 0)
+        })?;
+
+    // times skipped over by daylight saving don't exist
+    Local.from_local_datetime(&time).earliest()
+}
+
+impl std::str::FromStr for Revision {
+    type Err = InvalidRevision;
+
+    /// Parse a [Revision] given on the command line. Dates and relative times are resolved to a [Revision::AsOfTime]
+    /// straight away, using the local timezone
+    fn from_str(s: &str) -> Result<Revision, InvalidRevision> {
+        let s = s.trim();
+        fn number<T: std::str::FromStr>(n: &str) -> Result<T, InvalidRevision> {
+            n.parse().map_err(|_| InvalidRevision)
+        }
+
+        if s == "latest" {
+            return Ok(Revision::FromLatest(0));
+        }
+
+        if let Some(n) = s.strip_prefix('~') {
+            return Ok(Revision::FromLatest(number(n)?));
+        }
+
+        if let Some(n) = s.strip_prefix('#') {
+            return Ok(Revision::FromEarliest(number(n)?));
+        }
+
+        match s.split_once(':') {
+            Some(("latest", n)) => return Ok(Revision::FromLatest(number(n)?)),
+            Some(("earliest", n)) => return Ok(Revision::FromEarliest(number(n)?)),
+            Some(("time", n)) => return Ok(Revision::AsOfTime(number(n)?)),
+            Some(("tag", name)) => {
+                return TagName::parse(name)
+                    .map(Revision::Tag)
+                    .ok_or(InvalidRevision)
+            }
+            _ => (),
+        }
+
+        if let Some(duration) = parse_relative(s) {
+            let time = Local::now()
+                .checked_sub_signed(duration)
+                .ok_or(InvalidRevision)?;
+
+            return Ok(Revision::AsOfTime(nanos(time)?));
+        }
+
+        if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+            return Ok(Revision::AsOfTime(nanos(time)?));
+        }
+
+        if let Some(time) = parse_local(s) {
+            return Ok(Revision::AsOfTime(nanos(time)?));
+        }
+
+        match ObjectPrefix::parse(s) {
+            Some(prefix) => Ok(Revision::Prefix(prefix)),
+            None => Err(InvalidRevision),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, TimeZone};
+
+    use crate::files::{tests, Error, Object, Path, Revision, DEFAULT_TREE};
+
+    fn parse(s: &str) -> Revision {
+        s.parse().unwrap()
+    }
+
+    fn time(revision: Revision) -> u128 {
+        match revision {
+            Revision::AsOfTime(time) => time,
+            revision => panic!("expected a time, got {revision:?}"),
+        }
+    }
+
+    #[test]
+    fn revisions_are_parsed() {
+        assert!(matches!(parse("latest"), Revision::FromLatest(0)));
+        assert!(matches!(parse("~3"), Revision::FromLatest(3)));
+        assert!(matches!(parse("latest:3"), Revision::FromLatest(3)));
+        assert!(matches!(parse("#2"), Revision::FromEarliest(2)));
+        assert!(matches!(parse("earliest:2"), Revision::FromEarliest(2)));
+        assert_eq!(time(parse("time:1234")), 1234);
+
+        assert_eq!(
+            time(parse("2023-10-01T14:00:00.5+01:00")),
+            1_696_165_200_500_000_000
+        );
+
+        let local = Local.with_ymd_and_hms(2023, 10, 1, 14, 0, 0).unwrap();
+        let local = local.timestamp() as u128 * 1_000_000_000;
+
+        assert_eq!(time(parse("2023-10-01 14:00")), local);
+        assert_eq!(time(parse("2023-10-01T14:00:00")), local);
+        assert_eq!(time(parse("2023-10-01")), local - 14 * 3600 * 1_000_000_000);
+
+        // relative times are parsed before now, so the difference between them is at least what was asked for
+        let hours = time(parse("3 hours ago"));
+        let yesterday = time(parse("yesterday"));
+        let now = time(parse("0 seconds ago"));
+
+        assert!(now - hours >= 3 * 3600 * 1_000_000_000);
+        assert!(now - yesterday >= 24 * 3600 * 1_000_000_000);
+        assert!(time(parse("1 week ago")) < yesterday);
+
+        for invalid in [
+            "",
+            "~",
+            "#x",
+            "3 fortnights ago",
+            "abc",
+            "2023-13-01",
+            "tomorrow",
+            "tag:",
+            "tag:no spaces",
+        ] {
+            assert!(invalid.parse::<Revision>().is_err(), "{invalid}");
+        }
+
+        // revisions are displayed in a form they can be parsed from
+        for revision in ["latest", "~3", "#2", "time:1234", "abcd1", "tag:v1.0"] {
+            assert_eq!(parse(revision).to_string(), revision);
+        }
+    }
+
+    #[test]
+    fn received_prefixes_are_checked() {
+        let sent = bincode::serialize(&parse("abcd1")).unwrap();
+        let received: Revision = bincode::deserialize(&sent).unwrap();
+
+        assert_eq!(received.to_string(), "abcd1");
+
+        // a prefix that could never have been parsed is refused, rather than being used as it is
+        for invalid in ["zzzz".to_owned(), "ab".to_owned(), "a".repeat(65)] {
+            let crafted = bincode::serialize(&(3u32, &invalid)).unwrap();
+
+            assert!(
+                bincode::deserialize::<Revision>(&crafted).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn revisions_are_found_by_hash() {
+        let files = tests::open();
+
+        files.insert(Path::new("/a").unwrap(), b"a").unwrap();
+        files.insert(Path::new("/b").unwrap(), b"b").unwrap();
+
+        let history = files.get_history(DEFAULT_TREE).unwrap();
+        let (_, object) = history[1];
+        let hex = object.hex().to_string();
+
+        let get = |revision: &str| files.get(Path::new("/a").unwrap(), revision.parse().unwrap());
+
+        assert_eq!(get(&hex[..8]).unwrap().unwrap(), b"a");
+        assert!(get(&hex).unwrap().is_some());
+        assert!(get(&hex[..7]).unwrap().is_some());
+
+        // no revision starts with a prefix that differs from every revision's hash
+        let unused = (0..=0xffffu32)
+            .map(|n| format!("{n:04x}"))
+            .find(|prefix| {
+                history
+                    .iter()
+                    .all(|(_, object)| !object.hex().to_string().starts_with(prefix))
+            })
+            .unwrap();
+
+        assert!(matches!(get(&unused), Err(Error::RevisionNotFound { .. })));
+
+        // hashes must still be valid hex
+        assert!("zzzz".parse::<Revision>().is_err());
+        assert!(Object::of(b"")
+            .hex()
+            .to_string()
+            .parse::<Revision>()
+            .is_ok());
+    }
+}
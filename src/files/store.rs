@@ -0,0 +1,462 @@
+use std::io::{self, Write};
+use std::path::{Path as SysPath, PathBuf};
+
+use crate::config::Storage;
+use crate::files::{Error, Object};
+
+/// The key the storage backend of a database is recorded under, in the database's default tree
+const BACKEND_KEY: &[u8] = b"storage";
+
+/// The name of the backend a [Storage] config stores objects with
+fn backend(storage: &Storage) -> &'static str {
+    match storage {
+        Storage::Sled => "sled",
+        Storage::Dir { .. } => "dir",
+    }
+}
+
+/// Check that a database is opened with the storage backend it was created with, as it's objects can't be found with
+/// any other. The backend is recorded the first time a database is opened
+pub fn check_backend(db: &sled::Db, storage: &Storage) -> Result<(), Error> {
+    let configured = backend(storage);
+
+    let recorded = match db.get(BACKEND_KEY)? {
+        Some(recorded) => String::from_utf8_lossy(&recorded).into_owned(),
+
+        // databases written before the backend was recorded have objects in exactly one place, and every initialised
+        // database has objects, if only those of it's keyring
+        None if db.open_tree("objects")?.is_empty() && !db.open_tree("roots")?.is_empty() => {
+            "dir".to_owned()
+        }
+        None if !db.open_tree("objects")?.is_empty() => "sled".to_owned(),
+
+        None => {
+            db.insert(BACKEND_KEY, configured)?;
+            return Ok(());
+        }
+    };
+
+    if recorded != configured {
+        return Err(Error::StorageMismatch {
+            recorded,
+            configured: configured.to_owned(),
+        });
+    }
+
+    db.insert(BACKEND_KEY, configured)?;
+
+    Ok(())
+}
+
+/// Where the data of each [Object] is stored, by it's hash. Objects are immutable, so storing an object that is already
+/// stored may leave either copy in place. Only objects are stored this way; the history and tags of each tree are always
+/// kept in the sled database
+pub trait ObjectStore: Send + Sync {
+    /// Load the data stored for an `object`, if it is stored
+    fn get(&self, object: &Object) -> Result<Option<Vec<u8>>, Error>;
+
+    fn contains(&self, object: &Object) -> Result<bool, Error>;
+
+    fn insert(&self, object: &Object, data: Vec<u8>) -> Result<(), Error>;
+
+    /// Remove an `object`, returning the size of the data that was stored for it, if it was stored
+    fn remove(&self, object: &Object) -> Result<Option<u64>, Error>;
+
+    /// Every stored [Object], in no particular order
+    fn objects(&self) -> Box<dyn Iterator<Item = Result<Object, Error>> + '_>;
+
+    /// Remove every object
+    fn clear(&self) -> Result<(), Error>;
+
+    /// Make sure every stored object has been written to disk
+    fn flush(&self) -> Result<(), Error>;
+}
+
+/// Stores objects in a tree of the sled database, alongside the rest of the database
+pub struct SledStore(pub sled::Tree);
+
+impl ObjectStore for SledStore {
+    fn get(&self, object: &Object) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.0.get(object.hash())?.map(|data| data.to_vec()))
+    }
+
+    fn contains(&self, object: &Object) -> Result<bool, Error> {
+        Ok(self.0.contains_key(object.hash())?)
+    }
+
+    fn insert(&self, object: &Object, data: Vec<u8>) -> Result<(), Error> {
+        self.0.insert(object.hash(), data)?;
+
+        Ok(())
+    }
+
+    fn remove(&self, object: &Object) -> Result<Option<u64>, Error> {
+        Ok(self.0.remove(object.hash())?.map(|data| data.len() as u64))
+    }
+
+    fn objects(&self) -> Box<dyn Iterator<Item = Result<Object, Error>> + '_> {
+        let objects = self.0.iter().keys().filter_map(|hash| match hash {
+            Ok(hash) => match <[u8; 32]>::try_from(&hash[..]) {
+                Ok(hash) => Some(Ok(Object::from_hash(hash))),
+                Err(_) => {
+                    log::error!("object with invalid key {:?}", &hash[..]);
+                    None
+                }
+            },
+            Err(e) => Some(Err(e.into())),
+        });
+
+        Box::new(objects)
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        Ok(self.0.clear()?)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.0.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Stores each object as a file in a directory, named by it's hash. Files are spread between directories named after
+/// the first two bytes of their hash, e.g. `ab/cd/abcd...`, so that no one directory grows too large
+pub struct DirStore {
+    dir: PathBuf,
+}
+
+impl DirStore {
+    /// Open a store in `dir`, creating it if it doesn't exist
+    pub fn open(dir: impl AsRef<SysPath>) -> Result<DirStore, Error> {
+        let dir = dir.as_ref().to_owned();
+        std::fs::create_dir_all(&dir)?;
+
+        Ok(DirStore { dir })
+    }
+
+    fn path(&self, object: &Object) -> PathBuf {
+        let hash = object.hash();
+
+        self.dir
+            .join(format!("{:02x}", hash[0]))
+            .join(format!("{:02x}", hash[1]))
+            .join(object.hex().to_string())
+    }
+
+    /// The entries of a directory, or none if it doesn't exist or is not a directory
+    fn entries(dir: &SysPath) -> io::Result<Vec<std::fs::DirEntry>> {
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+
+        std::fs::read_dir(dir)?.collect()
+    }
+
+    /// Every stored [Object]. Files that are not named after a hash, such as ones still being written, and files not
+    /// where an object with that hash would be, are skipped
+    fn list(&self) -> Result<Vec<Object>, Error> {
+        let mut objects = vec![];
+
+        for first in DirStore::entries(&self.dir)? {
+            for second in DirStore::entries(&first.path())? {
+                for file in DirStore::entries(&second.path())? {
+                    let object = file.file_name().to_str().and_then(|name| name.parse().ok());
+
+                    if let Some(object) = object.filter(|object| self.path(object) == file.path()) {
+                        objects.push(object);
+                    }
+                }
+            }
+        }
+
+        Ok(objects)
+    }
+}
+
+impl ObjectStore for DirStore {
+    fn get(&self, object: &Object) -> Result<Option<Vec<u8>>, Error> {
+        match std::fs::read(self.path(object)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn contains(&self, object: &Object) -> Result<bool, Error> {
+        Ok(self.path(object).try_exists()?)
+    }
+
+    fn insert(&self, object: &Object, data: Vec<u8>) -> Result<(), Error> {
+        let path = self.path(object);
+        let parent = path.parent().unwrap();
+
+        std::fs::create_dir_all(parent)?;
+
+        // write to a temporary file first, so that an object is never seen half written
+        let mut file = tempfile::NamedTempFile::new_in(parent)?;
+        file.write_all(&data)?;
+        file.as_file().sync_data()?;
+        file.persist(path).map_err(|e| e.error)?;
+
+        Ok(())
+    }
+
+    fn remove(&self, object: &Object) -> Result<Option<u64>, Error> {
+        let path = self.path(object);
+
+        let len = match std::fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        std::fs::remove_file(path)?;
+
+        Ok(Some(len))
+    }
+
+    fn objects(&self) -> Box<dyn Iterator<Item = Result<Object, Error>> + '_> {
+        match self.list() {
+            Ok(objects) => Box::new(objects.into_iter().map(Ok)),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        // the directory may hold other files, so only objects are removed, along with the directories they leave empty
+        for object in self.list()? {
+            self.remove(&object)?;
+
+            let path = self.path(&object);
+
+            for dir in path.ancestors().skip(1).take(2) {
+                match std::fs::remove_dir(dir) {
+                    Ok(()) => (),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                    // still holds other objects, or files that are not objects
+                    Err(_) => break,
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        // every object is synced as it is written
+        Ok(())
+    }
+}
+
+/// Keeps objects in memory, for tests
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStore(std::sync::RwLock<std::collections::HashMap<Object, Vec<u8>>>);
+
+#[cfg(test)]
+impl ObjectStore for MemoryStore {
+    fn get(&self, object: &Object) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.0.read().unwrap().get(object).cloned())
+    }
+
+    fn contains(&self, object: &Object) -> Result<bool, Error> {
+        Ok(self.0.read().unwrap().contains_key(object))
+    }
+
+    fn insert(&self, object: &Object, data: Vec<u8>) -> Result<(), Error> {
+        self.0.write().unwrap().insert(*object, data);
+
+        Ok(())
+    }
+
+    fn remove(&self, object: &Object) -> Result<Option<u64>, Error> {
+        let removed = self.0.write().unwrap().remove(object);
+
+        Ok(removed.map(|data| data.len() as u64))
+    }
+
+    fn objects(&self) -> Box<dyn Iterator<Item = Result<Object, Error>> + '_> {
+        let objects: Vec<_> = self.0.read().unwrap().keys().copied().collect();
+
+        Box::new(objects.into_iter().map(Ok))
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        self.0.write().unwrap().clear();
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DirStore, MemoryStore, ObjectStore, SledStore};
+    use crate::config::{Compression, Storage};
+    use crate::files::{tests, Error, Files, Object, Path, Revision};
+
+    fn check(store: &dyn ObjectStore) {
+        let a = Object::of(b"a");
+        let b = Object::of(b"b");
+
+        assert_eq!(store.get(&a).unwrap(), None);
+        assert!(!store.contains(&a).unwrap());
+
+        store.insert(&a, b"a".to_vec()).unwrap();
+        store.insert(&b, b"bb".to_vec()).unwrap();
+        store.insert(&b, b"bb".to_vec()).unwrap();
+
+        assert_eq!(store.get(&a).unwrap().unwrap(), b"a");
+        assert!(store.contains(&b).unwrap());
+
+        let mut objects: Vec<_> = store.objects().map(Result::unwrap).collect();
+        objects.sort_by_key(|object| *object.hash());
+
+        let mut expected = vec![a, b];
+        expected.sort_by_key(|object| *object.hash());
+
+        assert_eq!(objects, expected);
+
+        assert_eq!(store.remove(&b).unwrap(), Some(2));
+        assert_eq!(store.remove(&b).unwrap(), None);
+        assert_eq!(store.objects().count(), 1);
+
+        store.clear().unwrap();
+        assert_eq!(store.objects().count(), 0);
+        assert!(!store.contains(&a).unwrap());
+    }
+
+    #[test]
+    fn stores_behave_alike() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        check(&SledStore(db.open_tree("objects").unwrap()));
+
+        check(&DirStore::open(dir.path()).unwrap());
+        check(&MemoryStore::default());
+    }
+
+    #[test]
+    fn objects_are_spread_between_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DirStore::open(dir.path()).unwrap();
+
+        let object = Object::of(b"data");
+        store.insert(&object, b"data".to_vec()).unwrap();
+
+        let hex = object.hex().to_string();
+        let path = dir.path().join(&hex[0..2]).join(&hex[2..4]).join(&hex);
+
+        assert_eq!(std::fs::read(path).unwrap(), b"data");
+
+        // partially written files are not objects
+        std::fs::write(
+            dir.path().join(&hex[0..2]).join(&hex[2..4]).join(".tmp123"),
+            b"",
+        )
+        .unwrap();
+        assert_eq!(store.objects().count(), 1);
+    }
+
+    #[test]
+    fn only_objects_are_cleared() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DirStore::open(dir.path()).unwrap();
+
+        let object = Object::of(b"data");
+        store.insert(&object, b"data".to_vec()).unwrap();
+
+        // a file named like an object, but not where that object would be stored
+        let other = Object::of(b"other").hex().to_string();
+
+        for path in ["notes.txt", "ab/notes.txt", &other] {
+            let path = dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"keep").unwrap();
+        }
+
+        store.clear().unwrap();
+
+        let mut left: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+
+        let mut expected = vec!["ab".to_owned(), "notes.txt".to_owned(), other];
+        expected.sort();
+
+        assert_eq!(left, expected);
+        assert!(dir.path().join("ab/notes.txt").exists());
+        assert!(!store.contains(&object).unwrap());
+    }
+
+    #[test]
+    fn databases_are_opened_with_their_backend() {
+        let db = tempfile::tempdir().unwrap();
+        let objects = tempfile::tempdir().unwrap();
+
+        let dir = Storage::Dir {
+            dir: objects.path().to_owned(),
+        };
+
+        tests::reopen(db.path(), &dir, Default::default).unwrap();
+
+        assert!(matches!(
+            tests::reopen(db.path(), &Storage::Sled, Default::default),
+            Err(Error::StorageMismatch { .. })
+        ));
+
+        // a database that didn't record it's backend is checked against where it's objects are
+        let db = tempfile::tempdir().unwrap();
+
+        tests::reopen(db.path(), &Storage::Sled, Default::default)
+            .unwrap()
+            .db
+            .remove(super::BACKEND_KEY)
+            .unwrap();
+
+        assert!(matches!(
+            tests::reopen(db.path(), &dir, Default::default),
+            Err(Error::StorageMismatch { .. })
+        ));
+        tests::reopen(db.path(), &Storage::Sled, Default::default).unwrap();
+    }
+
+    #[test]
+    fn objects_are_stored_apart_from_the_database() {
+        let db = tempfile::tempdir().unwrap();
+        let objects = tempfile::tempdir().unwrap();
+
+        let storage = Storage::Dir {
+            dir: objects.path().to_owned(),
+        };
+        let files = Files::open(
+            db.path(),
+            &storage,
+            Compression::default(),
+            Default::default(),
+        )
+        .unwrap();
+
+        let path = Path::new("/file").unwrap();
+        files.insert(path, b"on another disk").unwrap();
+
+        assert!(DirStore::open(objects.path())
+            .unwrap()
+            .contains(&Object::of(b"on another disk"))
+            .unwrap());
+        assert!(files.db.open_tree("objects").unwrap().is_empty());
+
+        assert_eq!(
+            files.get(path, Revision::FromLatest(0)).unwrap().unwrap(),
+            b"on another disk"
+        );
+        assert!(files.fsck().unwrap().is_clean());
+    }
+}
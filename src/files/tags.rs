@@ -0,0 +1,354 @@
+use chrono::TimeZone;
+use serde::{Deserialize, Serialize};
+use sled::Transactional;
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::files::{Error, Files, Object, Revision};
+
+/// The longest a tag's name may be, in bytes
+const MAX_TAG_NAME: usize = 32;
+
+/// The tags of a tree, mapping each name to the revision it pins, and when that revision was created
+pub(super) type Tags = BTreeMap<String, (u128, Object)>;
+
+/// The name of a tag; made up of ASCII letters, digits, `-`, `_` and `.`. It is stored inline, so that a
+/// [Revision] naming a tag can still be copied, and sent as a string, so that a name received from a client is checked
+/// with [TagName::parse]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct TagName {
+    bytes: [u8; MAX_TAG_NAME],
+    len: usize,
+}
+
+impl TagName {
+    /// Check that `name` is a valid tag name
+    pub fn parse(name: &str) -> Option<TagName> {
+        let valid = !name.is_empty()
+            && name.len() <= MAX_TAG_NAME
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+
+        if !valid {
+            return None;
+        }
+
+        let mut bytes = [0; MAX_TAG_NAME];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+
+        Some(TagName {
+            bytes,
+            len: name.len(),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ever built from a valid ASCII name
+        std::str::from_utf8(&self.bytes[..self.len]).unwrap()
+    }
+}
+
+impl TryFrom<String> for TagName {
+    type Error = Error;
+
+    fn try_from(name: String) -> Result<TagName, Error> {
+        parse_name(&name)
+    }
+}
+
+impl From<TagName> for String {
+    fn from(name: TagName) -> String {
+        name.as_str().to_owned()
+    }
+}
+
+impl std::fmt::Display for TagName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A name pinned to a revision of a tree
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TagInfo {
+    pub name: String,
+    /// When the tagged revision was created
+    pub timestamp: u128,
+    /// The [Object] the tagged revision is stored as
+    pub object: Object,
+}
+
+impl std::fmt::Display for TagInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let date = chrono::Local.timestamp_nanos(self.timestamp as i64);
+
+        write!(f, "{}: {date} {}", self.name, self.object.hex())
+    }
+}
+
+fn parse_name(name: &str) -> Result<TagName, Error> {
+    TagName::parse(name).ok_or_else(|| Error::InvalidTagName {
+        name: name.to_owned(),
+    })
+}
+
+// filesystem internals
+impl Files {
+    /// Deserialise the tags of a tree, which has none if nothing is stored for it
+    pub(super) fn decode_tags(&self, tags: Option<sled::IVec>) -> Result<Tags, Error> {
+        match tags {
+            Some(tags) => Ok(bincode::deserialize(&self.keys.open(&tags)?)?),
+            None => Ok(Tags::new()),
+        }
+    }
+
+    /// Serialise the tags of a tree, in the form they are stored in the database
+    pub(super) fn encode_tags(&self, tags: &Tags) -> Result<Vec<u8>, Error> {
+        Ok(self.keys.seal(bincode::serialize(tags)?))
+    }
+
+    /// Find the revision a tag pins in a `tree`, along with the time it was created
+    pub(super) fn get_tag(
+        &self,
+        tree: &str,
+        name: TagName,
+    ) -> Result<Option<(u128, Object)>, Error> {
+        let tags = self.decode_tags(self.tags.get(tree)?)?;
+
+        Ok(tags.get(name.as_str()).copied())
+    }
+
+    /// Every [Object] tagged in a `tree`
+    pub(super) fn tagged(&self, tree: &[u8]) -> Result<HashSet<Object>, Error> {
+        let tags = self.decode_tags(self.tags.get(tree)?)?;
+
+        Ok(tags.into_values().map(|(_, object)| object).collect())
+    }
+
+    /// Every [Object] tagged in any tree
+    pub(super) fn all_tagged(&self) -> Result<HashSet<Object>, Error> {
+        let mut tagged = HashSet::new();
+
+        for entry in self.tags.iter() {
+            let (_, tags) = entry?;
+            tagged.extend(
+                self.decode_tags(Some(tags))?
+                    .into_values()
+                    .map(|(_, object)| object),
+            );
+        }
+
+        Ok(tagged)
+    }
+}
+
+// public helpers
+impl Files {
+    /// Pin a new tag called `name` to a given `revision` of a `tree`. Tagged revisions are never pruned or collected
+    pub fn create_tag(&self, tree: &str, name: &str, revision: Revision) -> Result<TagInfo, Error> {
+        Files::check_tree_name(tree)?;
+        let name = parse_name(name)?;
+
+        log::info!("tagging {revision} in tree '{tree}' as '{name}'");
+
+        // the revision may be pruned and it's objects collected before the tag is stored
+        let _guard = self.collector.write();
+
+        let (timestamp, object) = self.get_revision(tree, revision)?;
+
+        (&self.roots, &self.tags)
+            .transaction(|(roots, tags)| {
+                use sled::transaction::ConflictableTransactionError::Abort;
+
+                // the tree may have been deleted since the revision was found
+                if roots.get(tree)?.is_none() {
+                    return Err(Abort(Error::TreeNotFound {
+                        name: tree.to_owned(),
+                    }));
+                }
+
+                let mut existing = self.decode_tags(tags.get(tree)?).map_err(Abort)?;
+
+                if existing.contains_key(name.as_str()) {
+                    return Err(Abort(Error::TagExists {
+                        name: name.to_string(),
+                    }));
+                }
+
+                existing.insert(name.to_string(), (timestamp, object));
+
+                let existing = self.encode_tags(&existing).map_err(Abort)?;
+                tags.insert(tree, existing)?;
+
+                Ok(())
+            })
+            .map_err(Error::from)?;
+
+        Ok(TagInfo {
+            name: name.to_string(),
+            timestamp,
+            object,
+        })
+    }
+
+    /// List the tags of a `tree`, in order of their names
+    pub fn list_tags(&self, tree: &str) -> Result<Vec<TagInfo>, Error> {
+        Files::check_tree_name(tree)?;
+
+        if !self.roots.contains_key(tree)? {
+            return Err(Error::TreeNotFound {
+                name: tree.to_owned(),
+            });
+        }
+
+        let tags = self.decode_tags(self.tags.get(tree)?)?;
+
+        Ok(tags
+            .into_iter()
+            .map(|(name, (timestamp, object))| TagInfo {
+                name,
+                timestamp,
+                object,
+            })
+            .collect())
+    }
+
+    /// Remove the tag called `name` from a `tree`. The revision it pinned is left in the tree's history
+    pub fn delete_tag(&self, tree: &str, name: &str) -> Result<(), Error> {
+        Files::check_tree_name(tree)?;
+        let name = parse_name(name)?;
+
+        log::info!("deleting tag '{name}' from tree '{tree}'");
+
+        self.tags
+            .transaction(|tags| {
+                use sled::transaction::ConflictableTransactionError::Abort;
+
+                let mut existing = self.decode_tags(tags.get(tree)?).map_err(Abort)?;
+
+                if existing.remove(name.as_str()).is_none() {
+                    return Err(Abort(Error::TagNotFound {
+                        tree: tree.to_owned(),
+                        name: name.to_string(),
+                    }));
+                }
+
+                if existing.is_empty() {
+                    tags.remove(tree)?;
+                } else {
+                    let existing = self.encode_tags(&existing).map_err(Abort)?;
+                    tags.insert(tree, existing)?;
+                }
+
+                Ok(())
+            })
+            .map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TagName;
+    use crate::config::Retention;
+    use crate::files::{tests, Error, Path, Revision, DEFAULT_TREE};
+
+    #[test]
+    fn tags_are_created_and_deleted() {
+        let files = tests::open();
+        let path = Path::new("/a").unwrap();
+
+        files.insert(path, b"before").unwrap();
+        files
+            .create_tag(DEFAULT_TREE, "before-migration", Revision::FromLatest(0))
+            .unwrap();
+        files.insert(path, b"after").unwrap();
+
+        let tagged = "tag:before-migration".parse().unwrap();
+        assert_eq!(files.get(path, tagged).unwrap().unwrap(), b"before");
+
+        let tags = files.list_tags(DEFAULT_TREE).unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].name, "before-migration");
+
+        // names are unique within a tree
+        assert!(matches!(
+            files.create_tag(DEFAULT_TREE, "before-migration", Revision::FromLatest(0)),
+            Err(Error::TagExists { .. })
+        ));
+        assert!(matches!(
+            files.create_tag(DEFAULT_TREE, "no spaces", Revision::FromLatest(0)),
+            Err(Error::InvalidTagName { .. })
+        ));
+
+        files.delete_tag(DEFAULT_TREE, "before-migration").unwrap();
+
+        assert!(files.list_tags(DEFAULT_TREE).unwrap().is_empty());
+        assert!(matches!(
+            files.get(path, tagged),
+            Err(Error::RevisionNotFound { .. })
+        ));
+        assert!(matches!(
+            files.delete_tag(DEFAULT_TREE, "before-migration"),
+            Err(Error::TagNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn tagged_revisions_are_kept() {
+        let files = tests::open();
+        let path = Path::new("/a").unwrap();
+
+        files.insert(path, b"first").unwrap();
+        files
+            .create_tag(DEFAULT_TREE, "first", Revision::FromLatest(0))
+            .unwrap();
+        files.insert(path, b"second").unwrap();
+        files.insert(path, b"third").unwrap();
+
+        let policy = Retention {
+            keep_last: Some(1),
+            ..Retention::default()
+        };
+
+        files.prune(&policy).unwrap();
+        files.collect_garbage().unwrap();
+
+        // only the tagged revision survives alongside the latest
+        assert_eq!(files.get_history(DEFAULT_TREE).unwrap().len(), 2);
+
+        let tagged = "tag:first".parse().unwrap();
+        assert_eq!(files.get(path, tagged).unwrap().unwrap(), b"first");
+
+        // tags follow their tree when it is renamed, and go with it when it is deleted
+        files.rename_tree(DEFAULT_TREE, "renamed").unwrap();
+        assert_eq!(files.list_tags("renamed").unwrap().len(), 1);
+
+        files.delete_tree("renamed").unwrap();
+        files.create_tree("renamed").unwrap();
+        assert!(files.list_tags("renamed").unwrap().is_empty());
+    }
+
+    #[test]
+    fn received_names_are_checked() {
+        let name = TagName::parse("v1.0").unwrap();
+        let received: TagName = bincode::deserialize(&bincode::serialize(&name).unwrap()).unwrap();
+
+        assert_eq!(received, name);
+
+        // a name that could never have been parsed is refused, rather than being used as it is
+        for invalid in ["", "no spaces", "caf\u{e9}", &"a".repeat(33)] {
+            let crafted = bincode::serialize(invalid).unwrap();
+
+            assert!(
+                bincode::deserialize::<TagName>(&crafted).is_err(),
+                "{invalid}"
+            );
+        }
+
+        // nor can the name be given as raw bytes that aren't a string
+        let crafted = bincode::serialize(&vec![0xffu8; 4]).unwrap();
+        assert!(bincode::deserialize::<TagName>(&crafted).is_err());
+    }
+}
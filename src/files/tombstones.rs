@@ -0,0 +1,292 @@
+use chrono::TimeZone;
+use serde::{Deserialize, Serialize};
+
+use crate::files::{Error, Files, Object, Path, Revision, Tree};
+
+/// A deleted file or directory, which is still kept in it's parent directory
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Tombstone {
+    pub path: String,
+    /// When the node was deleted. Tombstones left by older versions of `ksync` hold the time the node was created
+    pub deleted: u128,
+}
+
+impl std::fmt::Display for Tombstone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let date = chrono::Local.timestamp_nanos(self.deleted as i64);
+
+        write!(f, "{} deleted {date}", self.path)
+    }
+}
+
+// public helpers
+impl Files {
+    /// List the tombstones at or below a given `path` in the latest revision of a `tree`, in order of their paths.
+    /// Nodes inside of a deleted directory are not listed on their own
+    pub fn tombstones(&self, tree: &str, path: Path) -> Result<Vec<Tombstone>, Error> {
+        Files::check_tree_name(tree)?;
+
+        log::debug!("listing tombstones below {path} in tree '{tree}'");
+
+        let mut tombstones = self.with_root(tree, Revision::FromLatest(0), |tree| {
+            let node = tree.traverse_all(path)?.ok_or_else(|| Error::NotFound {
+                path: path.as_str().to_owned(),
+            })?;
+
+            let tombstones = node
+                .iter()
+                .filter(|(_, node)| node.data().is_none())
+                .map(|(relative, node)| {
+                    // paths are relative to `path`, which is itself listed as an empty path
+                    let path = match path.as_str() {
+                        "/" => relative,
+                        path => format!("{path}{relative}"),
+                    };
+
+                    Tombstone {
+                        path,
+                        deleted: node.timestamp(),
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            Ok(tombstones)
+        })?;
+
+        tombstones.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(tombstones)
+    }
+
+    /// Bring back a deleted `path` in a `tree` with the contents it had in the last revision it existed in, as a new
+    /// revision. Returns the index of that revision in the tree's history. If an `expected` revision is given, the path
+    /// is only brought back if it is still the latest revision
+    pub fn undelete(
+        &self,
+        tree: &str,
+        path: Path,
+        expected: Option<Object>,
+    ) -> Result<usize, Error> {
+        Files::check_tree_name(tree)?;
+
+        log::debug!("undeleting {path} in tree '{tree}'");
+
+        // the revision the node is found in may be pruned before it is linked again
+        let _guard = self.collector.write();
+
+        let history = self.get_root_history(tree)?;
+
+        if let Some(&(_, latest)) = history.last() {
+            if self.resolve(latest, path)?.is_some() {
+                return Err(Error::AlreadyExists {
+                    path: path.as_str().to_owned(),
+                });
+            }
+        }
+
+        for (revision, &(timestamp, root)) in history.iter().enumerate().rev() {
+            if self.resolve(root, path)?.is_none() {
+                continue;
+            }
+
+            let mut old = Tree::load(self, root, timestamp)?;
+            let node = old.traverse(path)?.filter(|node| node.data().is_some());
+
+            if let Some(node) = node.cloned() {
+                self.restore_node(tree, path, node, expected)?;
+
+                return Ok(revision);
+            }
+        }
+
+        Err(Error::NotFound {
+            path: path.as_str().to_owned(),
+        })
+    }
+
+    /// Permanently remove the tombstones at or below a given `path` in a `tree` that were deleted before a given `time`,
+    /// as a single new revision, so that directories don't grow without bound. Older revisions still hold them. Returns
+    /// the number of tombstones removed. If an `expected` revision is given, they are only removed if it is still the
+    /// latest revision
+    pub fn purge_tombstones(
+        &self,
+        tree: &str,
+        path: Path,
+        time: u128,
+        expected: Option<Object>,
+    ) -> Result<usize, Error> {
+        log::debug!("purging tombstones below {path} in tree '{tree}'");
+
+        // don't add a revision that changes nothing
+        let tombstones = self.tombstones(tree, path)?;
+
+        if tombstones.iter().all(|tombstone| tombstone.deleted >= time) {
+            return Ok(0);
+        }
+
+        self.with_root_mut_from(tree, expected, |tree| tree.purge(path, time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tombstone;
+    use crate::files::{tests, Error, Path, Revision, DEFAULT_TREE};
+
+    fn paths(tombstones: &[Tombstone]) -> Vec<&str> {
+        tombstones
+            .iter()
+            .map(|tombstone| tombstone.path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn tombstones_are_listed_and_undeleted() {
+        let files = tests::open();
+        let path = |path| Path::new(path).unwrap();
+
+        files.insert(path("/a/file"), b"v1").unwrap();
+        files.insert(path("/a/file"), b"v2").unwrap();
+        files.insert(path("/b/gone/file"), b"gone").unwrap();
+        files.insert(path("/b/kept"), b"kept").unwrap();
+
+        files.delete(DEFAULT_TREE, path("/a/file"), None).unwrap();
+        files.delete(DEFAULT_TREE, path("/b/gone"), None).unwrap();
+
+        let tombstones = files.tombstones(DEFAULT_TREE, path("/")).unwrap();
+        assert_eq!(paths(&tombstones), ["/a/file", "/b/gone"]);
+
+        let below = files.tombstones(DEFAULT_TREE, path("/b")).unwrap();
+        assert_eq!(paths(&below), ["/b/gone"]);
+
+        // the file comes back with it's last contents
+        let revision = files.undelete(DEFAULT_TREE, path("/a/file"), None).unwrap();
+        let get = |p| files.get(path(p), Revision::FromLatest(0)).unwrap();
+
+        assert_eq!(get("/a/file").unwrap(), b"v2");
+        assert_eq!(
+            files
+                .get(path("/a/file"), Revision::FromEarliest(revision))
+                .unwrap()
+                .unwrap(),
+            b"v2"
+        );
+
+        // as does a whole directory, once only it's parent is left
+        files.delete(DEFAULT_TREE, path("/b"), None).unwrap();
+        files
+            .undelete(DEFAULT_TREE, path("/b/gone/file"), None)
+            .unwrap();
+
+        assert_eq!(get("/b/gone/file").unwrap(), b"gone");
+
+        assert!(matches!(
+            files.undelete(DEFAULT_TREE, path("/a/file"), None),
+            Err(Error::AlreadyExists { .. })
+        ));
+        assert!(matches!(
+            files.undelete(DEFAULT_TREE, path("/never"), None),
+            Err(Error::NotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn old_tombstones_are_purged() {
+        let files = tests::open();
+        let path = |path| Path::new(path).unwrap();
+
+        files.insert(path("/dir/old"), b"old").unwrap();
+        files.insert(path("/new"), b"new").unwrap();
+        files.delete(DEFAULT_TREE, path("/dir/old"), None).unwrap();
+
+        let tombstones = files.tombstones(DEFAULT_TREE, path("/")).unwrap();
+        let cutoff = tombstones[0].deleted + 1;
+
+        files.delete(DEFAULT_TREE, path("/new"), None).unwrap();
+
+        let revisions = files.get_history(DEFAULT_TREE).unwrap().len();
+
+        // nothing was deleted before the first deletion
+        let purged = files
+            .purge_tombstones(DEFAULT_TREE, path("/"), cutoff - 1, None)
+            .unwrap();
+
+        assert_eq!(purged, 0);
+        assert_eq!(files.get_history(DEFAULT_TREE).unwrap().len(), revisions);
+
+        let purged = files
+            .purge_tombstones(DEFAULT_TREE, path("/"), cutoff, None)
+            .unwrap();
+
+        assert_eq!(purged, 1);
+
+        let tombstones = files.tombstones(DEFAULT_TREE, path("/")).unwrap();
+        assert_eq!(paths(&tombstones), ["/new"]);
+
+        // the purged path can still be brought back from history
+        files
+            .undelete(DEFAULT_TREE, path("/dir/old"), None)
+            .unwrap();
+        assert_eq!(
+            files
+                .get(path("/dir/old"), Revision::FromLatest(0))
+                .unwrap()
+                .unwrap(),
+            b"old"
+        );
+    }
+
+    #[test]
+    fn nested_tombstones_are_purged_as_they_are_listed() {
+        let files = tests::open();
+        let path = |path| Path::new(path).unwrap();
+
+        files.insert(path("/a/b/c/old"), b"old").unwrap();
+        files.insert(path("/a/gone/inner"), b"inner").unwrap();
+        files.insert(path("/a/b/new"), b"new").unwrap();
+
+        files
+            .delete(DEFAULT_TREE, path("/a/b/c/old"), None)
+            .unwrap();
+        // the tombstone of `inner` goes with it's directory
+        files
+            .delete(DEFAULT_TREE, path("/a/gone/inner"), None)
+            .unwrap();
+        files.delete(DEFAULT_TREE, path("/a/gone"), None).unwrap();
+
+        let tombstones = files.tombstones(DEFAULT_TREE, path("/")).unwrap();
+        assert_eq!(paths(&tombstones), ["/a/b/c/old", "/a/gone"]);
+
+        let cutoff = tombstones.iter().map(|t| t.deleted).max().unwrap() + 1;
+        files.delete(DEFAULT_TREE, path("/a/b/new"), None).unwrap();
+
+        // a tombstone is purged when it is named itself, as it is listed when it is named itself
+        let old = files.tombstones(DEFAULT_TREE, path("/a/b/c/old")).unwrap();
+        assert_eq!(paths(&old), ["/a/b/c/old"]);
+
+        let purged = files
+            .purge_tombstones(DEFAULT_TREE, path("/a/b/c/old"), cutoff, None)
+            .unwrap();
+        assert_eq!(purged, 1);
+
+        let purged = files
+            .purge_tombstones(DEFAULT_TREE, path("/"), cutoff, None)
+            .unwrap();
+        assert_eq!(purged, 1);
+
+        let tombstones = files.tombstones(DEFAULT_TREE, path("/")).unwrap();
+        assert_eq!(paths(&tombstones), ["/a/b/new"]);
+
+        // once nothing listed is old enough, nothing is purged, and no revision is added
+        let revisions = files.get_history(DEFAULT_TREE).unwrap().len();
+
+        for purge in ["/", "/a", "/a/b/new"] {
+            let purged = files
+                .purge_tombstones(DEFAULT_TREE, path(purge), cutoff, None)
+                .unwrap();
+            assert_eq!(purged, 0);
+        }
+
+        assert_eq!(files.get_history(DEFAULT_TREE).unwrap().len(), revisions);
+    }
+}
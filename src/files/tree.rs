@@ -0,0 +1,618 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::files::{tags::Tags, Error, Files, Node, NodeData, Object, Path, RootHistory};
+
+/// Marks an [Object] as containing a single directory, rather than a whole tree serialized as one [Node] by an older
+/// version of `ksync`
+const MAGIC: &[u8; 8] = b"ksyncdr\0";
+
+/// A child of a directory, as it is stored in the database
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct Entry {
+    /// `None` if the child has been deleted
+    data: Option<EntryData>,
+    timestamp: u128,
+}
+
+/// What a child of a stored directory references
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub(super) enum EntryData {
+    Dir(Object),
+    File(Object),
+}
+
+/// Serialize the children of a directory to the format it is stored in
+fn encode(entries: &BTreeMap<&str, Entry>) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    bincode::serialize_into(&mut data, entries).unwrap();
+
+    data
+}
+
+/// Deserialize the children of a directory from an [Object]'s data. Returns `None` if the data is not a directory
+fn decode(data: &[u8]) -> Option<BTreeMap<String, Entry>> {
+    let data = data.strip_prefix(MAGIC)?;
+
+    bincode::deserialize(data).ok()
+}
+
+/// A filesystem tree, where each directory is stored as it's own [Object].
+///
+/// Directories are only loaded once a path inside of them is visited, and storing the tree only rewrites the
+/// directories that were loaded, so an operation on a path costs as much as the depth of the path, rather than the
+/// size of the whole tree. Directories that are not visited are shared with the revision the tree was loaded from.
+pub struct Tree<'a> {
+    files: &'a Files,
+    root: Node,
+}
+
+impl<'a> Tree<'a> {
+    /// Load the root of a tree from the [Object] it is stored as
+    pub(super) fn load(
+        files: &'a Files,
+        object: Object,
+        timestamp: u128,
+    ) -> Result<Tree<'a>, Error> {
+        let root = files.load_dir(object, timestamp)?;
+
+        Ok(Tree { files, root })
+    }
+
+    /// Load every directory on the way to a given `path`, along with `path` itself
+    fn expand(&mut self, path: Path) -> Result<(), Error> {
+        for path in path.ancestors().chain([path]) {
+            let Some(node) = self.root.traverse_mut(path)? else {
+                // nothing further down to load
+                break;
+            };
+
+            if let Some(NodeData::Stored(object)) = node.data() {
+                *node = self.files.load_dir(*object, node.timestamp())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the [Node] at a given [Path]. If it is a directory, it's children may not have been loaded
+    pub fn traverse(&mut self, path: Path) -> Result<Option<&Node>, Error> {
+        self.expand(path)?;
+        self.root.traverse(path)
+    }
+
+    /// Returns the [Node] at a given [Path], along with everything below it
+    pub fn traverse_all(&mut self, path: Path) -> Result<Option<&Node>, Error> {
+        self.expand(path)?;
+
+        if let Some(node) = self.root.traverse_mut(path)? {
+            self.files.load_all(node)?;
+        }
+
+        self.root.traverse(path)
+    }
+
+    /// Make a directory at a given [Path]. See [Node::make_dir] for details
+    pub fn make_dir(&mut self, path: Path) -> Result<(), Error> {
+        self.expand(path)?;
+        self.root.make_dir(path)
+    }
+
+    /// Recursively make new directories from a given [Path]
+    pub fn make_dir_recursive(&mut self, path: Path) -> Result<(), Error> {
+        self.expand(path)?;
+        self.root.make_dir_recursive(path)
+    }
+
+    /// Creates a new file at a given [Path], referencing an [Object]
+    pub fn insert(&mut self, path: Path, object: Object) -> Result<(), Error> {
+        self.expand(path)?;
+        self.root.insert(path, object)
+    }
+
+    pub fn delete(&mut self, path: Path) -> Result<(), Error> {
+        self.expand(path)?;
+        self.root.delete(path)
+    }
+
+    /// Link `node` at `to`, which may have come from another revision of the tree. See [Node::link] for details
+    pub fn link(&mut self, to: Path, node: Node) -> Result<(), Error> {
+        self.expand(to)?;
+        self.root.link(to, node)
+    }
+
+    /// Move the [Node] at `from` to `to`. See [Node::rename] for details
+    pub fn rename(&mut self, from: Path, to: Path) -> Result<(), Error> {
+        self.expand(from)?;
+        self.expand(to)?;
+        self.root.rename(from, to)
+    }
+
+    /// Remove the tombstones at or below a given [Path] that were deleted before a given `time`. See [Node::purge]
+    pub fn purge(&mut self, path: Path, time: u128) -> Result<usize, Error> {
+        self.expand(path)?;
+
+        let Some(node) = self.root.traverse_mut(path)? else {
+            return Ok(0);
+        };
+
+        // `path` may be a tombstone itself, which is listed by [Files::tombstones] like any other
+        if node.data().is_none() {
+            if node.timestamp() >= time {
+                return Ok(0);
+            }
+
+            let (parent, Some(name)) = path.parent_child() else {
+                return Ok(0);
+            };
+
+            let removed = self
+                .root
+                .traverse_mut(parent)?
+                .and_then(|parent| parent.dir_mut())
+                .and_then(|map| map.remove(name));
+
+            return Ok(removed.map_or(0, |_| 1));
+        }
+
+        self.files.load_all(node)?;
+
+        Ok(node.purge(time))
+    }
+
+    /// Store every directory that was loaded, returning the [Object] of the root
+    pub(super) fn store(&self) -> Result<Object, Error> {
+        self.files.store_dir(&self.root)
+    }
+}
+
+impl Files {
+    /// Load a directory from the [Object] it is stored as. It's children that are directories are left unloaded
+    fn load_dir(&self, object: Object, timestamp: u128) -> Result<Node, Error> {
+        let data = self.load(&object)?;
+        let entries = decode(&data).ok_or(Error::NotADirectory)?;
+
+        let children = entries
+            .into_iter()
+            .map(|(name, entry)| {
+                let data = entry.data.map(|data| match data {
+                    EntryData::Dir(object) => NodeData::Stored(object),
+                    EntryData::File(object) => NodeData::File(object),
+                });
+
+                (name, Node::from_parts(data, entry.timestamp))
+            })
+            .collect();
+
+        Ok(Node::from_parts(Some(NodeData::Dir(children)), timestamp))
+    }
+
+    /// Load every directory below a given `node`
+    fn load_all(&self, node: &mut Node) -> Result<(), Error> {
+        if let Some(NodeData::Stored(object)) = node.data() {
+            *node = self.load_dir(*object, node.timestamp())?;
+        }
+
+        if let Some(children) = node.dir_mut() {
+            for child in children.values_mut() {
+                self.load_all(child)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Store a directory, and any of it's children that have been loaded, returning the [Object] it is stored as
+    fn store_dir(&self, node: &Node) -> Result<Object, Error> {
+        let children = node.dir().ok_or(Error::NotADirectory)?;
+        let mut entries = BTreeMap::new();
+
+        for (name, child) in children {
+            let data = match child.data() {
+                Some(NodeData::Dir(_)) => Some(EntryData::Dir(self.store_dir(child)?)),
+                Some(NodeData::Stored(object)) => Some(EntryData::Dir(*object)),
+                Some(NodeData::File(object)) => Some(EntryData::File(*object)),
+                None => None,
+            };
+
+            let entry = Entry {
+                data,
+                timestamp: child.timestamp(),
+            };
+
+            entries.insert(name.as_str(), entry);
+        }
+
+        self.create_object(encode(&entries))
+    }
+
+    /// Store an empty directory, to be used as the root of a new tree
+    pub(super) fn store_empty_dir(&self) -> Result<Object, Error> {
+        self.store_dir(&Node::new_dir())
+    }
+
+    /// List the children of a stored directory that have not been deleted, along with the objects they reference
+    pub(super) fn read_dir(&self, object: &Object) -> Result<Vec<(String, EntryData)>, Error> {
+        let data = self.load(object)?;
+        let entries = decode(&data).ok_or(Error::NotADirectory)?;
+
+        let children = entries
+            .into_iter()
+            .filter_map(|(name, entry)| Some((name, entry.data?)))
+            .collect();
+
+        Ok(children)
+    }
+
+    /// Convert every revision of every root, and every tagged revision, that is stored as a single serialized [Node]
+    /// into a tree of directories. Older versions of `ksync` serialized a directory's children in an arbitrary order, so
+    /// identical trees could be stored as different objects; converted trees are stored in canonical order, so they hash
+    /// identically again. The old objects are left to be collected
+    pub(super) fn migrate_trees(&self) -> Result<(), Error> {
+        let _guard = self.collector.write();
+
+        // a root may share it's trees between revisions
+        let mut converted: HashMap<Object, Object> = HashMap::new();
+
+        // nothing has a header yet, until a later migration gives it one
+        for entry in self.roots.iter() {
+            let (root, history) = entry?;
+            let mut history: RootHistory = bincode::deserialize(&self.keys.open_legacy(&history)?)?;
+            let mut changed = false;
+
+            for (_, object) in history.iter_mut() {
+                let new = self.migrate_tree(*object, &mut converted)?;
+
+                changed |= new != *object;
+                *object = new;
+            }
+
+            if changed {
+                log::info!(
+                    "migrated root '{}' to a tree of directories",
+                    String::from_utf8_lossy(&root)
+                );
+
+                self.roots.insert(&root, self.encode_history(&history)?)?;
+            }
+        }
+
+        for entry in self.tags.iter() {
+            let (tree, tags) = entry?;
+            let mut tags: Tags = bincode::deserialize(&self.keys.open_legacy(&tags)?)?;
+            let mut changed = false;
+
+            for (_, object) in tags.values_mut() {
+                let new = self.migrate_tree(*object, &mut converted)?;
+
+                changed |= new != *object;
+                *object = new;
+            }
+
+            if changed {
+                self.tags.insert(&tree, self.encode_tags(&tags)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Convert a revision stored as a single serialized [Node] into a tree of directories, returning the [Object] it
+    /// is now stored as
+    fn migrate_tree(
+        &self,
+        object: Object,
+        converted: &mut HashMap<Object, Object>,
+    ) -> Result<Object, Error> {
+        if let Some(new) = converted.get(&object) {
+            return Ok(*new);
+        }
+
+        let data = self.load_legacy(&object)?;
+
+        if decode(&data).is_some() {
+            return Ok(object);
+        }
+
+        let node: Node = bincode::deserialize(&data)?;
+        let new = self.store_dir(&node)?;
+
+        converted.insert(object, new);
+
+        Ok(new)
+    }
+
+    /// Store every file that an older version of `ksync` stored as a single object as chunks and a
+    /// [super::chunk::Manifest], in every revision of every root, and every tagged revision. The directories above
+    /// them are rewritten, so each revision is stored as a new object, and the old ones are left to be collected
+    pub(super) fn migrate_files(&self) -> Result<(), Error> {
+        let _guard = self.collector.write();
+
+        // files, directories and whole revisions are shared between revisions and roots
+        let mut converted: HashMap<Object, Object> = HashMap::new();
+
+        for entry in self.roots.iter() {
+            let (root, history) = entry?;
+            let mut history = self.decode_history(&history)?;
+            let mut changed = false;
+
+            for (_, object) in history.iter_mut() {
+                let new = self.migrate_dir_files(*object, &mut converted)?;
+
+                changed |= new != *object;
+                *object = new;
+            }
+
+            if changed {
+                log::info!(
+                    "migrated the files of root '{}' to chunks",
+                    String::from_utf8_lossy(&root)
+                );
+
+                self.roots.insert(&root, self.encode_history(&history)?)?;
+            }
+        }
+
+        for entry in self.tags.iter() {
+            let (tree, tags) = entry?;
+            let mut tags = self.decode_tags(Some(tags))?;
+            let mut changed = false;
+
+            for (_, object) in tags.values_mut() {
+                let new = self.migrate_dir_files(*object, &mut converted)?;
+
+                changed |= new != *object;
+                *object = new;
+            }
+
+            if changed {
+                self.tags.insert(&tree, self.encode_tags(&tags)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Upgrade the files below a stored directory with [Files::upgrade_file], returning the [Object] the directory is
+    /// now stored as
+    fn migrate_dir_files(
+        &self,
+        object: Object,
+        converted: &mut HashMap<Object, Object>,
+    ) -> Result<Object, Error> {
+        if let Some(new) = converted.get(&object) {
+            return Ok(*new);
+        }
+
+        let mut entries = decode(&self.load(&object)?).ok_or(Error::NotADirectory)?;
+        let mut changed = false;
+
+        for entry in entries.values_mut() {
+            let (old, new) = match entry.data {
+                Some(EntryData::Dir(dir)) => {
+                    let new = self.migrate_dir_files(dir, converted)?;
+                    entry.data = Some(EntryData::Dir(new));
+
+                    (dir, new)
+                }
+                Some(EntryData::File(file)) => {
+                    let new = match converted.get(&file) {
+                        Some(new) => *new,
+                        None => self.upgrade_file(file)?,
+                    };
+
+                    converted.insert(file, new);
+                    entry.data = Some(EntryData::File(new));
+
+                    (file, new)
+                }
+                None => continue,
+            };
+
+            changed |= new != old;
+        }
+
+        let new = if changed {
+            let entries = entries
+                .iter()
+                .map(|(name, entry)| (name.as_str(), *entry))
+                .collect();
+
+            self.create_object(encode(&entries))?
+        } else {
+            object
+        };
+
+        converted.insert(object, new);
+
+        Ok(new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::{tests, Revision, DEFAULT_TREE};
+
+    /// The directories of the latest revision of the filesystem
+    fn dirs(files: &Files) -> Vec<Object> {
+        let (_, root) = *files.get_history(DEFAULT_TREE).unwrap().last().unwrap();
+        let mut dirs = vec![root];
+        let mut i = 0;
+
+        while let Some(dir) = dirs.get(i) {
+            let children = files.read_dir(dir).unwrap();
+
+            dirs.extend(children.into_iter().filter_map(|(_, child)| match child {
+                EntryData::Dir(object) => Some(object),
+                EntryData::File(_) => None,
+            }));
+            i += 1;
+        }
+
+        dirs
+    }
+
+    #[test]
+    fn unchanged_directories_are_shared() {
+        let files = tests::open();
+
+        files.insert(Path::new("/a/1").unwrap(), b"one").unwrap();
+        files.insert(Path::new("/b/2").unwrap(), b"two").unwrap();
+        let before = dirs(&files);
+
+        files.insert(Path::new("/a/3").unwrap(), b"three").unwrap();
+        let after = dirs(&files);
+
+        // the root and `/a` are rewritten, `/b` is shared
+        assert_eq!(before.len(), 3);
+        assert_eq!(after.len(), 3);
+        assert_eq!(before.iter().filter(|dir| after.contains(dir)).count(), 1);
+
+        let path = Path::new("/b/2").unwrap();
+        assert_eq!(
+            files.get(path, Revision::FromLatest(0)).unwrap().as_deref(),
+            Some(&b"two"[..])
+        );
+    }
+
+    #[test]
+    fn whole_nodes_are_loaded() {
+        let files = tests::open();
+
+        files.insert(Path::new("/a/b/c").unwrap(), b"deep").unwrap();
+        files
+            .delete(DEFAULT_TREE, Path::new("/a/b/c").unwrap(), None)
+            .unwrap();
+        files
+            .insert(Path::new("/a/d").unwrap(), b"shallow")
+            .unwrap();
+
+        let node = files
+            .get_node(
+                DEFAULT_TREE,
+                Path::new("/").unwrap(),
+                Revision::FromLatest(0),
+            )
+            .unwrap()
+            .unwrap();
+
+        let mut paths: Vec<_> = node.iter().map(|(path, _)| path).collect();
+        paths.sort();
+
+        assert_eq!(paths, ["/", "/a", "/a/b", "/a/b/c", "/a/d"]);
+    }
+
+    #[test]
+    fn serialized_trees_are_migrated() {
+        let files = tests::open();
+
+        let file = files.write_file(b"old").unwrap();
+        let mut node = Node::new_dir();
+        node.make_dir(Path::new("/old").unwrap()).unwrap();
+        node.insert(Path::new("/old/file").unwrap(), file).unwrap();
+
+        let object = files
+            .create_object(bincode::serialize(&node).unwrap())
+            .unwrap();
+        files.roots.merge("fs", object.hash()).unwrap();
+
+        files.migrate_trees().unwrap();
+
+        let path = Path::new("/old/file").unwrap();
+        assert_eq!(
+            files.get(path, Revision::FromLatest(0)).unwrap().as_deref(),
+            Some(&b"old"[..])
+        );
+
+        // the old tree is no longer referenced
+        files.collect_garbage().unwrap();
+        assert!(files.objects.get(&object).unwrap().is_none());
+    }
+
+    #[test]
+    fn identical_trees_hash_identically() {
+        let files = tests::open();
+        let file = files.write_file(b"same").unwrap();
+
+        let tree = |names: &[&str]| {
+            let mut node = Node::from_parts(Some(NodeData::Dir(Default::default())), 0);
+
+            for name in names {
+                let child = Node::from_parts(Some(NodeData::File(file)), 1);
+                node.insert_child(name, child).unwrap();
+            }
+
+            node
+        };
+
+        let forwards = tree(&["a", "b", "c", "d"]);
+        let backwards = tree(&["d", "c", "b", "a"]);
+
+        assert_eq!(
+            bincode::serialize(&forwards).unwrap(),
+            bincode::serialize(&backwards).unwrap()
+        );
+        assert_eq!(
+            files.store_dir(&forwards).unwrap(),
+            files.store_dir(&backwards).unwrap()
+        );
+    }
+
+    /// The layout of a [Node] as serialized by older versions of `ksync`, where children were kept in a `HashMap` and
+    /// serialized in whatever order it iterated them
+    #[derive(Serialize)]
+    struct LegacyNode {
+        data: Option<LegacyData>,
+        timestamp: u128,
+    }
+
+    #[derive(Serialize)]
+    enum LegacyData {
+        Dir(Vec<(String, LegacyNode)>),
+    }
+
+    #[test]
+    fn unordered_trees_are_canonicalised() {
+        let files = tests::open();
+
+        let legacy = |names: &[&str]| {
+            let children = names
+                .iter()
+                .map(|name| {
+                    let child = LegacyNode {
+                        data: Some(LegacyData::Dir(vec![])),
+                        timestamp: 1,
+                    };
+
+                    (name.to_string(), child)
+                })
+                .collect();
+
+            let root = LegacyNode {
+                data: Some(LegacyData::Dir(children)),
+                timestamp: 0,
+            };
+
+            files
+                .create_object(bincode::serialize(&root).unwrap())
+                .unwrap()
+        };
+
+        let forwards = legacy(&["x", "y", "z"]);
+        let backwards = legacy(&["z", "y", "x"]);
+        assert_ne!(forwards, backwards);
+
+        files.roots.merge("fs", forwards.hash()).unwrap();
+        files.roots.merge("fs", backwards.hash()).unwrap();
+
+        files.migrate_trees().unwrap();
+
+        let history = files.get_history(DEFAULT_TREE).unwrap();
+        let (_, forwards) = history[history.len() - 2];
+        let (_, backwards) = history[history.len() - 1];
+
+        assert_eq!(forwards, backwards);
+    }
+}
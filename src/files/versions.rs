@@ -0,0 +1,226 @@
+use chrono::TimeZone;
+use serde::{Deserialize, Serialize};
+
+use crate::files::{tree::EntryData, Error, Files, Node, Object, Path, Revision};
+
+/// What was at a path in a revision of a tree
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionData {
+    File(Object),
+    /// A directory, along with the [Object] it is stored as
+    Dir(Object),
+    /// Nothing was at the path, or it had been deleted
+    Deleted,
+}
+
+/// A version of a path, as it was from a revision of a tree until the next version
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    /// The index of the revision in the tree's history, from the earliest, i.e. `#N`
+    pub revision: usize,
+    /// When the revision was created
+    pub timestamp: u128,
+    pub data: VersionData,
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let date = chrono::Local.timestamp_nanos(self.timestamp as i64);
+
+        write!(f, "#{} {date} ", self.revision)?;
+
+        match self.data {
+            VersionData::File(object) => write!(f, "file {object:?}"),
+            VersionData::Dir(object) => write!(f, "directory {object:?}"),
+            VersionData::Deleted => write!(f, "deleted"),
+        }
+    }
+}
+
+// public helpers
+impl Files {
+    /// List every distinct version of a given `path` in a `tree`, from the earliest. A new version is listed each time
+    /// the path is created, changed or deleted
+    pub fn log(&self, tree: &str, path: Path) -> Result<Vec<Version>, Error> {
+        Files::check_tree_name(tree)?;
+
+        log::debug!("listing versions of {path} in tree '{tree}'");
+
+        let history = self.get_root_history(tree)?;
+        let mut versions: Vec<Version> = vec![];
+        let mut previous = None;
+
+        for (revision, (timestamp, root)) in history.into_iter().enumerate() {
+            // revisions are often rolled back to, so the same root may appear more than once in a row
+            if previous == Some(root) {
+                continue;
+            }
+
+            previous = Some(root);
+
+            let data = match self.resolve(root, path)? {
+                Some(EntryData::File(object)) => VersionData::File(object),
+                Some(EntryData::Dir(object)) => VersionData::Dir(object),
+                None => VersionData::Deleted,
+            };
+
+            let changed = match versions.last() {
+                Some(version) => version.data != data,
+                // the path didn't exist before it was first created
+                None => data != VersionData::Deleted,
+            };
+
+            if changed {
+                versions.push(Version {
+                    revision,
+                    timestamp,
+                    data,
+                });
+            }
+        }
+
+        Ok(versions)
+    }
+
+    /// Bring back the file or directory at `path` as it was in a given `revision` of a `tree`, replacing whatever is at
+    /// `path` now, as a single new revision. Nothing else in the tree is changed. If an `expected` revision is given,
+    /// the path is only restored if it is still the latest revision
+    pub fn restore(
+        &self,
+        tree: &str,
+        path: Path,
+        revision: Revision,
+        expected: Option<Object>,
+    ) -> Result<(), Error> {
+        Files::check_tree_name(tree)?;
+
+        log::debug!("restoring {path} @ {revision:?} in tree '{tree}'");
+
+        // as with a copy, the old revision may be pruned before the restored node is linked
+        let _guard = self.collector.write();
+
+        let node = self.with_root(tree, revision, |tree| {
+            let node = tree.traverse(path)?.filter(|node| node.data().is_some());
+
+            Ok(node.cloned())
+        })?;
+
+        let node = node.ok_or_else(|| Error::NotFound {
+            path: path.as_str().to_owned(),
+        })?;
+
+        self.restore_node(tree, path, node, expected)
+    }
+}
+
+// filesystem internals
+impl Files {
+    /// Link a `node` from an older revision at `path` as a new revision, replacing whatever is at `path` now. The caller
+    /// must hold a [gc::WriteGuard](crate::files::gc::WriteGuard) from before `node` was found
+    pub(super) fn restore_node(
+        &self,
+        tree: &str,
+        path: Path,
+        node: Node,
+        expected: Option<Object>,
+    ) -> Result<(), Error> {
+        let (parent, _) = path.parent_child();
+
+        self.with_root_mut_from(tree, expected, |tree| {
+            tree.make_dir_recursive(parent)?;
+
+            if tree
+                .traverse(path)?
+                .is_some_and(|node| node.data().is_some())
+            {
+                tree.delete(path)?;
+            }
+
+            tree.link(path, node.clone())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VersionData;
+    use crate::files::{tests, Path, Revision, DEFAULT_TREE};
+
+    #[test]
+    fn versions_are_listed() {
+        let files = tests::open();
+        let path = Path::new("/passwords.kdbx").unwrap();
+
+        files.insert(path, b"v1").unwrap();
+        files
+            .insert(Path::new("/other").unwrap(), b"other")
+            .unwrap();
+        files.insert(path, b"v2").unwrap();
+        files.insert(path, b"v2").unwrap();
+        files.delete(DEFAULT_TREE, path, None).unwrap();
+        files.insert(path, b"v3").unwrap();
+
+        let versions = files.log(DEFAULT_TREE, path).unwrap();
+        let kinds: Vec<_> = versions
+            .iter()
+            .map(|version| match version.data {
+                VersionData::File(_) => "file",
+                VersionData::Dir(_) => "dir",
+                VersionData::Deleted => "deleted",
+            })
+            .collect();
+
+        // inserting the same contents again isn't a new version
+        assert_eq!(kinds, ["file", "file", "deleted", "file"]);
+        assert_eq!(
+            versions.iter().map(|v| v.revision).collect::<Vec<_>>(),
+            [1, 3, 5, 6]
+        );
+
+        // each version can be read back by it's revision
+        let v2 = files
+            .get(path, Revision::FromEarliest(versions[1].revision))
+            .unwrap();
+        assert_eq!(v2.unwrap(), b"v2");
+    }
+
+    #[test]
+    fn restore_leaves_other_changes() {
+        let files = tests::open();
+        let path = Path::new("/passwords.kdbx").unwrap();
+        let other = Path::new("/dir/other").unwrap();
+
+        files.insert(path, b"good").unwrap();
+        files.insert(path, b"overwritten").unwrap();
+        files.insert(other, b"other").unwrap();
+
+        files
+            .restore(DEFAULT_TREE, path, Revision::FromLatest(2), None)
+            .unwrap();
+
+        let get = |path| files.get(path, Revision::FromLatest(0)).unwrap();
+
+        assert_eq!(get(path).unwrap(), b"good");
+        assert_eq!(get(other).unwrap(), b"other");
+
+        // restore a whole directory, after it was deleted
+        files
+            .delete(DEFAULT_TREE, Path::new("/dir").unwrap(), None)
+            .unwrap();
+        files
+            .restore(
+                DEFAULT_TREE,
+                Path::new("/dir").unwrap(),
+                Revision::FromLatest(1),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(get(other).unwrap(), b"other");
+
+        // nothing was at the path in the revision
+        assert!(files
+            .restore(DEFAULT_TREE, other, Revision::FromEarliest(0), None)
+            .is_err());
+    }
+}
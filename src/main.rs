@@ -1,9 +1,8 @@
-#![feature(io_error_more, async_fn_in_trait)]
-
 mod admin;
 mod cli;
 mod client;
 mod config;
+mod encryption;
 mod files;
 mod proto;
 mod server;
@@ -43,6 +42,10 @@ enum Command {
         #[arg(short, long)]
         remote: Option<SocketAddr>,
 
+        /// The filesystem tree to operate on
+        #[arg(long, default_value = files::DEFAULT_TREE)]
+        tree: String,
+
         #[command(subcommand)]
         method: cli::Method,
     },
@@ -70,7 +73,8 @@ async fn main() -> anyhow::Result<()> {
 
     let config = config::load_config(args.config).await?;
 
-    println!("{config:?}");
+    // logged rather than printed, as `get` and `export` may be writing to stdout
+    log::debug!("{config:?}");
 
     match args.command {
         Command::Daemon => {
@@ -103,6 +107,7 @@ async fn main() -> anyhow::Result<()> {
         Command::Cli {
             key,
             remote,
+            tree,
             method,
         } => {
             let key = if let Some(key) = key {
@@ -121,11 +126,13 @@ async fn main() -> anyhow::Result<()> {
                 None
             };
 
-            cli::invoke(key, remote, method).await?;
+            let encryption = config.client.and_then(|config| config.encryption);
+
+            cli::invoke(key, remote, encryption, &tree, method).await?;
         }
 
         Command::Admin { command } => {
-            admin::admin_cli(command)?;
+            admin::admin_cli(command, config.server)?;
         }
     }
 
@@ -1,10 +1,11 @@
 use std::io;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::{files::Files, server::Context};
+use crate::files::{self, Conflict, Files};
+use crate::server::Context;
 
 /// Reads exactly `N` bytes from a given `reader`, and returns it as an array
 pub async fn read_array<const N: usize, R: AsyncReadExt + Unpin>(
@@ -57,15 +58,99 @@ pub async fn write_int<W: AsyncWriteExt + Unpin>(writer: &mut W, val: u64) -> io
 /// Writes a `length, bytes...` encoded list of bytes to a given `writer`
 pub async fn write_data<W: AsyncWriteExt + Unpin>(writer: &mut W, data: &[u8]) -> io::Result<()> {
     write_int(writer, data.len() as u64).await?;
-    writer.write_all(&data).await?;
+    writer.write_all(data).await?;
 
     Ok(())
 }
 
+/// The size of the frames a stream is split into when sending it
+pub const FRAME_SIZE: usize = 256 * 1024;
+
+/// The largest frame that will be accepted while receiving a stream
+pub const MAX_FRAME: usize = 1024 * 1024;
+
+/// Writes a single frame of a stream to a given `writer`. An empty frame marks the end of a stream
+pub async fn write_frame<W: AsyncWriteExt + Unpin>(writer: &mut W, frame: &[u8]) -> io::Result<()> {
+    write_data(writer, frame).await
+}
+
+/// Reads a single frame of a stream from a given `reader`, refusing any frame larger than [MAX_FRAME]
+pub async fn read_frame<R: AsyncReadExt + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_int(reader).await? as usize;
+
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            Error::FrameTooLarge { len },
+        ));
+    }
+
+    let mut buf = vec![0; len];
+
+    reader.read_exact(&mut buf).await?;
+
+    Ok(buf)
+}
+
+/// Sends the contents of `reader` down a given `writer` as a stream of frames, so that it never has to be held in
+/// memory all at once. Returns the number of bytes sent
+pub async fn send_stream<R, W>(writer: &mut W, reader: &mut R) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let mut buf = vec![0; FRAME_SIZE];
+    let mut total = 0;
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+
+        if n == 0 {
+            break;
+        }
+
+        write_frame(writer, &buf[..n]).await?;
+        total += n as u64;
+    }
+
+    write_frame(writer, &[]).await?;
+    writer.flush().await?;
+
+    Ok(total)
+}
+
+/// Receives a stream of frames from a given `reader`, writing their contents to `writer`. Returns the number of bytes
+/// received
+pub async fn recv_stream<R, W>(reader: &mut R, writer: &mut W) -> io::Result<u64>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut total = 0;
+
+    loop {
+        let frame = read_frame(reader).await?;
+
+        if frame.is_empty() {
+            break;
+        }
+
+        writer.write_all(&frame).await?;
+        total += frame.len() as u64;
+    }
+
+    writer.flush().await?;
+
+    Ok(total)
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("invalid protocol '{proto:?}'")]
     InvalidProtocol { proto: [u8; 8] },
+
+    #[error("frame of {len} bytes exceeds the maximum frame size")]
+    FrameTooLarge { len: usize },
     // #[error("unknown method '{method}'")]
     // UnknownMethod { method: String }
 }
@@ -103,7 +188,7 @@ pub async fn read_packet<R: AsyncReadExt + Unpin>(reader: &mut R) -> io::Result<
             if &proto != b"ksync\0\0\0" {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
-                    Error::InvalidProtocol { proto: proto },
+                    Error::InvalidProtocol { proto },
                 ));
             }
 
@@ -140,6 +225,23 @@ pub async fn write_packet<W: AsyncWriteExt + Unpin, T: Serialize>(
     Ok(())
 }
 
+/// Writes an error response to a given `writer`. A [Conflict] is sent as a `CONFLICT` packet rather than as a message,
+/// so that the client can tell it apart from other errors
+pub async fn write_error<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    error: &anyhow::Error,
+) -> anyhow::Result<()> {
+    let conflict = match error.downcast_ref::<files::Error>() {
+        Some(files::Error::Conflict { conflict }) => Some(conflict),
+        _ => error.downcast_ref::<Conflict>(),
+    };
+
+    match conflict {
+        Some(conflict) => write_packet(writer, "CONFLICT", conflict).await,
+        None => write_packet(writer, "ERR", error.to_string()).await,
+    }
+}
+
 /// The [Method] trait is used to implement different methods of the protocol (e.g. `GET`, `INSERT`, etc.)
 /// This trait is used to automatically convert to/from bincode over the wire
 pub trait Method: Send + Sync + 'static {
@@ -198,6 +300,13 @@ pub async fn invoke<'a, M: Method, S: AsyncReadExt + AsyncWriteExt + Unpin>(
     // send method call to server
     write_packet(stream, M::NAME, input).await?;
 
+    read_response(stream).await
+}
+
+/// Reads a response from the server, returning the result of the call
+pub async fn read_response<T: DeserializeOwned, S: AsyncReadExt + Unpin>(
+    stream: &mut S,
+) -> anyhow::Result<T> {
     // read response from server
     let response = read_packet(stream).await?.ok_or({
         let err: io::Error = io::ErrorKind::UnexpectedEof.into();
@@ -208,8 +317,70 @@ pub async fn invoke<'a, M: Method, S: AsyncReadExt + AsyncWriteExt + Unpin>(
     if response.method == "OK" {
         let result = bincode::deserialize(&response.data)?;
         Ok(result)
+    } else if response.method == "CONFLICT" {
+        // returned as a Conflict, so that callers can downcast to it
+        let conflict: Conflict = bincode::deserialize(&response.data)?;
+        Err(conflict.into())
     } else {
         let err: &str = bincode::deserialize(&response.data)?;
-        Err(io::Error::new(io::ErrorKind::Other, err).into())
+        Err(io::Error::other(err).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn streams_round_trip() {
+        let data = crate::files::tests::data(5, 3 * FRAME_SIZE + 17);
+        let mut wire = vec![];
+
+        let sent = send_stream(&mut wire, &mut &data[..]).await.unwrap();
+        assert_eq!(sent, data.len() as u64);
+
+        let mut received = vec![];
+        let len = recv_stream(&mut &wire[..], &mut received).await.unwrap();
+
+        assert_eq!(len, data.len() as u64);
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test]
+    async fn conflicts_are_received_as_conflicts() {
+        let object = crate::files::Object::of(b"a");
+
+        let conflict = Conflict {
+            tree: "fs".to_owned(),
+            expected: object,
+            actual: crate::files::Object::of(b"b"),
+        };
+
+        let mut wire = vec![];
+        write_error(&mut wire, &files::Error::from(conflict).into())
+            .await
+            .unwrap();
+        write_error(&mut wire, &anyhow::anyhow!("something else"))
+            .await
+            .unwrap();
+
+        let mut wire = &wire[..];
+
+        let err = read_response::<(), _>(&mut wire).await.unwrap_err();
+        let conflict = err.downcast_ref::<Conflict>().unwrap();
+        assert_eq!(conflict.expected, object);
+
+        let err = read_response::<(), _>(&mut wire).await.unwrap_err();
+        assert!(err.downcast_ref::<Conflict>().is_none());
+        assert_eq!(err.to_string(), "something else");
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused() {
+        let mut wire = vec![];
+        write_int(&mut wire, MAX_FRAME as u64 + 1).await.unwrap();
+
+        let err = read_frame(&mut &wire[..]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
     }
 }
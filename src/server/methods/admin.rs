@@ -1,6 +1,6 @@
-use crate::files::{crypto, Files};
+use crate::files::{crypto, fsck::FsckReport, gc::GcReport, prune::PruneReport, Files};
 use crate::proto::Method;
-use crate::server::{methods::auth, Context};
+use crate::server::{self, methods::auth, Context};
 
 pub struct Configure;
 
@@ -26,3 +26,84 @@ impl Method for Configure {
         Ok(())
     }
 }
+
+/// Remove every object in the database that is no longer referenced by any revision of any root. The collection is ran
+/// on a blocking thread once the method returns, after which a [GcReport] is sent
+pub struct CollectGarbage;
+
+impl Method for CollectGarbage {
+    type Input<'a> = ();
+    type Output = ();
+
+    const NAME: &'static str = "COLLECT_GARBAGE";
+
+    fn call<'a>(_: &Files, ctx: &mut Context, _: Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        log::info!("client {} collecting garbage", ctx.addr());
+
+        ctx.run_blocking(|files| {
+            let report: GcReport = files.collect_garbage()?;
+
+            Ok(bincode::serialize(&report)?)
+        });
+
+        Ok(())
+    }
+}
+
+/// Drop the revisions of every filesystem tree that are not kept by the server's retention policy. Pruning is ran on a
+/// blocking thread once the method returns, after which a [PruneReport] is sent
+pub struct Prune;
+
+impl Method for Prune {
+    type Input<'a> = ();
+    type Output = ();
+
+    const NAME: &'static str = "PRUNE";
+
+    fn call<'a>(_: &Files, ctx: &mut Context, _: Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        log::info!("client {} pruning history", ctx.addr());
+
+        let policy = ctx
+            .config()
+            .retention
+            .clone()
+            .ok_or(server::Error::NoRetention)?;
+
+        ctx.run_blocking(move |files| {
+            let report: PruneReport = files.prune(&policy)?;
+
+            Ok(bincode::serialize(&report)?)
+        });
+
+        Ok(())
+    }
+}
+
+/// Check the integrity of the database. The check is ran on a blocking thread once the method returns, after which a
+/// [FsckReport] is sent
+pub struct Fsck;
+
+impl Method for Fsck {
+    type Input<'a> = ();
+    type Output = ();
+
+    const NAME: &'static str = "FSCK";
+
+    fn call<'a>(_: &Files, ctx: &mut Context, _: Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        log::info!("client {} checking database integrity", ctx.addr());
+
+        ctx.run_blocking(|files| {
+            let report: FsckReport = files.fsck()?;
+
+            Ok(bincode::serialize(&report)?)
+        });
+
+        Ok(())
+    }
+}
+
+pub fn register(ctx: &mut Context) {
+    ctx.register(&CollectGarbage);
+    ctx.register(&Prune);
+    ctx.register(&Fsck);
+}
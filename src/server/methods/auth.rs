@@ -2,8 +2,6 @@ use crate::proto::Method;
 use crate::files::{Files, crypto};
 use crate::server::Context;
 
-use std::io;
-
 pub struct Identify;
 
 impl Method for Identify {
@@ -16,15 +14,19 @@ impl Method for Identify {
         log::info!("client {} identified with server", ctx.addr());
         ctx.deregister(&Identify);
 
-        if files.verify_client(&key)? {
+        if files.verify_admin(&key)? {
+            log::info!("registering filesystem and admin methods for client {}", ctx.addr());
+            super::fs::register(ctx);
+            super::admin::register(ctx);
+            Ok(())
+        } else if files.verify_client(&key)? {
             log::info!("registering filesystem methods for client {}", ctx.addr());
             super::fs::register(ctx);
             Ok(())
         } else {
             log::error!("client {} failed to identify; untrusted certificate", ctx.addr());
-            
-            let err: io::Error = io::ErrorKind::InvalidData.into();
-            Err(err.into())
+
+            Err(crypto::Error::UntrustedCertificate.into())
         }
     }
 }
\ No newline at end of file
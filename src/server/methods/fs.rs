@@ -1,29 +1,99 @@
+use std::collections::VecDeque;
 use std::io;
 
 use crate::proto::Method;
-use crate::files::{Files, Path, Revision, Node, RootHistory};
+use crate::files::{Conflict, Files, Object, Path, Revision, Node, RootHistory, TreeInfo};
+use crate::files::batch::{self, Operation};
+use crate::files::diff::Change;
+use crate::files::tags::TagInfo;
+use crate::files::tombstones::Tombstone;
+use crate::files::versions::Version;
+use crate::files::chunk::FileWriter;
 use crate::server::Context;
+use crate::server::transfer::{Sink, Source};
 
-/// The [Get] method resolves a virtual filesystem [Path] to it's respective object, loads it, and sends it back to the client
+/// Streams the chunks of a file to the client
+struct FileSource {
+    chunks: VecDeque<Object>,
+}
+
+impl Source for FileSource {
+    fn next(&mut self, files: &Files) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.chunks.pop_front() {
+            Some(chunk) => Ok(Some(files.read_chunk(&chunk)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Stores a file streamed from the client, and links it into the filesystem once it has been received
+struct FileSink {
+    tree: String,
+    path: String,
+    /// The revision the file is inserted into, if the client gave one
+    expected: Option<Object>,
+    writer: FileWriter,
+}
+
+impl Sink for FileSink {
+    fn write(&mut self, files: &Files, data: &[u8]) -> anyhow::Result<()> {
+        self.writer.write(files, data)?;
+
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>, files: &Files) -> anyhow::Result<Vec<u8>> {
+        let object = self.writer.finish(files)?;
+
+        files.insert_object(&self.tree, Path::new(&self.path)?, object, self.expected)?;
+
+        Ok(bincode::serialize(&())?)
+    }
+}
+
+/// Stores the files inserted by a batch as they are streamed from the client, and applies the batch once they have all
+/// been received
+struct BatchSink {
+    batch: batch::Batch,
+}
+
+impl Sink for BatchSink {
+    fn write(&mut self, files: &Files, data: &[u8]) -> anyhow::Result<()> {
+        self.batch.write(files, data)?;
+
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, files: &Files) -> anyhow::Result<Vec<u8>> {
+        self.batch.finish(files)?;
+
+        Ok(bincode::serialize(&())?)
+    }
+}
+
+/// The [Get] method resolves a virtual filesystem [Path] to it's respective object, and streams it back to the client.
+/// Responds with the size of the file, followed by it's contents
 pub struct Get;
 
 impl Method for Get {
-    type Input<'a> = Path<'a>;
-    type Output = Vec<u8>;
+    type Input<'a> = (&'a str, Path<'a>);
+    type Output = u64;
 
     const NAME: &'static str = "GET";
 
-    fn call<'a>(files: &Files, ctx: &mut Context, path: Self::Input<'a>) -> anyhow::Result<Self::Output> {
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, path): Self::Input<'a>) -> anyhow::Result<Self::Output> {
         let addr = ctx.addr();
 
-        log::info!("client {addr} retrieving file {path}");
+        log::info!("client {addr} retrieving file {path} from tree '{tree}'");
 
-        let data = files.get(path, Revision::FromLatest(0))?;
+        let manifest = files.get_manifest(tree, path, Revision::FromLatest(0))?;
 
-        if let Some(data) = data {
-            let data = &data[..];
+        if let Some(manifest) = manifest {
+            ctx.send(FileSource {
+                chunks: manifest.chunks().iter().copied().collect(),
+            });
 
-            Ok(data.to_owned())
+            Ok(manifest.size())
         } else {
             let err: io::Error = io::ErrorKind::NotFound.into();
             Err(err.into())
@@ -31,40 +101,148 @@ impl Method for Get {
     }
 }
 
-/// The [Insert] methods creates an object for a given piece of data, and inserts it into the filesystem at a given path
+/// Fails with a [Conflict] if an `expected` revision was given, and it is no longer the latest revision of a `tree`
+fn check_expected(files: &Files, tree: &str, expected: Option<Object>) -> anyhow::Result<()> {
+    let history = files.get_history(tree)?;
+
+    if let (Some(expected), Some(&(_, actual))) = (expected, history.last()) {
+        if expected != actual {
+            return Err(Conflict { tree: tree.to_owned(), expected, actual }.into());
+        }
+    }
+
+    Ok(())
+}
+
+/// The [Insert] method receives a file streamed from the client, and inserts it into the filesystem at a given path.
+/// If the client gives the revision it expects to insert the file into, the insert fails with a [Conflict] if the tree
+/// has changed since
 pub struct Insert;
 
 impl Method for Insert {
-    type Input<'a> = (Path<'a>, Vec<u8>);
+    type Input<'a> = (&'a str, Path<'a>, Option<Object>);
     type Output = ();
 
     const NAME: &'static str = "INSERT";
 
-    fn call<'a>(files: &Files, ctx: &mut Context, (path, data): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, path, expected): Self::Input<'a>) -> anyhow::Result<Self::Output> {
         let addr = ctx.addr();
 
-        log::info!("client {addr} storing file {path}");
-        
-        files.insert(path, &data)?;
+        log::info!("client {addr} storing file {path} in tree '{tree}'");
+
+        // check the tree exists and hasn't changed up front, rather than once the whole file has been received
+        check_expected(files, tree, expected)?;
+
+        ctx.receive(FileSink {
+            tree: tree.to_owned(),
+            path: path.as_str().to_owned(),
+            expected,
+            writer: files.file_writer(),
+        });
 
         Ok(())
     }
 }
 
+/// The [InsertObject] method links a file the server already has to a given path, by the [Object] of it's manifest,
+/// so the client doesn't have to send it again. Responds with whether the file was linked; if the server doesn't have
+/// the whole file, nothing is changed, and the client should [Insert] it instead. As with [Insert], the client may give
+/// the revision it expects to insert the file into
+pub struct InsertObject;
+
+impl Method for InsertObject {
+    type Input<'a> = (&'a str, Path<'a>, Object, Option<Object>);
+    type Output = bool;
+
+    const NAME: &'static str = "INSERT_OBJECT";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, path, object, expected): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} linking object {} to {path} in tree '{tree}'", object.hex());
+
+        Ok(files.link_file(tree, path, object, expected)?)
+    }
+}
+
+/// The [Have] method checks which of a list of files the server already has in whole, by the [Object]s of their
+/// manifests, so that they can be linked rather than sent again
+pub struct Have;
+
+impl Method for Have {
+    type Input<'a> = Vec<Object>;
+    type Output = Vec<bool>;
+
+    const NAME: &'static str = "HAVE";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, objects: Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} checking for {} objects", objects.len());
+
+        Ok(files.have(&objects)?)
+    }
+}
+
+/// The [Delete] method deletes the node at a given path. As with [Insert], the client may give the revision it expects
+/// to delete the node from
 pub struct Delete;
 
 impl Method for Delete {
-    type Input<'a> = Path<'a>;
+    type Input<'a> = (&'a str, Path<'a>, Option<Object>);
     type Output = ();
 
     const NAME: &'static str = "DELETE";
 
-    fn call<'a>(files: &Files, ctx: &mut Context, path: Self::Input<'a>) -> anyhow::Result<Self::Output> {
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, path, expected): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} deleting file {path} from tree '{tree}'");
+
+        files.delete(tree, path, expected)?;
+
+        Ok(())
+    }
+}
+
+/// The [Move] method moves (or renames) a file or directory to a new path, relinking it's existing contents rather than
+/// having the client upload them again. As with [Insert], the client may give the revision it expects to change
+pub struct Move;
+
+impl Method for Move {
+    type Input<'a> = (&'a str, Path<'a>, Path<'a>, Option<Object>);
+    type Output = ();
+
+    const NAME: &'static str = "MOVE";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, from, to, expected): Self::Input<'a>) -> anyhow::Result<Self::Output> {
         let addr = ctx.addr();
 
-        log::info!("client {addr} deleting file {path}");
+        log::info!("client {addr} moving {from} to {to} in tree '{tree}'");
 
-        files.delete(path)?;
+        files.rename(tree, from, to, expected)?;
+
+        Ok(())
+    }
+}
+
+/// The [Copy] method copies a file or directory, as it was in a given revision, to a new path in the latest revision.
+/// The copy shares it's contents with the original, so nothing is uploaded or duplicated. As with [Insert], the client
+/// may give the revision it expects to change
+pub struct Copy;
+
+impl Method for Copy {
+    type Input<'a> = (&'a str, Path<'a>, Revision, Path<'a>, Option<Object>);
+    type Output = ();
+
+    const NAME: &'static str = "COPY";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, from, revision, to, expected): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} copying {from} @ {revision:?} to {to} in tree '{tree}'");
+
+        files.copy(tree, from, revision, to, expected)?;
 
         Ok(())
     }
@@ -92,17 +270,17 @@ impl Method for Clear {
 pub struct Rollback;
 
 impl Method for Rollback {
-    type Input<'a> = Revision;
+    type Input<'a> = (&'a str, Revision);
     type Output = ();
 
     const NAME: &'static str = "ROLLBACK";
 
-    fn call<'a>(files: &Files, ctx: &mut Context, revision: Self::Input<'a>) -> anyhow::Result<Self::Output> {
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, revision): Self::Input<'a>) -> anyhow::Result<Self::Output> {
         let addr = ctx.addr();
 
-        log::info!("client {addr} rolling back filesystem to revision {:?}", revision);
+        log::info!("client {addr} rolling back tree '{tree}' to revision {:?}", revision);
 
-        files.rollback(revision)?;
+        files.rollback(tree, revision)?;
 
         Ok(())
     }
@@ -111,17 +289,17 @@ impl Method for Rollback {
 pub struct GetNode;
 
 impl Method for GetNode {
-    type Input<'a> = (Path<'a>, Revision);
+    type Input<'a> = (&'a str, Path<'a>, Revision);
     type Output = Node;
 
     const NAME: &'static str = "GET_NODE";
 
-    fn call<'a>(files: &Files, ctx: &mut Context, (path, revision): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, path, revision): Self::Input<'a>) -> anyhow::Result<Self::Output> {
         let addr = ctx.addr();
 
-        log::info!("client {addr} requested node {} @ {:?}", path.as_str(), revision);
+        log::info!("client {addr} requested node {} @ {:?} from tree '{tree}'", path.as_str(), revision);
 
-        if let Some(node) = files.get_node(path, revision)? {
+        if let Some(node) = files.get_node(tree, path, revision)? {
             Ok(node)
         } else {
             let err: io::Error = io::ErrorKind::NotFound.into();
@@ -133,28 +311,382 @@ impl Method for GetNode {
 pub struct GetHistory;
 
 impl Method for GetHistory {
-    type Input<'a> = ();
+    type Input<'a> = &'a str;
     type Output = RootHistory;
 
     const NAME: &'static str = "GET_HISTORY";
 
-    fn call<'a>(files: &Files, ctx: &mut Context, _: Self::Input<'a>) -> anyhow::Result<Self::Output> {
+    fn call<'a>(files: &Files, ctx: &mut Context, tree: Self::Input<'a>) -> anyhow::Result<Self::Output> {
         let addr = ctx.addr();
 
-        log::info!("client {addr} requested history for root 'root'");
+        log::info!("client {addr} requested history for tree '{tree}'");
 
-        let history = files.get_history()?;
+        let history = files.get_history(tree)?;
 
         Ok(history)
     }
 }
 
+/// The [Diff] method lists the files that differ below a given [Path], between two revisions of a tree
+pub struct Diff;
+
+impl Method for Diff {
+    type Input<'a> = (&'a str, Revision, Revision, Path<'a>);
+    type Output = Vec<Change>;
+
+    const NAME: &'static str = "DIFF";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, from, to, path): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} comparing {path} @ {from:?} to {to:?} in tree '{tree}'");
+
+        Ok(files.diff(tree, from, to, path)?)
+    }
+}
+
+/// The [Log] method lists every distinct version of a given [Path], from the earliest
+pub struct Log;
+
+impl Method for Log {
+    type Input<'a> = (&'a str, Path<'a>);
+    type Output = Vec<Version>;
+
+    const NAME: &'static str = "LOG";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, path): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} listing versions of {path} in tree '{tree}'");
+
+        Ok(files.log(tree, path)?)
+    }
+}
+
+/// The [Restore] method brings back a given [Path] as it was in an older revision, without changing anything else in
+/// the tree. As with [Insert], the client may give the revision it expects to change
+pub struct Restore;
+
+impl Method for Restore {
+    type Input<'a> = (&'a str, Path<'a>, Revision, Option<Object>);
+    type Output = ();
+
+    const NAME: &'static str = "RESTORE";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, path, revision, expected): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} restoring {path} @ {revision:?} in tree '{tree}'");
+
+        files.restore(tree, path, revision, expected)?;
+
+        Ok(())
+    }
+}
+
+/// The [ListTombstones] method lists the deleted files and directories below a given [Path]
+pub struct ListTombstones;
+
+impl Method for ListTombstones {
+    type Input<'a> = (&'a str, Path<'a>);
+    type Output = Vec<Tombstone>;
+
+    const NAME: &'static str = "LIST_TOMBSTONES";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, path): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} listing tombstones below {path} in tree '{tree}'");
+
+        Ok(files.tombstones(tree, path)?)
+    }
+}
+
+/// The [Undelete] method brings back a deleted [Path] with it's last contents, responding with the index of the
+/// revision they were found in. As with [Insert], the client may give the revision it expects to change
+pub struct Undelete;
+
+impl Method for Undelete {
+    type Input<'a> = (&'a str, Path<'a>, Option<Object>);
+    type Output = usize;
+
+    const NAME: &'static str = "UNDELETE";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, path, expected): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} undeleting {path} in tree '{tree}'");
+
+        Ok(files.undelete(tree, path, expected)?)
+    }
+}
+
+/// The [PurgeTombstones] method permanently removes the tombstones below a given [Path] that were deleted before a
+/// given time, responding with the number removed
+pub struct PurgeTombstones;
+
+impl Method for PurgeTombstones {
+    type Input<'a> = (&'a str, Path<'a>, u128, Option<Object>);
+    type Output = usize;
+
+    const NAME: &'static str = "PURGE_TOMBSTONES";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, path, time, expected): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} purging tombstones below {path} in tree '{tree}'");
+
+        Ok(files.purge_tombstones(tree, path, time, expected)?)
+    }
+}
+
+/// The [CreateTag] method pins a name to a given [Revision] of a tree, responding with the new tag
+pub struct CreateTag;
+
+impl Method for CreateTag {
+    type Input<'a> = (&'a str, &'a str, Revision);
+    type Output = TagInfo;
+
+    const NAME: &'static str = "CREATE_TAG";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, name, revision): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} tagging {revision} in tree '{tree}' as '{name}'");
+
+        Ok(files.create_tag(tree, name, revision)?)
+    }
+}
+
+/// The [ListTags] method lists the tags of a tree, in order of their names
+pub struct ListTags;
+
+impl Method for ListTags {
+    type Input<'a> = &'a str;
+    type Output = Vec<TagInfo>;
+
+    const NAME: &'static str = "LIST_TAGS";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, tree: Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} listing tags of tree '{tree}'");
+
+        Ok(files.list_tags(tree)?)
+    }
+}
+
+/// The [DeleteTag] method removes a tag from a tree, leaving the revision it pinned in history
+pub struct DeleteTag;
+
+impl Method for DeleteTag {
+    type Input<'a> = (&'a str, &'a str);
+    type Output = ();
+
+    const NAME: &'static str = "DELETE_TAG";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, name): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} deleting tag '{name}' from tree '{tree}'");
+
+        files.delete_tag(tree, name)?;
+
+        Ok(())
+    }
+}
+
+/// The [Import] method receives a tar (or `.tar.gz`) archive streamed from the client, and inserts it's contents under a
+/// given path as a single revision. Responds with the number of files imported once the archive has been received
+pub struct Import;
+
+impl Method for Import {
+    type Input<'a> = (&'a str, Path<'a>);
+    type Output = ();
+
+    const NAME: &'static str = "IMPORT";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, path): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} importing archive to {path} in tree '{tree}'");
+
+        // check the tree exists before the client sends the archive
+        files.get_history(tree)?;
+
+        let tree = tree.to_owned();
+        let path = path.as_str().to_owned();
+
+        ctx.receive_blocking(move |files, reader| {
+            let count = files.import(&tree, Path::new(&path)?, reader)?;
+
+            Ok(bincode::serialize(&count)?)
+        });
+
+        Ok(())
+    }
+}
+
+/// The [Export] method streams a revision of the tree at a given [Path] back to the client as a `.tar.gz` archive
+pub struct Export;
+
+impl Method for Export {
+    type Input<'a> = (&'a str, Path<'a>, Revision);
+    type Output = ();
+
+    const NAME: &'static str = "EXPORT";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, path, revision): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} exporting {path} @ {revision:?} from tree '{tree}'");
+
+        // check the path exists up front, so the client gets an error rather than an empty stream
+        if files.get_node(tree, path, revision)?.filter(|node| node.data().is_some()).is_none() {
+            let err: io::Error = io::ErrorKind::NotFound.into();
+            return Err(err.into());
+        }
+
+        let tree = tree.to_owned();
+        let path = path.as_str().to_owned();
+
+        ctx.stream_blocking(move |files, writer| {
+            files.export(&tree, Path::new(&path)?, revision, writer)?;
+
+            Ok(())
+        });
+
+        Ok(())
+    }
+}
+
+/// The [Batch] method applies a list of [Operation]s to a tree as a single revision, once the contents of the files it
+/// inserts have been streamed from the client. If any operation fails, none of them are applied
+pub struct Batch;
+
+impl Method for Batch {
+    type Input<'a> = (&'a str, Vec<Operation>);
+    type Output = ();
+
+    const NAME: &'static str = "BATCH";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, (tree, operations): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} applying batch of {} operations to tree '{tree}'", operations.len());
+
+        ctx.receive(BatchSink {
+            batch: files.batch(tree, operations)?,
+        });
+
+        Ok(())
+    }
+}
+
+/// Create a new, empty filesystem tree
+pub struct CreateTree;
+
+impl Method for CreateTree {
+    type Input<'a> = &'a str;
+    type Output = ();
+
+    const NAME: &'static str = "CREATE_TREE";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, name: Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} creating tree '{name}'");
+
+        files.create_tree(name)?;
+
+        Ok(())
+    }
+}
+
+/// List the filesystem trees on the server
+pub struct ListTrees;
+
+impl Method for ListTrees {
+    type Input<'a> = ();
+    type Output = Vec<TreeInfo>;
+
+    const NAME: &'static str = "LIST_TREES";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, _: Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} listing trees");
+
+        Ok(files.list_trees()?)
+    }
+}
+
+/// Rename a filesystem tree, keeping it's history
+pub struct RenameTree;
+
+impl Method for RenameTree {
+    type Input<'a> = (&'a str, &'a str);
+    type Output = ();
+
+    const NAME: &'static str = "RENAME_TREE";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, (from, to): Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} renaming tree '{from}' to '{to}'");
+
+        files.rename_tree(from, to)?;
+
+        Ok(())
+    }
+}
+
+/// Delete a filesystem tree, along with it's history
+pub struct DeleteTree;
+
+impl Method for DeleteTree {
+    type Input<'a> = &'a str;
+    type Output = ();
+
+    const NAME: &'static str = "DELETE_TREE";
+
+    fn call<'a>(files: &Files, ctx: &mut Context, name: Self::Input<'a>) -> anyhow::Result<Self::Output> {
+        let addr = ctx.addr();
+
+        log::info!("client {addr} deleting tree '{name}'");
+
+        files.delete_tree(name)?;
+
+        Ok(())
+    }
+}
+
 pub fn register(ctx: &mut Context) {
     ctx.register(&Get);
     ctx.register(&Insert);
+    ctx.register(&InsertObject);
+    ctx.register(&Have);
     ctx.register(&Delete);
+    ctx.register(&Move);
+    ctx.register(&Copy);
     ctx.register(&Clear);
     ctx.register(&Rollback);
     ctx.register(&GetNode);
     ctx.register(&GetHistory);
+    ctx.register(&Diff);
+    ctx.register(&Log);
+    ctx.register(&Restore);
+    ctx.register(&ListTombstones);
+    ctx.register(&Undelete);
+    ctx.register(&PurgeTombstones);
+    ctx.register(&CreateTag);
+    ctx.register(&ListTags);
+    ctx.register(&DeleteTag);
+    ctx.register(&Export);
+    ctx.register(&Import);
+    ctx.register(&Batch);
+    ctx.register(&CreateTree);
+    ctx.register(&ListTrees);
+    ctx.register(&RenameTree);
+    ctx.register(&DeleteTree);
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
 pub mod methods;
+pub mod transfer;
 
 use tokio::net::{self, TcpStream};
 
@@ -8,9 +9,11 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 
 use crate::config;
-use crate::files::Files;
+use crate::files::{at_rest, Files};
 use crate::proto::{self, Method, Packet, RawMethod};
 
+use transfer::{Sink, Source, Transfer};
+
 /// Represents the different protocol-specific errors that can be encountered while the server is running
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -18,26 +21,34 @@ pub enum Error {
     // InvalidBincode(Vec<u8>),
     #[error("invalid method {0:?}")]
     InvalidMethod(String),
+
+    #[error("no retention policy configured")]
+    NoRetention,
 }
 
 /// The [Server] struct holds all the state required to serve requests for files
 pub struct Server {
     listener: net::TcpListener,
     files: Arc<Files>,
+    config: Arc<config::Server>,
 }
 
 pub struct Context {
     addr: SocketAddr,
     methods: HashMap<&'static str, &'static dyn RawMethod>,
     stream: TcpStream,
+    config: Arc<config::Server>,
+    transfer: Option<Transfer>,
 }
 
 impl Context {
-    fn init(addr: SocketAddr, stream: TcpStream) -> Context {
+    fn init(addr: SocketAddr, stream: TcpStream, config: Arc<config::Server>) -> Context {
         Context {
             addr,
             stream,
+            config,
             methods: HashMap::new(),
+            transfer: None,
         }
     }
 
@@ -46,6 +57,47 @@ impl Context {
         self.addr
     }
 
+    /// Returns the configuration of the server a [Context] belongs to
+    pub fn config(&self) -> &config::Server {
+        &self.config
+    }
+
+    /// Stream the output of a [Source] to the client once the current method returns
+    pub fn send(&mut self, source: impl Source + 'static) {
+        self.transfer = Some(Transfer::Send(Box::new(source)));
+    }
+
+    /// Stream data from the client into a [Sink] once the current method returns
+    pub fn receive(&mut self, sink: impl Sink + 'static) {
+        self.transfer = Some(Transfer::Receive(Box::new(sink)));
+    }
+
+    /// Run a `job` on a blocking thread once the current method returns, reading the stream sent by the client as it
+    /// arrives, and sending the job's result to the client once the stream has finished
+    pub fn receive_blocking(
+        &mut self,
+        job: impl FnOnce(&Files, &mut dyn io::Read) -> anyhow::Result<Vec<u8>> + Send + 'static,
+    ) {
+        self.transfer = Some(Transfer::ReceiveStream(Box::new(job)));
+    }
+
+    /// Run a `job` on a blocking thread once the current method returns, sending it's result to the client as a second
+    /// response
+    pub fn run_blocking(
+        &mut self,
+        job: impl FnOnce(&Files) -> anyhow::Result<Vec<u8>> + Send + 'static,
+    ) {
+        self.transfer = Some(Transfer::Blocking(Box::new(job)));
+    }
+
+    /// Run a `job` on a blocking thread once the current method returns, streaming everything it writes to the client
+    pub fn stream_blocking(
+        &mut self,
+        job: impl FnOnce(&Files, &mut dyn io::Write) -> anyhow::Result<()> + Send + 'static,
+    ) {
+        self.transfer = Some(Transfer::Stream(Box::new(job)));
+    }
+
     /// Register a given [Method] with the [Context]
     pub fn register<M: Method>(&mut self, method: &'static M) {
         self.methods.insert(M::NAME, method);
@@ -74,18 +126,23 @@ impl Server {
         log::info!("initialising server with config: {config:#?}");
         let listener = net::TcpListener::bind(config.addr).await?;
         log::info!("listener bound to {}", config.addr);
-        let files = Files::open(config.db)?;
+        let keys = at_rest::Keys {
+            current: config.encryption.as_ref().map(at_rest::Key::load).transpose()?,
+            previous: None,
+        };
+        let files = Files::open(&config.db, &config.storage, config.compression, keys)?;
 
         Ok(Server {
             listener,
             files: Arc::new(files),
+            config: Arc::new(config),
         })
     }
 
     async fn accept(&self) -> io::Result<Context> {
         let (stream, addr) = self.listener.accept().await?;
 
-        let mut context = Context::init(addr, stream);
+        let mut context = Context::init(addr, stream, self.config.clone());
 
         if self.files.is_configured() {
             context.register(&methods::auth::Identify);
@@ -97,7 +154,76 @@ impl Server {
         Ok(context)
     }
 
+    /// Periodically prune the history of the filesystem, and collect any objects that are no longer referenced
+    fn spawn_pruning(&self) {
+        let Some(policy) = self.config.retention.clone() else {
+            return;
+        };
+
+        let Some(interval) = policy.interval else {
+            return;
+        };
+
+        let files = self.files.clone();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(tokio::time::Duration::from_secs(interval)).await;
+
+                let files = files.clone();
+                let policy = policy.clone();
+
+                // pruning and collection walk the entire database, so keep them off of the async workers
+                let result = tokio::task::spawn_blocking(move || {
+                    let pruned = files.prune(&policy)?;
+                    let collected = files.collect_garbage()?;
+
+                    Ok::<_, crate::files::Error>((pruned, collected))
+                })
+                .await;
+
+                match result {
+                    Ok(Ok((pruned, collected))) => {
+                        log::info!("automatic pruning: {pruned}; {collected}")
+                    }
+                    Ok(Err(e)) => log::error!("automatic pruning failed: {e}"),
+                    Err(e) => log::error!("automatic pruning panicked: {e}"),
+                }
+            }
+        });
+    }
+
+    /// Periodically write an archive of the filesystem to the backup directory
+    fn spawn_backups(&self) {
+        let Some(policy) = self.config.backup.clone() else {
+            return;
+        };
+
+        let files = self.files.clone();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(tokio::time::Duration::from_secs(policy.interval)).await;
+
+                let files = files.clone();
+                let policy = policy.clone();
+
+                // archiving reads every file in the tree, so keep it off of the async workers
+                let result = tokio::task::spawn_blocking(move || files.backup(&policy)).await;
+
+                match result {
+                    Ok(Ok(path)) => log::info!("automatic backup: wrote {path:?}"),
+                    Ok(Err(e)) => log::error!("automatic backup failed: {e}"),
+                    Err(e) => log::error!("automatic backup panicked: {e}"),
+                }
+            }
+        });
+    }
+
     pub async fn run(&self) -> anyhow::Result<()> {
+        self.spawn_pruning();
+        self.spawn_backups();
+
         loop {
             // accept connection from tcp listener
             let mut ctx = self.accept().await?;
@@ -108,32 +234,41 @@ impl Server {
             tokio::spawn(async move {
                 // wrap operation in async block; lets us catch errors
                 let fut = async move {
-                    loop {
-                        // read next packet from client
-                        if let Some(request) = proto::read_packet(&mut ctx.stream).await? {
-                            // dispatch request to respective method handler
-                            let response = ctx.dispatch(&files, request);
-
-                            // send response to client
-                            match response {
-                                Ok(data) => {
-                                    // proto::write_packet(&mut stream, "OK", data).await?;
-                                    proto::Packet {
-                                        method: "OK".to_string(),
-                                        data,
-                                    }
-                                    .write(&mut ctx.stream)
-                                    .await?;
+                    // read next packet from client
+                    while let Some(request) = proto::read_packet(&mut ctx.stream).await? {
+                        // dispatch request to respective method handler. methods may wait for a garbage collection
+                        // to finish, so keep them off of the async workers
+                        let (response, returned) = {
+                            let files = files.clone();
+
+                            tokio::task::spawn_blocking(move || {
+                                (ctx.dispatch(&files, request), ctx)
+                            })
+                            .await?
+                        };
+                        ctx = returned;
+
+                        // send response to client
+                        match response {
+                            Ok(data) => {
+                                // proto::write_packet(&mut stream, "OK", data).await?;
+                                proto::Packet {
+                                    method: "OK".to_string(),
+                                    data,
                                 }
+                                .write(&mut ctx.stream)
+                                .await?;
 
-                                Err(e) => {
-                                    proto::write_packet(&mut ctx.stream, "ERR", e.to_string())
-                                        .await?;
-                                    return Err(e);
+                                // perform any transfer the method requested
+                                if let Some(transfer) = ctx.transfer.take() {
+                                    transfer.run(&files, &mut ctx.stream).await?;
                                 }
                             }
-                        } else {
-                            break;
+
+                            Err(e) => {
+                                proto::write_error(&mut ctx.stream, &e).await?;
+                                return Err(e);
+                            }
                         }
                     }
 
@@ -0,0 +1,265 @@
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use crate::files::Files;
+use crate::proto;
+
+/// A [Source] produces the contents of a stream sent to the client after a method returns
+pub trait Source: Send {
+    /// Produce the next block of the stream, or `None` once it has finished. Blocks of any size may be returned; they
+    /// are split into frames before being sent
+    fn next(&mut self, files: &Files) -> anyhow::Result<Option<Vec<u8>>>;
+}
+
+/// A [Sink] consumes the contents of a stream sent by the client after a method returns. It is ran on a blocking thread,
+/// so may do slow work such as storing objects
+pub trait Sink: Send {
+    /// Process the next frame of the stream
+    fn write(&mut self, files: &Files, data: &[u8]) -> anyhow::Result<()>;
+
+    /// Called once the stream has finished. Returns the `bincode`-encoded response sent to the client
+    fn finish(self: Box<Self>, files: &Files) -> anyhow::Result<Vec<u8>>;
+}
+
+/// Work that is too slow to be done on the async workers, such as walking the whole database. Returns the
+/// `bincode`-encoded response sent to the client
+pub type Job = Box<dyn FnOnce(&Files) -> anyhow::Result<Vec<u8>> + Send>;
+
+/// Work ran on a blocking thread that writes a stream to the client as it goes, such as building an archive
+pub type StreamJob = Box<dyn FnOnce(&Files, &mut dyn Write) -> anyhow::Result<()> + Send>;
+
+/// Work ran on a blocking thread that reads a stream from the client as it arrives, such as importing an archive.
+/// Returns the `bincode`-encoded response sent to the client
+pub type ReceiveJob = Box<dyn FnOnce(&Files, &mut dyn Read) -> anyhow::Result<Vec<u8>> + Send>;
+
+/// A stream of data, or some other work, that follows the response to a method
+pub enum Transfer {
+    Send(Box<dyn Source>),
+    Receive(Box<dyn Sink>),
+    /// A [ReceiveJob], which reads the stream sent by the client
+    ReceiveStream(ReceiveJob),
+    /// A [Job] ran on a blocking thread, the result of which is sent to the client as a second response
+    Blocking(Job),
+    /// A [StreamJob], the output of which is sent to the client as a stream
+    Stream(StreamJob),
+}
+
+/// Collects the output of a [StreamJob] into frames, handing them to the async worker sending the stream
+struct FrameWriter {
+    frames: mpsc::Sender<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl FrameWriter {
+    /// Hand the buffered frame to the async worker, waiting if it has fallen behind
+    fn send(&mut self) -> io::Result<()> {
+        let frame = std::mem::replace(&mut self.buf, Vec::with_capacity(proto::FRAME_SIZE));
+
+        self.frames
+            .blocking_send(frame)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl Write for FrameWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(proto::FRAME_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+
+        if self.buf.len() == proto::FRAME_SIZE {
+            self.send()?;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.send()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the frames of a stream handed over by the async worker receiving it, for a [ReceiveJob]
+struct FrameReader {
+    frames: mpsc::Receiver<Vec<u8>>,
+    frame: Vec<u8>,
+    offset: usize,
+    /// Whether the empty frame ending the stream has been read
+    ended: bool,
+}
+
+impl Read for FrameReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.frame.len() {
+            if self.ended {
+                return Ok(0);
+            }
+
+            match self.frames.blocking_recv() {
+                Some(frame) if frame.is_empty() => self.ended = true,
+                Some(frame) => {
+                    self.frame = frame;
+                    self.offset = 0;
+                }
+                // the connection was dropped, so the stream is incomplete
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream ended early",
+                    ))
+                }
+            }
+        }
+
+        let n = buf.len().min(self.frame.len() - self.offset);
+        buf[..n].copy_from_slice(&self.frame[self.offset..self.offset + n]);
+        self.offset += n;
+
+        Ok(n)
+    }
+}
+
+/// Hand every frame of the stream sent by the client to a blocking thread, up to and including the empty frame ending it
+async fn forward(stream: &mut TcpStream, frames: mpsc::Sender<Vec<u8>>) -> anyhow::Result<()> {
+    loop {
+        let frame = proto::read_frame(stream).await?;
+        let end = frame.is_empty();
+
+        // once the blocking thread has failed, it stops receiving, but we still have to consume the rest of the stream
+        // before we can respond
+        let _ = frames.send(frame).await;
+
+        if end {
+            return Ok(());
+        }
+    }
+}
+
+/// Send the outcome of a [Sink], [Job] or [ReceiveJob] to the client
+async fn respond(stream: &mut TcpStream, result: anyhow::Result<Vec<u8>>) -> anyhow::Result<()> {
+    match result {
+        Ok(data) => {
+            proto::Packet {
+                method: "OK".to_string(),
+                data,
+            }
+            .write(stream)
+            .await?;
+
+            Ok(())
+        }
+
+        Err(e) => {
+            proto::write_error(stream, &e).await?;
+            Err(e)
+        }
+    }
+}
+
+impl Transfer {
+    /// Perform the transfer over a given `stream`
+    pub async fn run(self, files: &Arc<Files>, stream: &mut TcpStream) -> anyhow::Result<()> {
+        match self {
+            Transfer::Send(mut source) => {
+                // if the source fails part-way through, the connection is dropped, and the client will see the
+                // stream end early
+                while let Some(block) = source.next(files)? {
+                    for frame in block.chunks(proto::FRAME_SIZE) {
+                        proto::write_frame(stream, frame).await?;
+                    }
+                }
+
+                proto::write_frame(stream, &[]).await?;
+                stream.flush().await?;
+            }
+
+            Transfer::Receive(mut sink) => {
+                let files = files.clone();
+                // only buffer a few frames, so a slow sink holds up the client rather than filling memory
+                let (frames, mut rx) = mpsc::channel::<Vec<u8>>(4);
+
+                // storing frames means compressing, encrypting and writing them, so keep it off of the async workers
+                let handle = tokio::task::spawn_blocking(move || {
+                    loop {
+                        match rx.blocking_recv() {
+                            Some(frame) if frame.is_empty() => break,
+                            Some(frame) => sink.write(&files, &frame)?,
+                            // the connection was dropped, so the stream is incomplete
+                            None => anyhow::bail!("stream ended early"),
+                        }
+                    }
+
+                    sink.finish(&files)
+                });
+
+                forward(stream, frames).await?;
+                respond(stream, handle.await?).await?;
+            }
+
+            Transfer::ReceiveStream(job) => {
+                let files = files.clone();
+                // only buffer a few frames, so a slow job holds up the client rather than filling memory
+                let (frames, rx) = mpsc::channel(4);
+
+                let handle = tokio::task::spawn_blocking(move || {
+                    let mut reader = FrameReader {
+                        frames: rx,
+                        frame: vec![],
+                        offset: 0,
+                        ended: false,
+                    };
+
+                    job(&files, &mut reader)
+                });
+
+                forward(stream, frames).await?;
+                respond(stream, handle.await?).await?;
+            }
+
+            Transfer::Blocking(job) => {
+                let files = files.clone();
+                let result = tokio::task::spawn_blocking(move || job(&files)).await?;
+
+                respond(stream, result).await?;
+            }
+
+            Transfer::Stream(job) => {
+                let files = files.clone();
+                // only buffer a few frames, so a slow client holds up the job rather than filling memory
+                let (frames, mut rx) = mpsc::channel(4);
+
+                let handle = tokio::task::spawn_blocking(move || {
+                    let mut writer = FrameWriter {
+                        frames,
+                        buf: Vec::with_capacity(proto::FRAME_SIZE),
+                    };
+
+                    job(&files, &mut writer)?;
+                    writer.flush()?;
+
+                    Ok::<_, anyhow::Error>(())
+                });
+
+                while let Some(frame) = rx.recv().await {
+                    proto::write_frame(stream, &frame).await?;
+                }
+
+                // if the job fails part-way through, the connection is dropped, and the client will see the stream
+                // end early
+                handle.await??;
+
+                proto::write_frame(stream, &[]).await?;
+                stream.flush().await?;
+            }
+        }
+
+        Ok(())
+    }
+}
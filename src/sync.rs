@@ -1,18 +1,23 @@
+use digest::Digest;
 use notify::EventKind;
 use notify::Watcher;
+use tokio::io::AsyncReadExt;
 use tokio::sync::mpsc;
 
-use digest::Digest;
-
+use std::io;
 use std::path::PathBuf;
 use std::time::Duration;
 use std::time::SystemTime;
 
-use crate::client::Client;
+use crate::client::{self, Client};
 use crate::config;
+use crate::encryption::Cipher;
+use crate::files::chunk::ManifestBuilder;
 use crate::files::Node;
+use crate::files::Object;
 use crate::files::Path;
 use crate::files::Revision;
+use crate::proto;
 use crate::server::methods;
 
 enum SyncEvent {
@@ -50,7 +55,11 @@ pub struct SyncClient {
     _watcher: notify::RecommendedWatcher,
     event_queue: mpsc::Receiver<SyncEvent>,
     dir: PathBuf,
+    /// The filesystem tree on the server that is synchronised
+    tree: String,
     client: Client,
+    /// Encrypts files before they are sent to the server, if enabled
+    cipher: Option<Cipher>,
 }
 
 impl SyncClient {
@@ -93,11 +102,18 @@ impl SyncClient {
         // authenticate with the server using client key
         client.invoke(methods::auth::Identify, key).await?;
 
+        let cipher = match &config.encryption {
+            Some(encryption) => Some(Cipher::load(encryption).await?),
+            None => None,
+        };
+
         Ok(SyncClient {
             _watcher: watcher,
             event_queue,
             dir,
+            tree: config.tree,
             client,
+            cipher,
         })
     }
 
@@ -107,36 +123,126 @@ impl SyncClient {
     }
 
     /// Takes a path within the sync point folder and produces a path relative to the server
-    fn remote_path<'a>(&self, path: &std::path::Path) -> String {
+    fn remote_path(&self, path: &std::path::Path) -> String {
         let new_path = path.strip_prefix(&self.dir).unwrap();
         let as_str = new_path.to_str().unwrap();
 
         format!("/{}", as_str)
     }
 
+    /// Encrypt the names of a path on the server, if they are encrypted
+    fn encrypt_path(&self, path: Path) -> String {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt_path(path.as_str()),
+            None => path.as_str().to_owned(),
+        }
+    }
+
+    /// Retrieve the whole of the latest revision of the tree, with it's names decrypted
+    async fn listing(&mut self) -> anyhow::Result<Node> {
+        let mut listing = self
+            .client
+            .invoke(
+                methods::fs::GetNode,
+                (&self.tree, Path::new("/")?, Revision::FromLatest(0)),
+            )
+            .await?;
+
+        if let Some(cipher) = &self.cipher {
+            cipher.decrypt_node(&mut listing);
+        }
+
+        Ok(listing)
+    }
+
+    /// Check whether a local file has the same contents as an [Object] on the server, without reading it all into
+    /// memory. Encrypted files are compared by encrypting the local file as it is read
+    async fn is_same(
+        cipher: Option<&Cipher>,
+        local_path: &std::path::Path,
+        object: &Object,
+    ) -> io::Result<bool> {
+        let mut file = tokio::fs::File::open(local_path).await?;
+
+        if let Some(cipher) = cipher {
+            let encrypted = client::file_object(&mut cipher.encrypt_reader(file)).await?;
+
+            return Ok(encrypted == *object);
+        }
+        let mut buf = vec![0; proto::FRAME_SIZE];
+
+        let mut manifest = ManifestBuilder::default();
+        // older servers stored files as a single object, referenced by the hash of the whole file
+        let mut hasher = sha2::Sha256::new();
+
+        loop {
+            let n = file.read(&mut buf).await?;
+
+            if n == 0 {
+                break;
+            }
+
+            manifest.update(&buf[..n]);
+            hasher.update(&buf[..n]);
+        }
+
+        let legacy = Object::from_hash(hasher.finalize().into());
+
+        Ok(manifest.finish().object() == *object || legacy == *object)
+    }
+
     /// Fetch a given file from the server
     async fn fetch_file(&mut self, path: Path<'_>) -> anyhow::Result<()> {
-        let data = self.client.invoke(methods::fs::Get, path).await?;
-
         let (parent, _) = path.parent_child();
         let local_parent = self.local_path(parent);
         let local_path = self.local_path(path);
 
-        tokio::fs::create_dir_all(local_parent).await?;
-        tokio::fs::write(local_path, &data).await?;
+        tokio::fs::create_dir_all(&local_parent).await?;
+
+        // download to a temporary file, so a failed transfer doesn't leave a partial file in the sync folder
+        let tmp = tempfile::NamedTempFile::new_in(&local_parent)?;
+        let mut file = tokio::fs::File::from_std(tmp.reopen()?);
+
+        let remote = self.encrypt_path(path);
+        let remote = Path::new(&remote)?;
+
+        if let Some(cipher) = &self.cipher {
+            let mut writer = cipher.decrypt_writer(&mut file);
+
+            self.client
+                .download(methods::fs::Get, (&self.tree, remote), &mut writer)
+                .await?;
+
+            writer.finish()?;
+        } else {
+            self.client
+                .download(methods::fs::Get, (&self.tree, remote), &mut file)
+                .await?;
+        }
+
+        tmp.persist(local_path)?;
 
         Ok(())
     }
 
-    /// Update a given file on the server
+    /// Update a given file on the server. It's contents are only sent if the server doesn't already have them
     async fn upload_file(&mut self, path: Path<'_>) -> anyhow::Result<()> {
         let local_path = self.local_path(path);
 
-        let data = tokio::fs::read(local_path).await?;
+        let mut file = tokio::fs::File::open(local_path).await?;
 
-        self.client
-            .invoke(methods::fs::Insert, (path, data))
-            .await?;
+        let remote = self.encrypt_path(path);
+        let remote = Path::new(&remote)?;
+
+        if let Some(cipher) = &self.cipher {
+            self.client
+                .insert_file(&self.tree, remote, None, &mut cipher.encrypt_reader(file))
+                .await?;
+        } else {
+            self.client
+                .insert_file(&self.tree, remote, None, &mut file)
+                .await?;
+        }
 
         Ok(())
     }
@@ -161,7 +267,7 @@ impl SyncClient {
         // handle the file existing locally, but not on the server
         // TODO: re-structure this to be prettier
         if let Some(file) = root.traverse(path)? {
-            if file.data().is_none() || file.dir().is_some() {
+            if !file.is_file() {
                 if !local_path.exists() {
                     return Ok(FileStatus::Same);
                 }
@@ -174,21 +280,14 @@ impl SyncClient {
         if local_path.exists() {
             // check if the file exists on the server
             if let Some(file) = root.traverse(path)? {
-                if file.data().is_none() || file.dir().is_some() {
+                if !file.is_file() {
                     return Ok(FileStatus::Deleted);
                 }
 
                 let object = file.file().unwrap();
 
-                // read the contents of the file and calculate SHA-256 hash
-                let data = tokio::fs::read(&local_path).await?;
-
-                let mut hasher = sha2::Sha256::new();
-                hasher.update(&data);
-                let local_hash = hasher.finalize();
-
-                // check if the hashes match
-                let hash_match = &local_hash[..] == object.hash();
+                // check if the hashes of the local and remote copies match
+                let hash_match = Self::is_same(self.cipher.as_ref(), &local_path, object).await?;
 
                 if hash_match {
                     // hashes match; nothing to be done
@@ -249,13 +348,7 @@ impl SyncClient {
         let files = glob::glob(&format!("{}/**/*", dir_str))?;
 
         // retrieve the remote filesystem structure
-        let listing = self
-            .client
-            .invoke(
-                methods::fs::GetNode,
-                (Path::new("/")?, Revision::FromLatest(0)),
-            )
-            .await?;
+        let listing = self.listing().await?;
 
         for entry in files {
             let entry = entry?;
@@ -279,13 +372,7 @@ impl SyncClient {
     async fn resync(&mut self) -> anyhow::Result<()> {
         log::info!("re-syncing with server");
         // retrieve server's file listing
-        let listing = self
-            .client
-            .invoke(
-                methods::fs::GetNode,
-                (Path::new("/")?, Revision::FromLatest(0)),
-            )
-            .await?;
+        let listing = self.listing().await?;
 
         for (path, node) in listing.iter() {
             let path = Path::new(&path)?;
@@ -317,14 +404,8 @@ impl SyncClient {
                     log::trace!("got event {:#?}", event);
 
                     // create map of remote path -> metadata
-                    let mut files = self
-                        .client
-                        .invoke(
-                            methods::fs::GetNode,
-                            (Path::new("/")?, Revision::FromLatest(0)),
-                        )
-                        .await?;
-                    let files = files.file_list()?.as_map();
+                    let mut files = self.listing().await?;
+                    let files = files.file_list()?.into_map();
 
                     // iterate over files in event
                     for path in event.paths {
@@ -341,17 +422,9 @@ impl SyncClient {
                                 remote_path
                             );
 
-                            // read the contents of the file
-                            let data = tokio::fs::read(&path).await?;
-
-                            // calculate hash of file's contents
-                            let mut hasher = sha2::Sha256::new();
-                            hasher.update(&data);
-                            let hash: [u8; 32] = hasher.finalize().try_into().unwrap();
-
                             // if the server's copy of the file's hash matches the local copy, do nothing
                             if let Some((object, _)) = files.get(&remote_path) {
-                                if object.hash() == &hash {
+                                if Self::is_same(self.cipher.as_ref(), &path, object).await? {
                                     continue;
                                 }
                             }
@@ -361,9 +434,8 @@ impl SyncClient {
                                 "inserting file {} -> {remote_path}",
                                 path.to_string_lossy()
                             );
-                            self.client
-                                .invoke(methods::fs::Insert, (Path::new(&remote_path)?, data))
-                                .await?;
+
+                            self.upload_file(Path::new(&remote_path)?).await?;
                         }
                     }
                 }